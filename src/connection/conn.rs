@@ -1,23 +1,100 @@
 use binary_utils::*;
-use std::{collections::VecDeque, sync::Arc, time::SystemTime};
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
 
 use crate::{
     internal::{
+        cookie::CookieJar,
         frame::reliability::Reliability,
         queue::{Queue, SendPriority},
-        RakConnHandler, RakConnHandlerMeta,
+        RakConnHandler, RakConnHandlerMeta, MAX_ORDER_CHANNELS,
+    },
+    protocol::{
+        mcpe::motd::{Motd, MotdGenerator},
+        online::{ConnectedPing, Disconnect},
+        util::verify_magic,
+        Packet,
     },
-    protocol::{mcpe::motd::Motd, online::Disconnect, Packet},
-    rak_debug,
+    rak_debug, rak_warn,
     server::{RakEvent, RakNetVersion},
+    MAX_FRAME_HEADER_SIZE, MAX_MTU_SIZE, RAKNET_HEADER_FRAME_OVERHEAD,
 };
 
 use crate::protocol::handler::{handle_offline, handle_online};
 
 use super::state::ConnectionState;
+use super::stats::{ConnectionInfo, ConnectionStats};
 
 pub type SendCommand = (String, Vec<u8>);
 
+/// Why a stream could not be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendError {
+    /// RakNet only has 32 order channels, the peer would drop anything sent on another one.
+    InvalidChannel(u8),
+    /// The server stopped sending, nothing can reach the client anymore.
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::InvalidChannel(channel) => write!(f, "Invalid order channel: {}", channel),
+            SendError::Closed => write!(f, "The server stopped sending"),
+        }
+    }
+}
+
+/// How long a connection may go without sending a packet by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to complete the handshake by default, before it is dropped.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a reliable datagram waits for an ACK by default, before it is resent.
+pub const DEFAULT_RESEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The shortest a reliable datagram waits for an ACK, no matter how low the latency is.
+pub const MIN_RESEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How many times a reliable datagram is resent by default, before we give up on it.
+pub const DEFAULT_MAX_RESENDS: u32 = 5;
+
+/// How often a connected client is sent a `ConnectedPing` by default.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many `ConnectedPing`s in a row a client may leave unanswered by default,
+/// before the connection is considered lost.
+pub const DEFAULT_MAX_MISSED_PINGS: u32 = 3;
+
+/// How long the fragments of a frame are kept by default, before the frame is given up on.
+pub const DEFAULT_FRAGMENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most fragments a single frame may be split into by default.
+pub const DEFAULT_MAX_FRAGMENTS: u32 = 512;
+
+/// The largest a fragmented frame may be once it's reassembled by default, this is 1 MiB.
+pub const DEFAULT_MAX_FRAGMENTED_SIZE: usize = 1024 * 1024;
+
+/// The most fragmented frames a client may have waiting for reassembly at once by default.
+pub const DEFAULT_MAX_FRAGMENTED_FRAMES: usize = 32;
+
+/// The most packets held back on a single order channel by default, while they wait
+/// for the packets before them.
+pub const DEFAULT_MAX_ORDERED_PACKETS: usize = 512;
+
+/// The packet ids that are passed on to the user by default, this is the minecraft game packet.
+pub const DEFAULT_USER_PACKET_IDS: RangeInclusive<u8> = 0xfe..=0xfe;
+
+/// The most game packets held for a client that hasn't completed the handshake yet.
+const MAX_EARLY_PACKETS: usize = 128;
+
 #[derive(Debug, Clone)]
 pub struct Connection {
     /// The tokenized address of the connection.
@@ -29,25 +106,105 @@ pub struct Connection {
     /// Some states are used internally to rak-rs, but are not used in actual protocol
     /// such as "Unidentified" and "Online".
     pub state: ConnectionState,
+    /// The state the connection was in before it started timing out.
+    /// This is restored as soon as we hear from the client again.
+    pub(crate) state_before_timeout: Option<ConnectionState>,
     /// The maximum transfer unit for the connection.
     /// Any outbound packets will be sharded into frames of this size.
     /// By default minecraft will use `1400` bytes. However raknet has 16 bytes of overhead.
     /// so this may be reduced as `1400 - 16` which is `1384`.
     pub mtu: u16,
+    /// The largest mtu size this connection is allowed to negotiate.
+    /// Any larger mtu the client requests will be clamped to this size.
+    pub max_mtu: u16,
     /// The last recieved time.
     /// This is used to determine if the connection has timed out.
     /// This is the time the last packet was recieved.
     pub recv_time: SystemTime,
+    /// How long the connection may go without sending a packet before it is disconnected.
+    /// The connection is considered to be timing out after half of this time.
+    pub timeout: Duration,
+    /// The time the client has to be connected by, otherwise the server drops the connection.
+    /// This is `None` once the client is connected.
+    pub handshake_deadline: Option<SystemTime>,
+    /// The time the client completed the handshake, if it has.
+    pub connected_at: Option<SystemTime>,
+    /// How long a reliable datagram waits for an ACK before it is resent.
+    /// Once the latency of the client is known, the wait adapts to it, but never exceeds this.
+    pub resend_timeout: Duration,
+    /// How many times a reliable datagram is resent before we give up on it.
+    pub max_resends: u32,
+    /// How often the client is sent a `ConnectedPing`, this keeps the connection alive
+    /// on both ends and is used to measure the latency of the client.
+    pub ping_interval: Duration,
+    /// The last time we sent the client a `ConnectedPing`.
+    pub(crate) last_ping: Option<SystemTime>,
+    /// The timestamp of the last `ConnectedPing`, until the client answers it.
+    pub(crate) outstanding_ping: Option<i64>,
+    /// How many `ConnectedPing`s in a row the client may leave unanswered before
+    /// the connection is considered lost.
+    pub max_missed_pings: u32,
+    /// How many `ConnectedPing`s in a row the client left unanswered.
+    pub(crate) missed_pings: u32,
+    /// The smoothed round trip time, measured with `ConnectedPing`.
+    pub(crate) latency: Option<Duration>,
+    /// The latency above which a `Latency` event is dispatched for the client, if any.
+    pub latency_threshold: Option<Duration>,
+    /// How long we wait for the remaining fragments of a frame, once the first arrived.
+    /// Frames that aren't completed in time are dropped.
+    pub fragment_timeout: Duration,
+    /// The most fragments the client may split a single frame into.
+    /// Frames claiming more than this are dropped.
+    pub max_fragments: u32,
+    /// The largest a fragmented frame may be once it's reassembled, in bytes.
+    /// Frames that grow past this are dropped.
+    pub max_fragmented_size: usize,
+    /// The most fragmented frames the client may have waiting for reassembly at once.
+    /// The fragments of any other frame are dropped until one of these is completed.
+    pub max_fragmented_frames: usize,
+    /// The most packets held back on a single order channel, while they wait for the
+    /// packets before them. Packets past this are dropped as a protocol violation.
+    pub max_ordered_packets: usize,
     /// The time the server started.
     /// Used in pings
     pub start_time: SystemTime,
     /// The RakNet Version of the server.
     /// This is used to determine if the player can reliably join the server.
     pub raknet_version: RakNetVersion,
+    /// Additional RakNet versions the server accepts alongside `raknet_version`.
+    pub allowed_versions: Vec<RakNetVersion>,
+    /// Whether a connected client may start a new handshake from the same address.
+    /// When this is `false` the client is sent `AlreadyConnected` instead.
+    pub allow_reconnect: bool,
+    /// The ids of the packets that are passed on to the user as a `GamePacket`.
+    /// Any other packet is handled by raknet.
+    pub user_packet_ids: RangeInclusive<u8>,
+    /// Whether game packets the client sends before it completes the handshake are held
+    /// until it does. Otherwise they are dropped.
+    pub buffer_early_packets: bool,
+    /// The game packets held until the client completes the handshake.
+    pub(crate) early_packets: Vec<Vec<u8>>,
+    /// The address the server is bound to.
+    pub server_address: Option<SocketAddr>,
+    /// Whether or not the server address the client sends in its `SessionInfoRequest` has
+    /// to match `server_address`. Behind a proxy or NAT the client sees a different address,
+    /// so this is disabled by default.
+    pub validate_handshake_address: bool,
+    /// The cookies the client has to echo back during the handshake, if the server uses them.
+    pub(crate) cookies: Option<CookieJar>,
     /// Minecraft specific, the message of the day.
-    pub motd: Motd,
+    /// This is shared with the server, and is read every time the connection pings.
+    pub motd: Arc<RwLock<Motd>>,
+    /// The callback used to generate the motd on every ping, shared with the server.
+    /// When this is set, it takes precedence over `motd`.
+    pub motd_generator: Arc<RwLock<Option<MotdGenerator>>>,
     /// A reference to the server id.
     pub server_guid: u64,
+    /// The guid the client sent in its `SessionInfoRequest`, if it got that far.
+    pub client_guid: Option<i64>,
+    /// The datagrams the `ConnectionAccept` was sent in.
+    /// These are sent again as-is if the client repeats its `ConnectionRequest`.
+    pub(crate) connection_accept: Option<Vec<Vec<u8>>>,
     /// The packet queue for the connection.
     /// This is used to store packets that need to be sent, any packet here **WILL** be batched!
     pub queue: Queue<Vec<u8>>,
@@ -60,6 +217,8 @@ pub struct Connection {
     pub event_dispatch: VecDeque<RakEvent>,
     /// This is internal! This is used to handle all raknet packets, like frame, ping etc.
     pub(crate) rakhandler: RakConnHandlerMeta,
+    /// How much has been sent to and received from the client.
+    pub(crate) stats: ConnectionStats,
     /// This is internal! This is used to remove the connection if something goes wrong with connection states.
     /// (which is likely)
     ensure_disconnect: bool,
@@ -71,23 +230,115 @@ impl Connection {
         send_channel: Arc<tokio::sync::mpsc::Sender<SendCommand>>,
         start_time: SystemTime,
         server_guid: u64,
-        port: String,
+        motd: Arc<RwLock<Motd>>,
+        motd_generator: Arc<RwLock<Option<MotdGenerator>>>,
         raknet_version: RakNetVersion,
     ) -> Self {
         Self {
             address,
             state: ConnectionState::Unidentified,
+            state_before_timeout: None,
             mtu: 1400,
+            max_mtu: MAX_MTU_SIZE,
             recv_time: SystemTime::now(),
+            timeout: DEFAULT_TIMEOUT,
+            handshake_deadline: Some(SystemTime::now() + DEFAULT_HANDSHAKE_TIMEOUT),
+            connected_at: None,
+            resend_timeout: DEFAULT_RESEND_TIMEOUT,
+            max_resends: DEFAULT_MAX_RESENDS,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            last_ping: None,
+            outstanding_ping: None,
+            max_missed_pings: DEFAULT_MAX_MISSED_PINGS,
+            missed_pings: 0,
+            latency: None,
+            latency_threshold: None,
+            fragment_timeout: DEFAULT_FRAGMENT_TIMEOUT,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+            max_fragmented_size: DEFAULT_MAX_FRAGMENTED_SIZE,
+            max_fragmented_frames: DEFAULT_MAX_FRAGMENTED_FRAMES,
+            max_ordered_packets: DEFAULT_MAX_ORDERED_PACKETS,
             start_time,
-            motd: Motd::new(server_guid, port),
+            motd,
+            motd_generator,
             server_guid,
+            client_guid: None,
+            connection_accept: None,
             queue: Queue::new(),
             send_channel,
             event_dispatch: VecDeque::new(),
             raknet_version,
+            allowed_versions: Vec::new(),
+            allow_reconnect: false,
+            user_packet_ids: DEFAULT_USER_PACKET_IDS,
+            buffer_early_packets: false,
+            early_packets: Vec::new(),
+            server_address: None,
+            validate_handshake_address: false,
+            cookies: None,
             ensure_disconnect: false,
             rakhandler: RakConnHandlerMeta::new(),
+            stats: ConnectionStats::default(),
+        }
+    }
+
+    /// Whether or not a client using the given RakNet protocol may connect.
+    pub fn supports_protocol(&self, protocol: u8) -> bool {
+        self.raknet_version.to_u8() == protocol
+            || self
+                .allowed_versions
+                .iter()
+                .any(|version| version.to_u8() == protocol)
+    }
+
+    /// Whether or not the address the client claims to connect to is the address of the server.
+    /// An unspecified server ip (such as `0.0.0.0`) matches any ip on the same port.
+    pub fn is_server_address(&self, address: &SocketAddr) -> bool {
+        match self.server_address {
+            Some(server) => {
+                server.port() == address.port()
+                    && (server.ip().is_unspecified() || server.ip() == address.ip())
+            }
+            None => true,
+        }
+    }
+
+    /// The round trip time to the client, smoothed over roughly the last 8 pings.
+    /// This is `None` until the client has answered a ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// The round trip time to the client in milliseconds, or `0` if it isn't known yet.
+    pub fn ping_ms(&self) -> u32 {
+        self.latency.map_or(0, |latency| latency.as_millis() as u32)
+    }
+
+    /// Adds a round trip time measured with a `ConnectedPing` to the smoothed latency.
+    pub(crate) fn add_latency_sample(&mut self, sample: Duration) {
+        let latency = match self.latency {
+            // an exponentially weighted moving average, every sample counts for an eighth.
+            Some(latency) => (latency * 7 + sample) / 8,
+            None => sample,
+        };
+        self.latency = Some(latency);
+
+        if let Some(threshold) = self.latency_threshold {
+            if latency > threshold {
+                self.event_dispatch
+                    .push_back(RakEvent::Latency(self.address.clone(), self.ping_ms()));
+            }
+        }
+    }
+
+    /// How long a reliable datagram currently waits for an ACK before it is resent.
+    /// This is twice the latency of the client when it is known, bounded by `resend_timeout`.
+    pub fn current_resend_timeout(&self) -> Duration {
+        match self.latency {
+            Some(latency) => (latency * 2)
+                .max(MIN_RESEND_TIMEOUT)
+                .min(self.resend_timeout),
+            None => self.resend_timeout,
         }
     }
 
@@ -95,24 +346,36 @@ impl Connection {
     /// This is the MTU - the size of all possible raknet headers,
     /// so: `40 (Datagram Protocol) + 20 (Raknet)`
     pub fn max_frame_size(&self) -> usize {
-        self.mtu as usize - 60
+        (self.mtu - RAKNET_HEADER_FRAME_OVERHEAD) as usize
+    }
+
+    /// Get the maximum size of the body of a single fragment.
+    /// This leaves room for the largest frame header, so a fragment always fits in a frame packet.
+    pub fn max_fragment_size(&self) -> usize {
+        self.max_frame_size() - MAX_FRAME_HEADER_SIZE as usize
     }
 
     /// Adds the given stream to the connection's queue by priority.
     /// If instant is set to "true" the packet will be sent immediately.
-    pub fn send(&mut self, stream: Vec<u8>, instant: bool) {
+    pub fn send(&mut self, stream: Vec<u8>, instant: bool) -> Result<(), SendError> {
         if instant {
             // We're not going to batch this packet, so send it immediately.
-            self.send_immediate(stream);
+            self.send_immediate(stream)
         } else {
             // We're going to batch this packet, so push it to the queue.
             self.queue.push(stream, SendPriority::Normal);
+            Ok(())
         }
     }
 
     /// This method should be used externally to send packets to the connection.
     /// Packets here will be batched together and sent in frames.
     pub fn send_stream(&mut self, stream: Vec<u8>, priority: SendPriority) {
+        if self.is_disconnecting() {
+            // the client is being disconnected, there's no point in sending anything else.
+            return;
+        }
+
         if priority == SendPriority::Immediate {
             RakConnHandler::send_framed(self, stream, Reliability::ReliableOrd);
         } else {
@@ -120,17 +383,64 @@ impl Connection {
         }
     }
 
+    /// Sends the stream with the given reliability on the given order channel, or the
+    /// default channel if there is none. The stream is batched and sent on the next tick.
+    pub fn send_reliable(
+        &mut self,
+        data: Vec<u8>,
+        reliability: Reliability,
+        channel: Option<u8>,
+    ) -> Result<(), SendError> {
+        let channel = Self::order_channel(channel)?;
+        if self.is_disconnecting() {
+            return Ok(());
+        }
+
+        RakConnHandler::queue_framed(self, data, reliability, channel, None);
+        Ok(())
+    }
+
+    /// Sends the stream like `send_reliable`, once it is acknowledged by the client an
+    /// `AckReceipt` event is dispatched with the given receipt. If the client never
+    /// acknowledges it, a `LossReceipt` event is dispatched instead.
+    pub fn send_with_receipt(
+        &mut self,
+        data: Vec<u8>,
+        reliability: Reliability,
+        channel: Option<u8>,
+        receipt: u32,
+    ) -> Result<(), SendError> {
+        let channel = Self::order_channel(channel)?;
+        if self.is_disconnecting() {
+            return Ok(());
+        }
+
+        RakConnHandler::queue_framed(self, data, reliability, channel, Some(receipt));
+        Ok(())
+    }
+
+    /// The order channel to send on, the default channel is `0`.
+    fn order_channel(channel: Option<u8>) -> Result<u8, SendError> {
+        match channel.unwrap_or(0) {
+            channel if channel >= MAX_ORDER_CHANNELS => Err(SendError::InvalidChannel(channel)),
+            channel => Ok(channel),
+        }
+    }
+
     /// Immediately send the packet to the connection.
     /// This will not automatically batch the packet.
-    pub fn send_immediate(&mut self, stream: Vec<u8>) {
-        // check the context
-        if let Ok(_) =
-            futures_executor::block_on(self.send_channel.send((self.address.clone(), stream)))
-        {
-            // GREAT!
-        } else {
-            rak_debug!("Failed to send packet to {}", self.address);
-        }
+    /// Fails with `SendError::Closed` if the server stopped sending.
+    pub fn send_immediate(&mut self, stream: Vec<u8>) -> Result<(), SendError> {
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += stream.len() as u64;
+
+        // the sending task only stops with the server.
+        futures_executor::block_on(self.send_channel.send((self.address.clone(), stream))).map_err(
+            |_| {
+                rak_warn!("Failed to send packet to {}", self.address);
+                SendError::Closed
+            },
+        )
     }
 
     /// Sends the packet inside a frame and may queue it based on priority.
@@ -138,6 +448,10 @@ impl Connection {
     ///
     /// WARNING: DO NOT USE THIS FOR PACKETS THAT EXCEED MTU SIZE!
     pub fn send_frame(&mut self, stream: Vec<u8>, priority: SendPriority) {
+        if self.is_disconnecting() {
+            return;
+        }
+
         if priority == SendPriority::Immediate {
             // we need to batch this frame immediately.
             RakConnHandler::send_framed(self, stream, Reliability::ReliableOrd);
@@ -149,23 +463,36 @@ impl Connection {
 
     /// This will send a raknet packet to the connection.
     /// This method will automatically parse the packet and send it by the given priority.
-    pub fn send_packet(&mut self, packet: Packet, priority: SendPriority) {
+    pub fn send_packet(&mut self, packet: Packet, priority: SendPriority) -> Result<(), SendError> {
         // we can check the kind, if it's an online packet we need to frame it.
         if packet.is_online() {
             self.send_frame(packet.parse().unwrap(), priority);
-            return;
+            return Ok(());
         }
 
         if priority == SendPriority::Immediate {
-            self.send_immediate(packet.parse().unwrap());
+            self.send_immediate(packet.parse().unwrap())
         } else {
             self.queue
                 .push(packet.parse().unwrap(), SendPriority::Normal);
+            Ok(())
         }
     }
 
     pub fn recv(&mut self, payload: &Vec<u8>) {
+        if self.is_disconnected() {
+            // the connection is about to be removed, anything the client sends now is ignored.
+            return;
+        }
+
         self.recv_time = SystemTime::now();
+        self.stats.packets_recv += 1;
+        self.stats.bytes_recv += payload.len() as u64;
+
+        if payload.is_empty() {
+            // there's nothing for us to handle.
+            return;
+        }
 
         // build the packet
         if let Ok(packet) = Packet::compose(&payload, &mut 0) {
@@ -177,6 +504,16 @@ impl Connection {
                 return;
             } else {
                 // offline packet
+                // make sure this is actually a raknet packet before we reply to it,
+                // otherwise we might be replying to anything that looks like one.
+                if !verify_magic(payload) {
+                    rak_debug!(
+                        "[RakNet] [{}] Dropping offline packet with invalid magic.",
+                        self.address
+                    );
+                    return;
+                }
+
                 // handle the disconnected packet
                 handle_offline(self, packet);
 
@@ -188,6 +525,14 @@ impl Connection {
                 }
             }
         } else {
+            // the client is back, it picks up where it left off.
+            if self.state == ConnectionState::TimingOut {
+                self.state = self
+                    .state_before_timeout
+                    .take()
+                    .unwrap_or(ConnectionState::Unidentified);
+            }
+
             // this packet could be a Ack or Frame
             // lets pass it to the rak handler. The rakhandler will invoke `connection.handle` which is
             // where we handle the online packets.
@@ -196,11 +541,12 @@ impl Connection {
                     "We got a packet that we couldn't parse! Probably a Nak or Frame! Error: {}",
                     e
                 );
-            }
-
-            // let's update the client state to connected.
-            if !self.state.is_reliable() {
-                self.state = ConnectionState::Connected;
+                if e.is_protocol_violation() {
+                    self.event_dispatch.push_back(RakEvent::ProtocolViolation(
+                        self.address.clone(),
+                        e.to_string(),
+                    ));
+                }
             }
         }
     }
@@ -208,7 +554,35 @@ impl Connection {
     /// This is called by the rak handler when each frame is decoded.
     /// These packets are usually online packets or game packets!
     pub(crate) fn handle(&mut self, buffer: Vec<u8>) {
-        // check if the payload is a online packet.
+        if self.is_disconnected() {
+            // frames sent after the client disconnected are dropped.
+            return;
+        }
+
+        if buffer
+            .first()
+            .is_some_and(|id| self.user_packet_ids.contains(id))
+        {
+            // this is a packet for the user, such as a game packet.
+            if self.state != ConnectionState::Connected {
+                // the client hasn't completed the handshake, it has no business
+                // sending game packets yet.
+                if self.buffer_early_packets && self.early_packets.len() < MAX_EARLY_PACKETS {
+                    self.early_packets.push(buffer);
+                } else {
+                    rak_debug!(
+                        "[RakNet] [{}] Dropping a game packet sent before the handshake completed.",
+                        self.address
+                    );
+                }
+            } else {
+                self.event_dispatch
+                    .push_back(RakEvent::GamePacket(self.address.clone(), buffer));
+            }
+            return;
+        }
+
+        // anything else has to be an online packet.
         if let Ok(packet) = Packet::compose(&buffer, &mut 0) {
             // this is a packet! let's check the variety.
             if packet.is_online() {
@@ -226,57 +600,205 @@ impl Connection {
                 self.disconnect("Incorrect protocol usage within raknet.", true);
             }
         } else {
-            // this isn't an online packet we know about, so we're going to emit an event here.
-            // this is probably a game packet.
+            rak_debug!(
+                "[RakNet] [{}] Dropping a packet that could not be decoded.",
+                self.address
+            );
+        }
+    }
+
+    /// Called once the client completes the handshake.
+    /// Any game packets held until now are dispatched after the `Connect` event.
+    pub(crate) fn complete_handshake(&mut self) {
+        self.state = ConnectionState::Connected;
+        self.handshake_deadline = None;
+        self.connected_at = Some(SystemTime::now());
+        self.event_dispatch.push_back(RakEvent::Connect(
+            self.address.clone(),
+            self.client_guid.unwrap_or_default(),
+        ));
+
+        for buffer in std::mem::take(&mut self.early_packets) {
             self.event_dispatch
                 .push_back(RakEvent::GamePacket(self.address.clone(), buffer));
         }
     }
 
+    /// Disconnects the client.
+    ///
+    /// When the disconnect is server initiated, the client is sent a `Disconnect` notification
+    /// after everything that is already queued, and the connection is kept around until the
+    /// next tick has flushed it. Nothing else can be sent to the client in the meantime.
     pub fn disconnect<S: Into<String>>(&mut self, reason: S, server_initiated: bool) {
+        if self.is_disconnecting() {
+            // the notification is already on its way, if the client is the one leaving now
+            // there's no reason to wait for it anymore.
+            if !server_initiated {
+                self.close();
+            }
+            return;
+        }
+
         // disconnect!!!
         self.event_dispatch
             .push_back(RakEvent::Disconnect(self.address.clone(), reason.into()));
+
+        if server_initiated {
+            self.queue.push(
+                Packet::from(Disconnect {}).parse().unwrap(),
+                SendPriority::Normal,
+            );
+            // Freeze the queue, so the notification is the last thing the client gets.
+            self.queue.frozen = true;
+            self.state = ConnectionState::Disconnecting;
+        } else {
+            self.close();
+        }
+    }
+
+    /// Marks the connection to be removed from the server, anything still queued is dropped.
+    fn close(&mut self) {
         // actually handle this internally, cause we can't send packets if we're disconnected.
         self.state = ConnectionState::Offline;
         // the following is a hack to make sure the connection is removed from the server.
         self.ensure_disconnect = true;
         // We also need to flush the queue so packets aren't sent, because they are now useless.
         self.queue.flush();
-        // Freeze the queue, just in case this is a server sided disconnect.
-        // Otherwise this is useless.
         self.queue.frozen = true;
+        self.rakhandler.outbound.clear();
+    }
 
-        if server_initiated {
-            self.send_packet(Disconnect {}.into(), SendPriority::Immediate);
+    /// Tears down the current session so the client can go through the handshake again.
+    /// Unlike `disconnect` the connection is kept around.
+    pub(crate) fn reset_session<S: Into<String>>(&mut self, reason: S) {
+        self.event_dispatch
+            .push_back(RakEvent::Disconnect(self.address.clone(), reason.into()));
+        self.state = ConnectionState::Unidentified;
+        self.state_before_timeout = None;
+        self.queue = Queue::new();
+        self.rakhandler = RakConnHandlerMeta::new();
+        self.connection_accept = None;
+        self.connected_at = None;
+        self.early_packets.clear();
+        self.outstanding_ping = None;
+        self.missed_pings = 0;
+    }
+
+    /// A snapshot of how much has been sent to and received from the client.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.clone()
+    }
+
+    /// A snapshot of the address, state, mtu and latency of the connection.
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            address: self.address.clone(),
+            state: self.state.clone(),
+            mtu: self.mtu,
+            latency: self.latency(),
         }
     }
 
+    /// The amount of fragmented frames still waiting for the rest of their fragments.
+    pub fn pending_fragments(&self) -> usize {
+        self.rakhandler.fragmented_frames.len()
+    }
+
+    /// Sends the client a `ConnectedPing`, the pong it answers with updates the latency.
+    pub(crate) fn ping(&mut self) {
+        let ping = ConnectedPing {
+            time: SystemTime::now()
+                .duration_since(self.start_time)
+                .unwrap_or_default()
+                .as_millis() as i64,
+        };
+        self.last_ping = Some(SystemTime::now());
+        self.outstanding_ping = Some(ping.time);
+        // the ping is framed, sending it can only fail by the time it is flushed.
+        let _ = self.send_packet(ping.into(), SendPriority::Immediate);
+    }
+
+    /// Whether or not the client failed to connect before its handshake deadline.
+    pub fn is_handshake_expired(&self) -> bool {
+        self.handshake_deadline
+            .is_some_and(|deadline| SystemTime::now() >= deadline)
+    }
+
     /// This reads an internal value! This may not be in relation to the client's CURRENT state!
     pub fn is_disconnected(&self) -> bool {
         return self.ensure_disconnect == true;
     }
 
+    /// Whether or not the connection is disconnected, or is being disconnected by the server.
+    pub fn is_disconnecting(&self) -> bool {
+        self.state == ConnectionState::Disconnecting || self.is_disconnected()
+    }
+
     /// This is called every RakNet tick.
     /// This is used to update the connection state and send `Priority::Normal` packets.
     /// as well as other internal stuff like updating flushing Ack and Nack.
     pub fn tick(&mut self) {
-        if self.state.is_reliable() {
-            // we need to update the state of the connection.
-            // check whether or not we're becoming un-reliable.
-            if self.recv_time.elapsed().unwrap().as_secs() > 8 {
-                // we're becoming un-reliable.
-                self.state = ConnectionState::TimingOut;
-            }
-            // tick the rakhandler
+        if self.state == ConnectionState::Disconnecting {
+            // send the disconnect notification, after which the connection can be removed.
             RakConnHandler::tick(self);
-        } else {
-            if self.recv_time.elapsed().unwrap().as_secs() >= 15 {
-                // we're not reliable anymore.
-                self.state = ConnectionState::Disconnected;
-                self.disconnect("Timed Out", true);
-                return;
+            self.state = ConnectionState::Disconnected;
+            self.ensure_disconnect = true;
+            return;
+        }
+
+        let idle = self.recv_time.elapsed().unwrap_or_default();
+
+        if idle >= self.timeout {
+            // we haven't heard from the client in too long, they're gone.
+            // there's no one left to notify.
+            if !self.is_disconnected() {
+                self.disconnect("Timed Out", false);
             }
+            return;
+        }
+
+        if self.is_disconnected()
+            || matches!(
+                self.state,
+                ConnectionState::Disconnected | ConnectionState::Offline
+            )
+        {
+            // there's nothing left to do for the connection.
+            return;
         }
+
+        // we need to update the state of the connection.
+        // check whether or not we're becoming un-reliable.
+        if self.state.is_reliable() && idle > self.timeout / 2 {
+            // we're becoming un-reliable.
+            self.state_before_timeout = Some(self.state.clone());
+            self.state = ConnectionState::TimingOut;
+        }
+
+        // ping the client every now and then, so we know their latency.
+        // while the connection is timing out, this is what keeps it alive.
+        let connected = self.state == ConnectionState::Connected
+            || (self.state == ConnectionState::TimingOut
+                && self.state_before_timeout == Some(ConnectionState::Connected));
+        if connected {
+            let due = self
+                .last_ping
+                .is_none_or(|time| time.elapsed().unwrap_or_default() >= self.ping_interval);
+
+            if due {
+                if self.outstanding_ping.is_some() {
+                    // the client never answered the last ping.
+                    self.missed_pings += 1;
+                    if self.missed_pings >= self.max_missed_pings {
+                        self.disconnect("Connection lost", false);
+                        return;
+                    }
+                }
+                self.ping();
+            }
+        }
+
+        // tick the rakhandler
+        RakConnHandler::tick(self);
     }
 }
@@ -4,4 +4,8 @@ pub mod conn;
 /// Connection states
 pub mod state;
 
+/// Connection statistics.
+mod stats;
+
 pub use self::conn::*;
+pub use self::stats::*;
@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use super::state::ConnectionState;
+
+/// A snapshot of how much a connection has sent and received.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// The amount of bytes sent to the client.
+    pub bytes_sent: u64,
+    /// The amount of bytes received from the client.
+    pub bytes_recv: u64,
+    /// The amount of datagrams sent to the client.
+    pub packets_sent: u64,
+    /// The amount of datagrams received from the client.
+    pub packets_recv: u64,
+    /// The amount of fragmented frames dropped because they weren't completed in time.
+    pub fragments_dropped: u64,
+}
+
+/// A snapshot of a connection, as seen by the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// The `ip:port` of the client.
+    pub address: String,
+    /// The state the connection was in.
+    pub state: ConnectionState,
+    /// The mtu size negotiated with the client.
+    pub mtu: u16,
+    /// The round trip time to the client, `None` until the client has answered a ping.
+    pub latency: Option<Duration>,
+}
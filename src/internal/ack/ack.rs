@@ -1,8 +1,10 @@
-use std::{io::Cursor, ops::Range};
+use std::io::Cursor;
 
 use binary_utils::Streamable;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt, BE};
 
+use crate::internal::util::u24_before;
+
 /// An ack record.
 /// A record holds a single or range of acked packets.
 /// No real complexity other than that.
@@ -24,9 +26,14 @@ pub struct RangeRecord {
 }
 
 impl RangeRecord {
-    /// Fixes the end of the range if it is lower than the start.
+    /// Whether the range crosses the 24-bit wraparound, such as `0xfffffe` to `1`.
+    pub fn wraps(&self) -> bool {
+        self.end < self.start && u24_before(self.start, self.end)
+    }
+
+    /// Fixes the end of the range if it is lower than the start, unless the range wraps around.
     pub fn fix(&mut self) {
-        if self.end < self.start {
+        if self.end < self.start && !self.wraps() {
             let temp = self.end;
             self.end = self.start;
             self.start = temp;
@@ -50,38 +57,69 @@ impl Ack {
         }
     }
 
-    pub fn push_record(&mut self, seq: u32) {
-        self.records
-            .push(Record::Single(SingleRecord { sequence: seq }));
-    }
+    /// Builds an ack (or nack) for the given sequences.
+    /// Runs of consecutive sequences are compressed into a single range record.
+    pub fn from_sequences(mut sequences: Vec<u32>, nack: bool) -> Self {
+        sequences.sort_unstable();
+        sequences.dedup();
 
-    pub fn from_missing(missing: Vec<u32>) -> Self {
         let mut records: Vec<Record> = Vec::new();
-        let mut current: Range<u32> = 0..0;
-
-        for m in missing {
-            if current.end + 1 == m {
-                current.end += 1;
-            } else if m > current.end {
-                // This is a new range.
-                records.push(Record::Range(RangeRecord {
-                    start: current.start,
-                    end: current.end,
-                }));
-                current.start = m;
-                current.end = m;
-            } else {
-                // This is a new single.
-                records.push(Record::Single(SingleRecord { sequence: m }));
-                current.start = m + 1;
-                current.end = m + 1;
+        let mut sequences = sequences.into_iter();
+
+        if let Some(first) = sequences.next() {
+            let mut current: (u32, u32) = (first, first);
+            for seq in sequences {
+                if seq == current.1 + 1 {
+                    // this extends the current run.
+                    current.1 = seq;
+                } else {
+                    records.push(Self::record(current));
+                    current = (seq, seq);
+                }
             }
+            records.push(Self::record(current));
         }
 
-        let mut nack = Self::new(records.len().try_into().unwrap(), true);
-        nack.records = records;
+        let mut ack = Self::new(records.len() as u16, nack);
+        ack.records = records;
+        ack
+    }
 
-        return nack;
+    /// Splits the ack into as many acks as it takes for each of them to be at most
+    /// `max_size` bytes once encoded.
+    pub fn split(self, max_size: usize) -> Vec<Self> {
+        let nack = self.id == 0xa0;
+        let mut acks = Vec::new();
+        let mut current = Self::new(0, nack);
+        // the id and the record count.
+        let mut size = 3;
+
+        for record in self.records {
+            let record_size = match record {
+                Record::Single(_) => 4,
+                Record::Range(_) => 7,
+            };
+            if !current.records.is_empty() && size + record_size > max_size {
+                current.count = current.records.len() as u16;
+                acks.push(current);
+                current = Self::new(0, nack);
+                size = 3;
+            }
+            size += record_size;
+            current.records.push(record);
+        }
+
+        current.count = current.records.len() as u16;
+        acks.push(current);
+        acks
+    }
+
+    fn record((start, end): (u32, u32)) -> Record {
+        if start == end {
+            Record::Single(SingleRecord { sequence: start })
+        } else {
+            Record::Range(RangeRecord { start, end })
+        }
     }
 }
 
@@ -112,20 +150,20 @@ impl Streamable for Ack {
         position: &mut usize,
     ) -> Result<Self, binary_utils::error::BinaryError> {
         let mut stream = Cursor::new(source);
-        let id = stream.read_u8().unwrap();
-        let count = stream.read_u16::<BE>().unwrap();
+        let id = stream.read_u8()?;
+        let count = stream.read_u16::<BE>()?;
         let mut records: Vec<Record> = Vec::new();
         for _ in 0..count {
-            if stream.read_u8().unwrap() == 1 {
+            if stream.read_u8()? == 1 {
                 let record: SingleRecord = SingleRecord {
-                    sequence: stream.read_u24::<LittleEndian>().unwrap(),
+                    sequence: stream.read_u24::<LittleEndian>()?,
                 };
 
                 records.push(Record::Single(record));
             } else {
                 let record: RangeRecord = RangeRecord {
-                    start: stream.read_u24::<LittleEndian>().unwrap(),
-                    end: stream.read_u24::<LittleEndian>().unwrap(),
+                    start: stream.read_u24::<LittleEndian>()?,
+                    end: stream.read_u24::<LittleEndian>()?,
                 };
 
                 records.push(Record::Range(record));
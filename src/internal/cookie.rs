@@ -0,0 +1,53 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a cookie is valid for, in seconds.
+/// A cookie is accepted for the window it was generated in and the one after it.
+const COOKIE_WINDOW: u64 = 10;
+
+/// Generates the cookies sent in `OpenConnectReply`, these prove a client
+/// actually owns the address it is connecting from.
+///
+/// The cookie is derived from the address and a secret that is random for every server,
+/// so the server doesn't need to remember anything until the client echoes it back.
+#[derive(Debug, Clone)]
+pub struct CookieJar {
+    secret: RandomState,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self {
+            secret: RandomState::new(),
+        }
+    }
+
+    /// Generates the cookie for the given address.
+    pub fn generate(&self, address: &SocketAddr) -> u32 {
+        self.generate_in(address, Self::window())
+    }
+
+    /// Whether or not the cookie was generated for the given address recently.
+    pub fn verify(&self, address: &SocketAddr, cookie: u32) -> bool {
+        let window = Self::window();
+        cookie == self.generate_in(address, window)
+            || cookie == self.generate_in(address, window.saturating_sub(1))
+    }
+
+    fn generate_in(&self, address: &SocketAddr, window: u64) -> u32 {
+        let mut hasher = self.secret.build_hasher();
+        address.hash(&mut hasher);
+        window.hash(&mut hasher);
+        hasher.finish() as u32
+    }
+
+    fn window() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / COOKIE_WINDOW
+    }
+}
@@ -1,3 +1,8 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::Frame;
+
 /// The information for the given fragment.
 /// This is used to determine how to reassemble the frame.
 #[derive(Debug, Clone)]
@@ -12,3 +17,28 @@ pub struct FragmentMeta {
     /// This is the arrangement of the fragments in the frame.
     pub(crate) index: u32,
 }
+
+/// The fragments of a frame that have been received so far.
+#[derive(Debug, Clone)]
+pub struct FragmentGroup {
+    /// When the first fragment of the group was received.
+    /// Groups that aren't completed in time are evicted.
+    pub created: SystemTime,
+    /// The amount of fragments the frame was split into.
+    pub size: u32,
+    /// The fragments received so far, by their index.
+    pub parts: HashMap<u32, Frame>,
+    /// The amount of bytes received so far.
+    pub bytes: usize,
+}
+
+impl FragmentGroup {
+    pub fn new(size: u32) -> Self {
+        Self {
+            created: SystemTime::now(),
+            size,
+            parts: HashMap::new(),
+            bytes: 0,
+        }
+    }
+}
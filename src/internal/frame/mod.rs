@@ -3,6 +3,7 @@ pub mod fragment;
 #[allow(dead_code)]
 pub mod reliability;
 
+use std::fmt;
 use std::io::{Cursor, Write};
 
 use binary_utils::error::BinaryError;
@@ -12,6 +13,35 @@ use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use self::fragment::FragmentMeta;
 use self::reliability::Reliability;
 
+/// Errors that can occur while decoding a frame from the wire.
+#[derive(Debug)]
+pub enum FrameDecodeError {
+    /// The body length of the frame exceeds the bytes left in the buffer.
+    ///
+    /// **Tuple Values**:
+    /// 1. The length of the body the frame claims.
+    /// 2. The amount of bytes remaining in the buffer.
+    BodyOutOfBounds(usize, usize),
+}
+
+impl fmt::Display for FrameDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameDecodeError::BodyOutOfBounds(size, remaining) => write!(
+                f,
+                "Frame body of {} bytes exceeds the {} remaining bytes",
+                size, remaining
+            ),
+        }
+    }
+}
+
+impl From<FrameDecodeError> for BinaryError {
+    fn from(e: FrameDecodeError) -> Self {
+        BinaryError::RecoverableKnown(e.to_string())
+    }
+}
+
 /// Frames are a encapsulation of a packet or packets.
 /// They are used to send packets to the connection in a reliable way.
 #[derive(Debug, Clone)]
@@ -95,44 +125,26 @@ impl Streamable for FramePacket {
         let mut stream = Cursor::new(source);
         stream.set_position(*position as u64);
         stream.read_u8()?;
-        let mut frames: Vec<Frame> = Vec::new();
         let sequence = stream.read_u24::<LittleEndian>()?;
-        let mut offset: usize = stream.position() as usize;
-
-        loop {
-            if stream.position() > source.len() as u64 {
-                return Ok(FramePacket {
-                    reliability: Reliability::ReliableOrd,
-                    sequence,
-                    frames,
-                    byte_length: 0,
-                });
-            }
-
-            if stream.position() == source.len() as u64 {
-                break Ok(FramePacket {
-                    reliability: Reliability::ReliableOrd,
-                    sequence,
-                    frames,
-                    byte_length: 0,
-                });
-            }
 
-            if let Ok(frame) = Frame::compose(&source, &mut offset) {
-                stream.set_position(offset as u64);
-                frames.push(frame.clone());
+        // every frame advances the offset by the amount of bytes it consumed,
+        // once we reach the end of the source we have read every frame.
+        let start = *position;
+        let mut offset = stream.position() as usize;
+        let mut frames: Vec<Frame> = Vec::new();
 
-                // if frame.parse()?.len() + stream.position() as usize > source.len() {
-                //     return Ok(FramePacket { sequence, frames, byte_length: 0 });
-                // } else {
-                //     continue;
-                // }
-            } else {
-                return Err(BinaryError::RecoverableKnown(
-                    "Frame composition failed! Failed to read frame.".into(),
-                ));
-            }
+        while offset < source.len() {
+            frames.push(Frame::compose(source, &mut offset)?);
         }
+
+        *position = offset;
+
+        Ok(FramePacket {
+            reliability: Reliability::ReliableOrd,
+            sequence,
+            frames,
+            byte_length: offset - start,
+        })
     }
 
     fn parse(&self) -> Result<Vec<u8>, BinaryError> {
@@ -149,14 +161,15 @@ impl Streamable for FramePacket {
 }
 
 /// An individual data frame, these are constructed from a payload.
+///
+/// Frames do not carry a sequence of their own, the datagram sequence is
+/// stored on the `FramePacket` the frame is sent in.
+/// The length of the body is not stored either, it is always derived from `body`.
 #[derive(Debug, Clone)]
 pub struct Frame {
     /// The flags for this frame, the first 3 bits are reserved for the reliability while the 4th
     /// bit is used to represent if this is a fragment.
     pub flags: u8,
-    /// The length of the body of the frame.
-    /// This is sized to 24 bits internally, so any number here must be within that range.
-    pub size: u16,
     /// The Reliable index of the frame (if reliable)
     pub reliable_index: Option<u32>,
     /// The sequenced index of the frame (if sequenced)
@@ -178,6 +191,9 @@ pub struct Frame {
     pub reliability: Reliability,
     /// The body of the frame, this is the payload of the frame.
     pub body: Vec<u8>,
+    /// The tag the sender is given back once the frame is acknowledged, or lost.
+    /// This is never sent to the other side.
+    pub receipt: Option<u32>,
 }
 
 impl Frame {
@@ -186,7 +202,6 @@ impl Frame {
     pub fn init() -> Self {
         Self {
             flags: 0,
-            size: 0,
             reliable_index: None,
             sequence_index: None,
             order_index: None,
@@ -194,6 +209,7 @@ impl Frame {
             fragment_meta: None,
             reliability: Reliability::Unreliable,
             body: Vec::new(),
+            receipt: None,
         }
     }
 
@@ -223,8 +239,8 @@ impl Streamable for Frame {
         // set the reliability
         frame.reliability = Reliability::from_flags(frame.flags);
 
-        // read the length of the body in bits
-        frame.size = stream.read_u16::<BigEndian>()? / 8;
+        // read the length of the body in bits, rounding up to the nearest byte.
+        let size = (stream.read_u16::<BigEndian>()? as usize).div_ceil(8);
 
         // check whether or not this frame is reliable, if it is, read the reliable index
         if frame.reliability.is_reliable() {
@@ -246,18 +262,24 @@ impl Streamable for Frame {
         // check whether or not this frame is fragmented, if it is, read the fragment meta
         if (frame.flags & 0x10) > 0 {
             frame.fragment_meta = Some(FragmentMeta {
-                size: stream.read_u32::<BigEndian>()?.try_into().unwrap(),
+                size: stream.read_u32::<BigEndian>()?,
                 id: stream.read_u16::<BigEndian>()?,
-                index: stream.read_u32::<BigEndian>()?.try_into().unwrap(),
+                index: stream.read_u32::<BigEndian>()?,
             });
         }
 
-        // read the body
-        frame.body = (&source
-            [stream.position() as usize..stream.position() as usize + frame.size as usize])
+        // read the body, making sure the frame doesn't claim more than we have.
+        let start = stream.position() as usize;
+        let end = start + size;
+        frame.body = source
+            .get(start..end)
+            .ok_or(FrameDecodeError::BodyOutOfBounds(
+                size,
+                source.len().saturating_sub(start),
+            ))?
             .to_vec();
         // update the position.
-        *position = stream.position() as usize + frame.size as usize;
+        *position = end;
 
         Ok(frame)
     }
@@ -272,12 +294,18 @@ impl Streamable for Frame {
             flags |= 0x10;
         }
 
-        let size = self.body.len() as u16;
+        // the length is written in bits, larger bodies can't be described.
+        let size = u16::try_from(self.body.len() * 8).map_err(|_| {
+            BinaryError::RecoverableKnown(format!(
+                "Frame body of {} bytes is too large to encode",
+                self.body.len()
+            ))
+        })?;
 
         // write the flags
         stream.write_u8(flags)?;
         // write the length of the body in bits
-        stream.write_u16::<BigEndian>(size * 8)?;
+        stream.write_u16::<BigEndian>(size)?;
 
         // check whether or not this frame is reliable, if it is, write the reliable index
         if self.reliability.is_reliable() {
@@ -310,3 +338,79 @@ impl Streamable for Frame {
         Ok(stream.get_ref().clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELIABILITIES: [Reliability; 8] = [
+        Reliability::Unreliable,
+        Reliability::UnreliableSeq,
+        Reliability::Reliable,
+        Reliability::ReliableOrd,
+        Reliability::ReliableSeq,
+        Reliability::UnreliableAck,
+        Reliability::ReliableAck,
+        Reliability::ReliableOrdAck,
+    ];
+
+    /// A frame with every index its reliability carries, split or not.
+    fn frame(reliability: Reliability, fragmented: bool) -> Frame {
+        let mut frame = Frame::init();
+        frame.reliability = reliability;
+        frame.reliable_index = Some(0x010203);
+        frame.sequence_index = Some(0x040506);
+        frame.order_index = Some(0x070809);
+        frame.order_channel = Some(31);
+        if fragmented {
+            frame.fragment_meta = Some(FragmentMeta {
+                size: 3,
+                id: 0x0a0b,
+                index: 2,
+            });
+        }
+        frame.body = vec![0xfe, 1, 2, 3];
+        frame
+    }
+
+    #[test]
+    fn frames_round_trip_byte_for_byte() {
+        for reliability in RELIABILITIES {
+            for fragmented in [false, true] {
+                let bytes = frame(reliability, fragmented).parse().unwrap();
+                let mut offset = 0;
+                let decoded = Frame::compose(&bytes, &mut offset).unwrap();
+
+                assert_eq!(offset, bytes.len());
+                assert_eq!(decoded.parse().unwrap(), bytes, "{:?}", reliability);
+                assert_eq!(decoded.reliability, reliability);
+                assert_eq!(decoded.is_fragmented(), fragmented);
+                assert_eq!(decoded.body, [0xfe, 1, 2, 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_bodies_are_not_encoded() {
+        let mut frame = frame(Reliability::Unreliable, false);
+        frame.body = vec![0xfe; 8191];
+        assert_eq!(frame.parse().unwrap()[1..3], [0xff, 0xf8]);
+        frame.body.push(0xfe);
+        assert!(frame.parse().is_err());
+    }
+
+    #[test]
+    fn split_reliable_ordered_frame_layout() {
+        let bytes = frame(Reliability::ReliableOrd, true).parse().unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x70, 0x00, 0x20, // flags and the body length in bits
+                0x03, 0x02, 0x01, // reliable index
+                0x09, 0x08, 0x07, 31, // order index and channel
+                0x00, 0x00, 0x00, 0x03, 0x0a, 0x0b, 0x00, 0x00, 0x00, 0x02, // fragment meta
+                0xfe, 1, 2, 3,
+            ]
+        );
+    }
+}
@@ -1,10 +1,15 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
 
 // this is a cache store for packets,
 // any packets in here will be ticked, and over time, will be resent, or discarded
 #[derive(Debug, Clone)]
 pub struct CacheStore<K, V> {
     pub(crate) store: HashMap<K, (SystemTime, Vec<V>)>,
+    // how many times each entry has been returned by `expired`
+    pub(crate) tries: HashMap<K, u32>,
 }
 
 impl<K, V> CacheStore<K, V>
@@ -15,6 +20,7 @@ where
     pub fn new() -> Self {
         Self {
             store: HashMap::new(),
+            tries: HashMap::new(),
         }
     }
 
@@ -34,17 +40,59 @@ where
         ent.1.extend(buffers);
     }
 
-    // clear old packets from the cache and return them
-    pub fn flush(&mut self) -> Vec<(K, SystemTime, Vec<V>)> {
-        let mut flushed = Vec::new();
-        for (sequence, (time, frames)) in self.store.drain() {
-            flushed.push((sequence, time, frames));
+    pub fn flush_key(&mut self, key: K) -> Option<(SystemTime, Vec<V>)> {
+        self.tries.remove(&key);
+        self.store.remove(&key)
+    }
+
+    // returns the keys of the entries that have been waiting longer than `timeout`, so they can be
+    // sent again. the wait restarts for every key returned, entries that have already been
+    // returned `max_tries` times are discarded instead.
+    pub fn expired(&mut self, timeout: Duration, max_tries: u32) -> Vec<K>
+    where
+        K: Clone,
+    {
+        let mut expired = Vec::new();
+        let mut discarded = Vec::new();
+
+        for (key, (time, _)) in self.store.iter_mut() {
+            if time.elapsed().unwrap_or_default() < timeout {
+                continue;
+            }
+
+            let tries = self.tries.entry(key.clone()).or_insert(0);
+            if *tries >= max_tries {
+                discarded.push(key.clone());
+                continue;
+            }
+
+            *tries += 1;
+            *time = SystemTime::now();
+            expired.push(key.clone());
+        }
+
+        for key in discarded {
+            self.flush_key(key);
         }
-        return flushed;
+        expired
     }
 
-    pub fn flush_key(&mut self, key: K) -> Option<(SystemTime, Vec<V>)> {
-        self.store.remove(&key)
+    // moves the entry to a new key, keeping how many times it has been tried.
+    // the wait for the entry restarts, the buffers are returned so they can be updated.
+    pub fn rekey(&mut self, from: K, to: K) -> Option<&mut Vec<V>>
+    where
+        K: Clone,
+    {
+        let (_, buffers) = self.store.remove(&from)?;
+        if let Some(tries) = self.tries.remove(&from) {
+            self.tries.insert(to.clone(), tries);
+        }
+        let ent = self
+            .store
+            .entry(to)
+            .or_insert((SystemTime::now(), Vec::new()));
+        ent.1.extend(buffers);
+        Some(&mut ent.1)
     }
 
     pub fn has(&self, key: &K) -> bool {
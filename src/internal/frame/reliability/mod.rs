@@ -1,6 +1,7 @@
 pub mod cache;
+pub mod window;
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Reliability {
     /// Unreliable (with no ack)
@@ -12,12 +13,17 @@ pub enum Reliability {
     ReliableOrd,
     /// Reliably sequenced **AND** ordered
     ReliableSeq,
+    /// Unreliable, the sender gets a receipt once it is acknowledged or lost.
     UnreliableAck,
+    /// Reliable, the sender gets a receipt once it is acknowledged or lost.
     ReliableAck,
+    /// Reliably ordered, the sender gets a receipt once it is acknowledged or lost.
     ReliableOrdAck,
 }
 
 impl Reliability {
+    /// Reads the reliability from the flags of a frame.
+    /// This expects the whole flags byte, the reliability is stored in the top 3 bits.
     pub fn from_flags(flags: u8) -> Self {
         match (flags & 224) >> 5 {
             0 => Reliability::Unreliable,
@@ -33,6 +39,7 @@ impl Reliability {
         }
     }
 
+    /// The reliability as it is stored in the flags of a frame, so `from_flags` reads it back.
     pub fn to_flags(&self) -> u8 {
         match self {
             Reliability::Unreliable => 0 << 5,
@@ -49,7 +56,7 @@ impl Reliability {
     /// Whether or not the packet is ordered.
     pub fn is_ordered(&self) -> bool {
         match self {
-            Self::UnreliableSeq | Self::ReliableOrd | Self::ReliableOrdAck => true,
+            Self::ReliableOrd | Self::ReliableOrdAck => true,
             _ => false,
         }
     }
@@ -57,7 +64,11 @@ impl Reliability {
     /// Whether or not the packet is reliable.
     pub fn is_reliable(&self) -> bool {
         match self {
-            Self::Reliable | Self::ReliableOrd | Self::ReliableSeq | Self::ReliableOrdAck => true,
+            Self::Reliable
+            | Self::ReliableOrd
+            | Self::ReliableSeq
+            | Self::ReliableAck
+            | Self::ReliableOrdAck => true,
             _ => false,
         }
     }
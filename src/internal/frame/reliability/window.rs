@@ -0,0 +1,48 @@
+use std::collections::HashSet;
+
+use crate::internal::util::{u24_distance, u24_next, MAX_U24};
+
+/// How far ahead of the lowest missing index we accept reliable frames.
+/// This bounds how many indexes we have to remember.
+const WINDOW_SIZE: u32 = 0x1_0000;
+
+/// Keeps track of the reliable indexes that have been received, so resent frames
+/// are only handled once.
+///
+/// Everything below `start` has been received, the indexes after it that arrived
+/// early are kept until the gap before them is filled.
+#[derive(Debug, Clone)]
+pub struct ReliableWindow {
+    /// The lowest reliable index that hasn't been received yet.
+    start: u32,
+    /// The reliable indexes past `start` that have already been received.
+    received: HashSet<u32>,
+}
+
+impl ReliableWindow {
+    pub fn new() -> Self {
+        Self {
+            start: 0,
+            received: HashSet::new(),
+        }
+    }
+
+    /// Marks the index as received.
+    /// Returns `false` if the index was already received or is too far ahead of the window,
+    /// and the frame should be dropped.
+    pub fn insert(&mut self, index: u32) -> bool {
+        let index = index & MAX_U24;
+        // how far ahead of the window the index is, taking the wraparound into account.
+        // indexes behind the window wrap around to a huge distance, so they're dropped too.
+        let distance = u24_distance(self.start, index);
+        if distance >= WINDOW_SIZE || !self.received.insert(index) {
+            return false;
+        }
+
+        // move the window past every index we have without a gap.
+        while self.received.remove(&self.start) {
+            self.start = u24_next(self.start);
+        }
+        true
+    }
+}
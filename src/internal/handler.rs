@@ -3,27 +3,65 @@ use std::{
     collections::{HashMap, HashSet},
     fmt,
     io::Write,
+    time::{Duration, SystemTime},
 };
 
-use crate::connection::Connection;
+use crate::connection::{state::ConnectionState, Connection};
+use crate::server::RakEvent;
+use crate::UDP_HEADER_SIZE;
 
 use super::{
     ack::{Ack, Record},
     frame::{
-        reliability::{cache::CacheStore, Reliability},
+        fragment::FragmentGroup,
+        reliability::{cache::CacheStore, window::ReliableWindow, Reliability},
         Frame, FramePacket,
     },
     queue::OrderedQueue,
+    util::{u24_before, u24_distance, u24_next, MAX_U24},
 };
 
-#[cfg(feature = "debug")]
 use crate::rak_debug;
 
+/// The largest gap in datagram sequences we'll request again with a NACK.
+/// Anything larger is most likely a misbehaving client, so we won't track it.
+const MAX_NACK_GAP: u32 = 512;
+
+/// RakNet only has this many order channels.
+pub(crate) const MAX_ORDER_CHANNELS: u8 = 32;
+
 #[derive(Debug)]
 pub enum RakHandlerError {
     Unknown(String),
     BinaryError(binary_utils::error::BinaryError),
     UnknownPacket(u8),
+    /// A frame claims to be split into more fragments than we allow.
+    TooManyFragments(u32),
+    /// A fragment doesn't fit in the frame it belongs to, or was already received.
+    InvalidFragment(u16, u32),
+    /// A fragmented frame is larger than we allow.
+    FragmentedFrameTooLarge(u16),
+    /// A client has more fragmented frames waiting for reassembly than we allow.
+    TooManyFragmentedFrames(usize),
+    /// A frame is on an order channel that doesn't exist.
+    InvalidChannel(u8),
+    /// An order channel holds back more packets than we allow.
+    OrderedQueueFull(u8),
+}
+
+impl RakHandlerError {
+    /// Whether or not the client broke the protocol, rather than sending something we can't read.
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(
+            self,
+            RakHandlerError::TooManyFragments(_)
+                | RakHandlerError::InvalidFragment(_, _)
+                | RakHandlerError::FragmentedFrameTooLarge(_)
+                | RakHandlerError::TooManyFragmentedFrames(_)
+                | RakHandlerError::InvalidChannel(_)
+                | RakHandlerError::OrderedQueueFull(_)
+        )
+    }
 }
 
 impl fmt::Display for RakHandlerError {
@@ -32,6 +70,28 @@ impl fmt::Display for RakHandlerError {
             RakHandlerError::Unknown(s) => write!(f, "Unknown error: {}", s),
             RakHandlerError::BinaryError(e) => write!(f, "Binary error: {:?}", e),
             RakHandlerError::UnknownPacket(p) => write!(f, "Unknown packet: {}", p),
+            RakHandlerError::TooManyFragments(size) => {
+                write!(f, "Frame is split into too many fragments: {}", size)
+            }
+            RakHandlerError::InvalidFragment(id, index) => {
+                write!(f, "Invalid fragment {} of frame {}", index, id)
+            }
+            RakHandlerError::FragmentedFrameTooLarge(id) => {
+                write!(f, "Fragmented frame {} is too large", id)
+            }
+            RakHandlerError::TooManyFragmentedFrames(count) => {
+                write!(
+                    f,
+                    "Too many fragmented frames waiting for reassembly: {}",
+                    count
+                )
+            }
+            RakHandlerError::InvalidChannel(channel) => {
+                write!(f, "Invalid order channel: {}", channel)
+            }
+            RakHandlerError::OrderedQueueFull(channel) => {
+                write!(f, "Too many packets held back on order channel {}", channel)
+            }
         }
     }
 }
@@ -62,13 +122,28 @@ pub struct RakConnHandlerMeta {
     pub ack: CacheStore<u32, Vec<u8>>,
     /// A queue to send back to the client to acknowledge we've recieved these packets.
     pub ack_counts: HashSet<u32>,
-    /// The ordered channels that have been recieved and are waiting for completion.
-    /// Ordered channels will be reorded once all the packets have been received.
-    pub ordered_channels: OrderedQueue<Vec<u8>>,
+    /// The reliable indexes we've received from the client, so resent frames are dropped.
+    pub reliable_window: ReliableWindow,
+    /// The highest datagram sequence we've received from the client.
+    /// Any sequence we skip over is added to `nack`.
+    pub recv_seq: Option<u32>,
+    /// The datagram sequences we skipped over and haven't received since, up to
+    /// `MAX_NACK_GAP` behind `recv_seq`. Sequences in that range that aren't in here
+    /// were already handled.
+    pub missing: HashSet<u32>,
+    /// The ordered channels that have been recieved and are waiting for completion, by order channel.
+    /// Packets on a channel are released once every packet before them has been received.
+    pub ordered_channels: HashMap<u8, OrderedQueue<Vec<u8>>>,
+    /// The highest sequence index received on each order channel.
+    /// Sequenced frames older than this are dropped.
+    pub highest_sequence: HashMap<u8, u32>,
+    /// Sequenced frames that are ahead of the ordered packets on their channel,
+    /// they are held until every ordered packet before them has been received.
+    pub held_sequenced: HashMap<u8, Vec<Frame>>,
     /// The fragmented frames that are waiting for reassembly.
-    pub fragmented_frames: HashMap<u16, HashMap<u32, Frame>>,
-    /// The sequence number used to send packets.
-    /// This is incremented every time we send a packet that is reliable.
+    pub fragmented_frames: HashMap<u16, FragmentGroup>,
+    /// The sequence number used to send the next datagram.
+    /// This is incremented every time we send a frame packet.
     /// Any packets that are reliable, can be re-sent if they are acked.
     pub send_seq: u32,
     /// The next order index to use for ordered channels.
@@ -79,10 +154,19 @@ pub struct RakConnHandlerMeta {
     /// This is incremented every time we send a packet with an order channel.
     pub seq_index: HashMap<u8, u32>,
     /// The next message index, this is basically each reliable message.
-    /// This is incremented every time we send a packet with a reliable channel.
-    pub message_index: HashMap<i16, u32>,
-    /// The fragment id to be used next.
-    pub fragment_ids: HashSet<u16>,
+    /// This is shared by every channel, and incremented for every reliable frame we send,
+    /// including each fragment.
+    pub message_index: u32,
+    /// The fragment id to try next.
+    pub fragment_id: u16,
+    /// The fragment ids that are in flight, along with the reliable datagrams carrying them.
+    /// An id is only used again once each of these is acknowledged or given up on.
+    pub fragment_ids: HashMap<u16, Vec<u32>>,
+    /// Frames that have already been assigned their indexes, waiting to be sent on the next tick.
+    pub outbound: Vec<Frame>,
+    /// The receipts waiting on each datagram we sent, along with the time it was sent.
+    /// A receipt is only given once every datagram carrying it is acknowledged.
+    pub receipts: HashMap<u32, (SystemTime, Vec<u32>)>,
 }
 
 impl RakConnHandlerMeta {
@@ -91,19 +175,65 @@ impl RakConnHandlerMeta {
             nack: HashSet::new(),
             ack: CacheStore::new(),
             ack_counts: HashSet::new(),
-            ordered_channels: OrderedQueue::new(),
+            reliable_window: ReliableWindow::new(),
+            recv_seq: None,
+            missing: HashSet::new(),
+            ordered_channels: HashMap::new(),
+            highest_sequence: HashMap::new(),
+            held_sequenced: HashMap::new(),
             fragmented_frames: HashMap::new(),
             send_seq: 0,
             order_index: HashMap::new(),
-            message_index: HashMap::new(),
+            message_index: 0,
             seq_index: HashMap::new(),
-            fragment_ids: HashSet::new(),
+            fragment_id: 0,
+            fragment_ids: HashMap::new(),
+            outbound: Vec::new(),
+            receipts: HashMap::new(),
+        }
+    }
+
+    /// Records a datagram sequence we received, any sequences that were skipped over
+    /// are NACKed. Returns `false` if the datagram was already handled, or is too old to tell.
+    pub fn track_sequence(&mut self, sequence: u32) -> bool {
+        self.nack.remove(&sequence);
+
+        let expected = self.recv_seq.map_or(0, u24_next);
+        // how far ahead of the next sequence this is, taking the wraparound into account.
+        let distance = u24_distance(expected, sequence);
+
+        if let Some(newest) = self.recv_seq.filter(|_| u24_before(sequence, expected)) {
+            if u24_distance(sequence, newest) > MAX_NACK_GAP {
+                // we don't remember this far back, so it's treated as handled.
+                // it is still acknowledged, so the client stops resending it.
+                return false;
+            }
+            // this is older than the newest datagram, it's only new if we were missing it.
+            return self.missing.remove(&sequence);
+        }
+
+        // if we skipped over any sequences, we need to request them again.
+        // the ones too far behind to request are still remembered as missing.
+        for skipped in
+            (distance.saturating_sub(MAX_NACK_GAP)..distance).map(|i| (expected + i) & MAX_U24)
+        {
+            if distance <= MAX_NACK_GAP {
+                self.nack.insert(skipped);
+            }
+            self.missing.insert(skipped);
         }
+        self.recv_seq = Some(sequence);
+
+        // we stop waiting for datagrams that are too far behind.
+        self.missing
+            .retain(|seq| u24_distance(*seq, sequence) <= MAX_NACK_GAP);
+        true
     }
 
     pub fn next_seq(&mut self) -> u32 {
-        self.send_seq += 1;
-        self.send_seq
+        let seq = self.send_seq;
+        self.send_seq = u24_next(seq);
+        seq
     }
 
     pub fn get_order_index(&mut self, channel: u8) -> u32 {
@@ -113,20 +243,16 @@ impl RakConnHandlerMeta {
     pub fn next_order_index(&mut self, channel: u8) -> u32 {
         let index = self.order_index.entry(channel).or_insert(0);
         let cpy = *index;
-        *index += 1;
+        *index = u24_next(cpy);
+        // sequenced packets are sequenced within an order index, so their sequence starts over.
+        self.seq_index.insert(channel, 0);
         return cpy;
     }
 
-    #[allow(dead_code)]
-    pub fn get_reliable_index(&mut self, channel: u8) -> u32 {
-        *self.message_index.entry(channel.into()).or_insert(0)
-    }
-
-    pub fn next_reliable_index(&mut self, channel: u8) -> u32 {
-        let index = self.message_index.entry(channel.into()).or_insert(0);
-        let cpy = *index;
-        *index += 1;
-        return cpy;
+    pub fn next_reliable_index(&mut self) -> u32 {
+        let index = self.message_index;
+        self.message_index = u24_next(index);
+        index
     }
 
     #[allow(dead_code)]
@@ -137,18 +263,41 @@ impl RakConnHandlerMeta {
     pub fn next_sequence_index(&mut self, channel: u8) -> u32 {
         let index = self.seq_index.entry(channel).or_insert(0);
         let cpy = *index;
-        *index += 1;
+        *index = u24_next(cpy);
         return cpy;
     }
 
+    /// Takes the next fragment id that isn't in flight, wrapping around after `u16::MAX`.
     pub fn next_fragment_id(&mut self) -> u16 {
-        let next = self.fragment_ids.len() as u16;
-        self.fragment_ids.insert(next);
-        next
+        let mut id = self.fragment_id;
+        for _ in 0..u16::MAX {
+            if !self.fragment_ids.contains_key(&id) {
+                break;
+            }
+            id = id.wrapping_add(1);
+        }
+        self.fragment_id = id.wrapping_add(1);
+        self.fragment_ids.insert(id, Vec::new());
+        id
+    }
+
+    /// Frees the fragment ids that are no longer in flight, every reliable datagram carrying
+    /// them was acknowledged or given up on. Unreliable fragments are freed once they are sent.
+    pub fn free_fragment_ids(&mut self) {
+        let ack = &self.ack;
+        self.fragment_ids.retain(|_, sequences| {
+            sequences.retain(|sequence| ack.has(sequence));
+            !sequences.is_empty()
+        });
     }
 
-    pub fn free_fragment_id(&mut self, id: u16) {
-        self.fragment_ids.remove(&id);
+    /// Drops the fragmented frames that haven't been completed within `timeout`.
+    /// Returns how many were dropped.
+    pub fn evict_fragments(&mut self, timeout: Duration) -> usize {
+        let before = self.fragmented_frames.len();
+        self.fragmented_frames
+            .retain(|_, group| group.created.elapsed().unwrap_or_default() < timeout);
+        before - self.fragmented_frames.len()
     }
 }
 
@@ -173,86 +322,88 @@ impl RakConnHandler {
         match id {
             0x80..=0x8d => {
                 // this is a frame packet
-                return Self::handle_raw_frame(connection, payload);
+                Self::handle_raw_frame(connection, payload)
             }
             0xa0 => {
                 // this is an NACK packet, we need to send this packet back!
-                // let's check to see if we even have this packet.
-                let nack = Ack::compose(payload, &mut 0)?;
-
-                // check the records
-                for record in nack.records {
-                    match record {
-                        Record::Single(rec) => {
-                            // we're looking for a single record.
-                            if connection.rakhandler.ack.has(&rec.sequence) {
-                                // flush the cache for only this sequence
-                                if let Some(packets) =
-                                    connection.rakhandler.ack.flush_key(rec.sequence)
-                                {
-                                    for packet in packets.1 {
-                                        connection.send(packet, true);
-                                    }
-                                    connection.rakhandler.ack_counts.remove(&rec.sequence);
-                                }
-                            }
-                            // We don't have this record, but there's nothing we can do about it.
-                        }
-                        Record::Range(mut rec) => {
-                            rec.fix();
-                            // we're looking for a range of records.
-                            // we need to check if we have any of the records in the range.
-                            // we'll check the ack map for each record in the range.
-                            for i in rec.start..rec.end {
-                                if connection.rakhandler.ack.has(&i) {
-                                    // flush the cache for only this sequence
-                                    if let Some(packets) = connection.rakhandler.ack.flush_key(i) {
-                                        for packet in packets.1 {
-                                            connection.send(packet, true);
-                                        }
-                                        connection.rakhandler.ack_counts.remove(&i);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-
-                return Ok(());
+                Self::handle_nack(connection, payload)
             }
             0xc0 => {
                 // this is an ACK packet from the client, we can remove the packet from the ACK list (for real).
-                let ack = Ack::compose(payload, &mut 0)?;
-
-                for record in ack.records {
-                    match record {
-                        Record::Single(rec) => {
-                            // we're looking for a single record.
-                            connection.rakhandler.nack.remove(&rec.sequence);
-                            // connection.rakhandler.ack_counts.remove(&rec.sequence);
-                        }
-                        Record::Range(mut rec) => {
-                            rec.fix();
-                            // we're looking for a range of records.
-                            // we need to check if we have any of the records in the range.
-                            // we'll check the ack map for each record in the range.
-                            for i in rec.start..rec.end {
-                                connection.rakhandler.nack.remove(&i);
-                                // connection.rakhandler.ack_counts.remove(&i);
-                            }
-                        }
-                    }
-                }
-
-                return Ok(());
+                Self::handle_ack(connection, payload)
             }
             _ => {
                 // this is an unknown packet, we don't know what to do with it.
-                return Err(RakHandlerError::UnknownPacket(*id));
+                Err(RakHandlerError::UnknownPacket(*id))
             }
         }
     }
 
+    /// Handles an ACK from the client, the datagrams it covers no longer have to be resent.
+    fn handle_ack(connection: &mut Connection, payload: &[u8]) -> Result<(), RakHandlerError> {
+        let ack = Ack::compose(payload, &mut 0)?;
+
+        for record in ack.records {
+            match record {
+                Record::Single(rec) => {
+                    // the client got this packet, we don't need to resend it.
+                    connection.rakhandler.ack.flush_key(rec.sequence);
+                    Self::acknowledge_receipts(connection, rec.sequence);
+                }
+                Record::Range(mut rec) => {
+                    rec.fix();
+                    for i in Self::in_flight(connection, rec.start, rec.end) {
+                        connection.rakhandler.ack.flush_key(i);
+                        Self::acknowledge_receipts(connection, i);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a NACK from the client, the datagrams it covers are resent right away.
+    fn handle_nack(connection: &mut Connection, payload: &[u8]) -> Result<(), RakHandlerError> {
+        let nack = Ack::compose(payload, &mut 0)?;
+
+        for record in nack.records {
+            match record {
+                Record::Single(rec) => {
+                    // If we don't have this record, there's nothing we can do about it.
+                    Self::resend(connection, rec.sequence);
+                }
+                Record::Range(mut rec) => {
+                    rec.fix();
+                    for i in Self::in_flight(connection, rec.start, rec.end) {
+                        Self::resend(connection, i);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The sequences from `start` up to `end` we're still waiting on an ACK for, in order.
+    /// A range may cover every sequence there is, while only a few datagrams are in flight.
+    /// The range may wrap around, so it is walked from `start` rather than compared against.
+    fn in_flight(connection: &Connection, start: u32, end: u32) -> Vec<u32> {
+        let length = u24_distance(start, end);
+        let mut sequences = connection
+            .rakhandler
+            .ack
+            .store
+            .keys()
+            .chain(connection.rakhandler.receipts.keys())
+            .filter(|sequence| u24_distance(start, **sequence) <= length)
+            .copied()
+            .collect::<Vec<_>>();
+        sequences.sort_unstable_by_key(|sequence| u24_distance(start, *sequence));
+        sequences.dedup();
+        sequences
+    }
+
     /// Handles a raw frame packet.
     /// This packet has not yet been validated nor constructed,
     /// this method will parse and validate the packet as well as performing
@@ -262,58 +413,139 @@ impl RakConnHandler {
         payload: &[u8],
     ) -> Result<(), RakHandlerError> {
         let frame_packet = FramePacket::compose(&payload, &mut 0)?;
+        let sequence = frame_packet.sequence;
 
-        // let's handle each individual frame of the packet
-        for frame in frame_packet.frames {
-            if frame.reliability.is_reliable() {
-                connection
-                    .rakhandler
-                    .ack_counts
-                    .insert(frame_packet.sequence);
-            }
-            if frame.is_fragmented() {
-                // The fragmented frame meta data.
-                let meta = frame.fragment_meta.as_ref().unwrap();
-                // The fragmented frames bounded by this id.
-                let parts = connection
-                    .rakhandler
-                    .fragmented_frames
-                    .entry(meta.id)
-                    .or_insert(HashMap::new());
-
-                // We need to check if we have all the parts of the frame.
-                // If we do, we'll reassemble the frame.
-                if parts.len() != meta.size as usize {
-                    // We don't have all the parts, lets add this part to the list.
-                    parts.insert(meta.index, frame.clone());
-                }
+        // every datagram is acknowledged, even the ones we already handled,
+        // otherwise the client keeps resending them.
+        connection.rakhandler.ack_counts.insert(sequence);
 
-                if parts.len() == meta.size as usize {
-                    // We have all the fragments, we can reassemble the frame.
-                    // Sense we need to order this by their index, we need to sort the parts.
-                    let mut parts = parts.iter().collect::<Vec<_>>();
-                    parts.sort_by_key(|f| f.0);
+        if !connection.rakhandler.track_sequence(sequence) {
+            #[cfg(feature = "debug")]
+            rak_debug!("Duplicate datagram! {}", sequence);
+            return Ok(());
+        }
 
-                    // our parts are now sorted, we can now reassemble the frame.
-                    let mut buffer = Vec::new();
-                    for (_, frm) in parts {
-                        buffer.write_all(&frm.body).unwrap();
-                    }
+        // a frame we can't handle doesn't spoil the rest of the datagram,
+        // the first failure is still reported once every frame has been handled.
+        let mut failure = None;
 
-                    // This is now an online packet! we can handle it.
-                    // make a fake frame now.
-                    let mut fake_frame = frame.clone();
-                    fake_frame.body = buffer;
-                    fake_frame.fragment_meta = None;
+        // let's handle each individual frame of the packet
+        for frame in frame_packet.frames {
+            let index = frame.reliable_index.unwrap_or(0);
+            if frame.reliability.is_reliable()
+                && !connection.rakhandler.reliable_window.insert(index)
+            {
+                // the client resent a frame we already handled, the datagram is still acked.
+                #[cfg(feature = "debug")]
+                rak_debug!("Duplicate reliable frame! {:?}", frame);
+                continue;
+            }
 
-                    Self::handle_frame(connection, fake_frame.clone())?;
+            let result = if frame.is_fragmented() {
+                match Self::handle_fragment(connection, frame) {
+                    Ok(Some(frame)) => Self::handle_frame(connection, frame),
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
                 }
             } else {
-                Self::handle_frame(connection, frame.clone())?;
+                Self::handle_frame(connection, frame)
+            };
+
+            if let Err(e) = result {
+                rak_debug!(
+                    "[RakNet] [{}] Skipping a frame that could not be handled: {}",
+                    connection.address,
+                    e
+                );
+                failure.get_or_insert(e);
             }
         }
 
-        Ok(())
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Stores a fragment of a frame, once every fragment has been received
+    /// they are reassembled into the original frame.
+    fn handle_fragment(
+        connection: &mut Connection,
+        frame: Frame,
+    ) -> Result<Option<Frame>, RakHandlerError> {
+        // The fragmented frame meta data.
+        let meta = frame.fragment_meta.clone().unwrap();
+
+        // the client decides how many fragments there are, so we can't trust it.
+        if meta.size == 0 || meta.size > connection.max_fragments {
+            return Err(RakHandlerError::TooManyFragments(meta.size));
+        }
+
+        // every fragment but the last is as large as this one, so this is about how large
+        // the frame claims to be.
+        let max_size = connection.max_fragmented_size;
+        if (meta.size as usize).saturating_mul(frame.body.len()) > max_size
+            && meta.index + 1 != meta.size
+        {
+            connection.rakhandler.fragmented_frames.remove(&meta.id);
+            return Err(RakHandlerError::FragmentedFrameTooLarge(meta.id));
+        }
+
+        let pending = connection.rakhandler.fragmented_frames.len();
+        if pending >= connection.max_fragmented_frames
+            && !connection
+                .rakhandler
+                .fragmented_frames
+                .contains_key(&meta.id)
+        {
+            return Err(RakHandlerError::TooManyFragmentedFrames(pending));
+        }
+
+        // The fragmented frames bounded by this id.
+        let group = connection
+            .rakhandler
+            .fragmented_frames
+            .entry(meta.id)
+            .or_insert(FragmentGroup::new(meta.size));
+
+        if meta.index >= group.size || group.parts.contains_key(&meta.index) {
+            return Err(RakHandlerError::InvalidFragment(meta.id, meta.index));
+        }
+
+        group.bytes += frame.body.len();
+        if group.bytes > max_size {
+            // the fragments aren't as large as the first ones claimed.
+            connection.rakhandler.fragmented_frames.remove(&meta.id);
+            return Err(RakHandlerError::FragmentedFrameTooLarge(meta.id));
+        }
+        group.parts.insert(meta.index, frame.clone());
+
+        if group.parts.len() != group.size as usize {
+            // We don't have all the parts yet.
+            return Ok(None);
+        }
+
+        // We have all the fragments, we can reassemble the frame.
+        // The frame is no longer pending, so we can take it out of the store.
+        let group = connection
+            .rakhandler
+            .fragmented_frames
+            .remove(&meta.id)
+            .unwrap();
+
+        // Sense we need to order this by their index, we need to sort the parts.
+        let mut parts = group.parts.into_iter().collect::<Vec<_>>();
+        parts.sort_by_key(|f| f.0);
+
+        // our parts are now sorted, we can now reassemble the frame.
+        let mut buffer = Vec::new();
+        for (_, frm) in parts {
+            buffer.write_all(&frm.body).unwrap();
+        }
+
+        // This is now an online packet! we can handle it.
+        // make a fake frame now.
+        let mut fake_frame = frame;
+        fake_frame.body = buffer;
+        fake_frame.fragment_meta = None;
+        Ok(Some(fake_frame))
     }
 
     /// Handles a single frame within a packet.
@@ -321,25 +553,96 @@ impl RakConnHandler {
     /// in that, if it is ordered, it will order it as it was sent.
     /// And other related utilities.
     fn handle_frame(connection: &mut Connection, frame: Frame) -> Result<(), RakHandlerError> {
-        if frame.is_sequenced() || frame.reliability.is_reliable() {
-            if frame.reliability.is_ordered() {
-                // todo: Actually handle order
-                let id = frame.order_index.unwrap();
-                let success = connection
+        let channel = frame.order_channel.unwrap_or(0);
+        if frame.reliability.is_sequenced_or_ordered() && channel >= MAX_ORDER_CHANNELS {
+            return Err(RakHandlerError::InvalidChannel(channel));
+        }
+
+        if frame.is_sequenced() {
+            // sequenced packets are sent in between ordered packets, so they can't pass
+            // the ordered packets that were sent before them.
+            let order = frame.order_index.unwrap_or(0);
+            let expected = connection
+                .rakhandler
+                .ordered_channels
+                .get(&channel)
+                .map_or(0, |queue| queue.expected());
+
+            if u24_before(order, expected) {
+                #[cfg(feature = "debug")]
+                rak_debug!("Stale sequenced packet! {:?}", frame);
+                return Ok(());
+            }
+
+            if order != expected {
+                let max = connection.max_ordered_packets;
+                let held = connection
                     .rakhandler
-                    .ordered_channels
-                    .insert(frame.body.clone(), id);
-                if success {
-                    Self::handle_packet(connection, frame.body)?;
-                } else {
-                    // this is an old or duplicated packet!
+                    .held_sequenced
+                    .entry(channel)
+                    .or_default();
+                if held.len() >= max {
+                    return Err(RakHandlerError::OrderedQueueFull(channel));
+                }
+                held.push(frame);
+                return Ok(());
+            }
+
+            // only the newest sequenced packet on a channel matters, anything older is dropped.
+            let index = frame.sequence_index.unwrap_or(0);
+
+            if let Some(highest) = connection.rakhandler.highest_sequence.get(&channel) {
+                if !u24_before(*highest, index) {
                     #[cfg(feature = "debug")]
-                    rak_debug!("Duplicate packet! {:?}", frame);
+                    rak_debug!("Stale sequenced packet! {:?}", frame);
+                    return Ok(());
+                }
+            }
+
+            connection
+                .rakhandler
+                .highest_sequence
+                .insert(channel, index);
+            Self::handle_packet(connection, frame.body)?;
+        } else if frame.reliability.is_ordered() {
+            // hold the packet until every packet before it on this channel has arrived.
+            let index = frame.order_index.unwrap_or(0);
+            let max = connection.max_ordered_packets;
+            let queue = connection
+                .rakhandler
+                .ordered_channels
+                .entry(channel)
+                .or_insert(OrderedQueue::new());
+
+            if queue.len() >= max && index != queue.expected() {
+                // the client is holding back the packet everything else is waiting on.
+                return Err(RakHandlerError::OrderedQueueFull(channel));
+            }
+
+            if queue.insert(frame.body.clone(), index) {
+                let ready = queue.flush();
+                if ready.is_empty() {
+                    return Ok(());
+                }
+
+                // the sequenced packets that follow belong to the new order index,
+                // their sequence starts over.
+                connection.rakhandler.highest_sequence.remove(&channel);
+                for packet in ready {
+                    Self::handle_packet(connection, packet)?;
+                }
+
+                // the sequenced packets waiting on these may be handled now,
+                // the ones that are still ahead are held again.
+                if let Some(held) = connection.rakhandler.held_sequenced.remove(&channel) {
+                    for frame in held {
+                        Self::handle_frame(connection, frame)?;
+                    }
                 }
             } else {
-                // todo the frame is sequenced and reliable, we can handle it.
-                // todo remove this hack and actually handle the sequence!
-                Self::handle_packet(connection, frame.body)?;
+                // this is an old or duplicated packet!
+                #[cfg(feature = "debug")]
+                rak_debug!("Duplicate packet! {:?}", frame);
             }
         } else {
             Self::handle_packet(connection, frame.body)?;
@@ -354,7 +657,7 @@ impl RakConnHandler {
         // first try to handle the packet.
         // let packet = Packet::compose(&packet, &mut 0)?;
         // we should check ack here.
-        if packet.len() == 0 {
+        if packet.is_empty() {
             return Ok(());
         }
         if packet[0] == 0xa0 || packet[0] == 0xc0 {
@@ -366,48 +669,45 @@ impl RakConnHandler {
         Ok(())
     }
 
-    /// This function will batch all the frames together and send them to the client with the specified
-    /// reliability.
-    ///
-    /// If the packet is unreliable, raknet will not perform any checks to ensure that the client
-    /// may request the packet again.
-    fn send_frames(connection: &mut Connection, mut frames: Vec<Frame>, reliability: Reliability) {
-        // this will send each frame in it's own packet. if it's a fragmented.
-        if frames.len() == 0 {
-            return;
-        }
-
-        // get the frames that are free now.
-        let mut sent: HashMap<u16, (u32, Vec<u32>)> = HashMap::new();
-        // these are the frames that can be freed from the sent list.
-        // this is used to renew fragments so we can have different parts
-        let mut free: Vec<u16> = Vec::new();
+    /// Splits the payload into frames with the given reliability on the given order channel,
+    /// fragmenting it if it does not fit in a single frame.
+    /// Every frame of the payload shares the same order index.
+    fn frame_payload(
+        connection: &mut Connection,
+        payload: Vec<u8>,
+        reliability: Reliability,
+        channel: u8,
+    ) -> Vec<Frame> {
+        let mut frames = if payload.len() <= connection.max_frame_size() {
+            let mut frame = Frame::init();
+            frame.body = payload;
+            vec![frame]
+        } else {
+            FramePacket::partition(
+                payload,
+                connection.rakhandler.next_fragment_id(),
+                connection.max_fragment_size() as u32,
+            )
+        };
 
         let mut order_index: Option<u32> = None;
         let mut sequence: Option<u32> = None;
 
-        // this is an initial check
         if reliability.is_ordered() {
-            order_index = Some(connection.rakhandler.next_order_index(0));
+            order_index = Some(connection.rakhandler.next_order_index(channel));
         } else if reliability.is_sequenced() {
             // we still need an order index, however we don't need to increase the index.
-            order_index = Some(connection.rakhandler.get_order_index(0));
+            order_index = Some(connection.rakhandler.get_order_index(channel));
             // increase the sequence for this channel.
-            sequence = Some(connection.rakhandler.next_sequence_index(0));
+            sequence = Some(connection.rakhandler.next_sequence_index(channel));
         }
 
-        let mut outbound = FramePacket::new();
-        outbound.reliability = reliability;
-        outbound.sequence = connection.rakhandler.next_seq();
-
-        // if we need to fragment, then we need to add some complexity, otherwise, we can just send the packet.
-        // i have no idea why the fuck this is like this, but it is, lol
         for frame in frames.iter_mut() {
             frame.reliability = reliability;
 
             if reliability.is_reliable() {
                 // this is a reliable frame! Let's write the sequence it's bound to.
-                frame.reliable_index = Some(connection.rakhandler.next_reliable_index(0));
+                frame.reliable_index = Some(connection.rakhandler.next_reliable_index());
             }
 
             if reliability.is_sequenced() {
@@ -417,133 +717,341 @@ impl RakConnHandler {
 
             if reliability.is_sequenced_or_ordered() {
                 // this is an ordered frame! Let's write the order index it's bound to.
-                frame.order_channel = Some(0);
-                frame.order_index = Some(order_index.unwrap());
+                frame.order_channel = Some(channel);
+                frame.order_index = order_index;
             }
+        }
 
-            if frame.is_fragmented() {
-                if let Some(meta) = frame.fragment_meta.clone() {
-                    // we need to free this fragment id if we've sent all the parts.
-                    let parts = sent.entry(meta.id).or_insert((meta.size, Vec::new()));
-                    parts.1.push(meta.index);
+        frames
+    }
 
-                    if parts.1.len() == parts.0 as usize {
-                        // we've sent all the parts, we can free this id.
-                        sent.remove(&meta.id);
-                        free.push(meta.id);
-                    }
-                }
-            }
+    /// This function will batch all the frames together and send them to the client with the specified
+    /// reliability. Frames are packed into as few frame packets as the mtu allows.
+    ///
+    /// If the packet is unreliable, raknet will not perform any checks to ensure that the client
+    /// may request the packet again.
+    ///
+    /// Returns the datagrams that were sent.
+    fn send_frames(
+        connection: &mut Connection,
+        frames: Vec<Frame>,
+        reliability: Reliability,
+    ) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        if frames.is_empty() {
+            return datagrams;
+        }
 
-            if frame.fparse().len() + outbound.byte_length > (connection.mtu - 60).into() {
-                // we need to send this packet.
-                Self::send_frame(connection, &outbound);
+        let max_size = connection.max_frame_size();
+        let mut outbound = FramePacket::new();
+        outbound.reliability = reliability;
+
+        for frame in frames {
+            let size = frame.fparse().len();
+            if !outbound.frames.is_empty() && outbound.byte_length + size > max_size {
+                // this frame doesn't fit anymore, send what we have and start a new packet.
+                outbound.sequence = connection.rakhandler.next_seq();
+                datagrams.push(Self::send_frame(connection, &outbound));
                 outbound = FramePacket::new();
                 outbound.reliability = reliability;
-                outbound.sequence = connection.rakhandler.next_seq();
-            } else {
-                outbound.frames.push(frame.clone());
             }
+
+            outbound.byte_length += size;
+            outbound.frames.push(frame);
         }
 
         // send the last packet.
-        Self::send_frame(connection, &outbound);
+        outbound.sequence = connection.rakhandler.next_seq();
+        datagrams.push(Self::send_frame(connection, &outbound));
+        datagrams
+    }
 
-        for id in free {
-            connection.rakhandler.free_fragment_id(id);
+    /// This function will send the given frame packet to the client, returning the datagram.
+    fn send_frame(connection: &mut Connection, frame: &FramePacket) -> Vec<u8> {
+        let mut receipts = frame
+            .frames
+            .iter()
+            .filter_map(|frame| frame.receipt)
+            .collect::<Vec<_>>();
+        if !receipts.is_empty() {
+            // the fragments of a payload may share a datagram, they share the receipt too.
+            receipts.dedup();
+            connection
+                .rakhandler
+                .receipts
+                .insert(frame.sequence, (SystemTime::now(), receipts));
         }
-    }
 
-    /// This function will send the given frame packet to the client.
-    fn send_frame(connection: &mut Connection, frame: &FramePacket) {
+        let parsed = frame.fparse();
+        debug_assert!(
+            parsed.len() <= (connection.mtu - UDP_HEADER_SIZE) as usize,
+            "frame packet of {} bytes exceeds the mtu of {}",
+            parsed.len(),
+            connection.mtu
+        );
         if frame.reliability.is_reliable() {
             // we need to add this to the reliable list.
             // this is buffered and will die if the client doesn't respond.
-            let parsed = frame.fparse();
             connection
                 .rakhandler
                 .ack
                 .add(frame.sequence, parsed.clone());
-            connection.send_immediate(parsed);
-        } else {
-            connection.send_immediate(frame.fparse());
+
+            // the fragment ids stay in flight until this datagram is acknowledged.
+            for meta in frame.frames.iter().filter_map(|f| f.fragment_meta.as_ref()) {
+                if let Some(sequences) = connection.rakhandler.fragment_ids.get_mut(&meta.id) {
+                    sequences.push(frame.sequence);
+                }
+            }
         }
+        // a datagram that can't be sent is already logged, reliable ones are resent anyway.
+        let _ = connection.send_immediate(parsed.clone());
+        parsed
     }
 
     /// This is an instant send, this will send the packet to the client immediately.
-    pub fn send_framed(connection: &mut Connection, payload: Vec<u8>, reliability: Reliability) {
-        if payload.len() < 60 || (payload.len() - 60) < connection.mtu.into() {
-            let mut frame = Frame::init();
-            frame.body = payload;
-            Self::send_frames(connection, vec![frame], reliability);
-        } else {
-            let frames = FramePacket::partition(
-                payload,
-                connection.rakhandler.next_fragment_id(),
-                (connection.mtu - 60).into(),
-            );
-            Self::send_frames(connection, frames, reliability);
-        }
+    /// Returns the datagrams the payload was sent in.
+    pub fn send_framed(
+        connection: &mut Connection,
+        payload: Vec<u8>,
+        reliability: Reliability,
+    ) -> Vec<Vec<u8>> {
+        let frames = Self::frame_payload(connection, payload, reliability, 0);
+        Self::send_frames(connection, frames, reliability)
     }
 
-    pub fn tick(connection: &mut Connection) {
-        // lets send the packets in the queue now.
-        let packets = connection.queue.flush();
-        let mut current_frame_id: u16 = 0;
+    /// Frames the payload with the given reliability on the given order channel right away,
+    /// so it takes its indexes in the order it was sent. The frames are sent on the next tick.
+    /// The sender is given the receipt once every frame is acknowledged, or lost.
+    pub fn queue_framed(
+        connection: &mut Connection,
+        payload: Vec<u8>,
+        reliability: Reliability,
+        channel: u8,
+        receipt: Option<u32>,
+    ) {
+        let mut frames = Self::frame_payload(connection, payload, reliability, channel);
+        for frame in frames.iter_mut() {
+            frame.receipt = receipt;
+        }
+        connection.rakhandler.outbound.extend(frames);
+    }
 
-        for packet in packets {
-            // we need to handle these packets!
-            let mut frames =
-                FramePacket::partition(packet, current_frame_id, (connection.mtu - 60).into());
-            for frame in frames.iter_mut() {
-                if frame.is_fragmented() {
-                    if let Some(meta) = frame.fragment_meta.as_mut() {
-                        meta.id = current_frame_id;
-                    }
+    /// Gives the sender the receipts of the acknowledged datagram, unless they're still
+    /// waiting on other datagrams.
+    fn acknowledge_receipts(connection: &mut Connection, sequence: u32) {
+        if let Some((_, receipts)) = connection.rakhandler.receipts.remove(&sequence) {
+            for receipt in receipts {
+                let pending = connection
+                    .rakhandler
+                    .receipts
+                    .values()
+                    .any(|(_, pending)| pending.contains(&receipt));
+                if !pending {
+                    connection
+                        .event_dispatch
+                        .push_back(RakEvent::AckReceipt(connection.address.clone(), receipt));
                 }
             }
-            current_frame_id += 1;
-            Self::send_frames(connection, frames, Reliability::ReliableOrd);
         }
+    }
 
-        if connection.state.is_connected() {
-            // send the acks to the client that we got some packets
-            // // get missing packets and request them.
-            let missing = connection.rakhandler.ordered_channels.flush_missing();
+    /// Gives the sender a loss receipt for the datagrams that weren't acknowledged in time,
+    /// and won't be resent anymore.
+    fn expire_receipts(connection: &mut Connection, timeout: Duration) {
+        let lost = connection
+            .rakhandler
+            .receipts
+            .iter()
+            .filter(|(sequence, (sent, _))| {
+                !connection.rakhandler.ack.has(sequence)
+                    && sent.elapsed().unwrap_or_default() >= timeout
+            })
+            .map(|(sequence, _)| *sequence)
+            .collect::<Vec<_>>();
 
-            if missing.len() != 0 {
-                let nack = Ack::from_missing(missing);
+        for sequence in lost {
+            let (_, receipts) = connection.rakhandler.receipts.remove(&sequence).unwrap();
+            for receipt in receipts {
+                // the other datagrams carrying this receipt no longer matter.
+                for (_, pending) in connection.rakhandler.receipts.values_mut() {
+                    pending.retain(|other| *other != receipt);
+                }
+                connection
+                    .event_dispatch
+                    .push_back(RakEvent::LossReceipt(connection.address.clone(), receipt));
+            }
+        }
+        connection
+            .rakhandler
+            .receipts
+            .retain(|_, (_, pending)| !pending.is_empty());
+    }
 
-                #[cfg(feature = "debug")]
-                rak_debug!("NACK: {:#?}", nack);
+    /// Resends the reliable datagram with the given sequence under a new sequence, as the
+    /// client has already seen the old one. The frames keep their reliable indexes, so the
+    /// client can still tell if it got them before. The datagram stays in the recovery store,
+    /// in case the resend is lost too.
+    fn resend(connection: &mut Connection, sequence: u32) {
+        if !connection.rakhandler.ack.has(&sequence) {
+            return;
+        }
 
-                connection.send(nack.fparse(), true);
-            }
+        let next = connection.rakhandler.next_seq();
+        let handler = &mut connection.rakhandler;
+        let datagrams = handler.ack.rekey(sequence, next).unwrap();
+        for datagram in datagrams.iter_mut() {
+            datagram[1..4].copy_from_slice(&next.to_le_bytes()[..3]);
+        }
+        let datagrams = datagrams.clone();
 
-            // clear up the packets we've recieved.
-            let mut ack = Ack::new(connection.rakhandler.ack_counts.len() as u16, false);
-            for id in connection.rakhandler.ack_counts.iter() {
-                ack.push_record(*id);
+        // the receipts and fragment ids now wait on the new sequence.
+        if let Some(receipts) = handler.receipts.remove(&sequence) {
+            handler.receipts.insert(next, receipts);
+        }
+        for sequences in handler.fragment_ids.values_mut() {
+            for pending in sequences.iter_mut().filter(|pending| **pending == sequence) {
+                *pending = next;
             }
+        }
 
-            if ack.records.len() != 0 {
-                connection.rakhandler.ack_counts.clear();
-                connection.send(ack.fparse(), true);
+        for datagram in datagrams {
+            if connection.send_immediate(datagram).is_err() {
+                // the server stopped sending, the rest won't make it either.
+                break;
             }
+        }
+    }
 
-            // clean up the packets that we need to have an ack for.
-            let mut needs_cleared = Vec::<u32>::new();
-            for (id, queue) in connection.rakhandler.ack.store.iter() {
-                if queue.0.elapsed().unwrap().as_secs() > 5 {
-                    needs_cleared.push(*id);
+    /// Acknowledges every datagram we've received since the last flush.
+    pub fn flush_acks(connection: &mut Connection) {
+        // clear up the packets we've recieved.
+        if !connection.rakhandler.ack_counts.is_empty() {
+            let received = connection.rakhandler.ack_counts.drain().collect::<Vec<_>>();
+            let max_size = (connection.mtu - UDP_HEADER_SIZE) as usize;
+            // a lot of scattered sequences may not fit in a single datagram.
+            for ack in Ack::from_sequences(received, false).split(max_size) {
+                if connection.send(ack.fparse(), true).is_err() {
+                    break;
                 }
             }
-            for id in needs_cleared {
-                let packets = connection.rakhandler.ack.flush_key(id).unwrap().1;
-                for packet in packets {
-                    connection.send_immediate(packet);
+        }
+    }
+
+    pub fn tick(connection: &mut Connection) {
+        // the client is never going to complete these, so we stop waiting for them.
+        let timeout = connection.fragment_timeout;
+        let dropped = connection.rakhandler.evict_fragments(timeout);
+        connection.stats.fragments_dropped += dropped as u64;
+
+        // lets send the packets in the queue now, they're batched together as
+        // tightly as the mtu allows.
+        let packets = connection.queue.flush();
+        let mut frames = Vec::new();
+
+        for packet in packets {
+            frames.extend(Self::frame_payload(
+                connection,
+                packet,
+                Reliability::ReliableOrd,
+                0,
+            ));
+        }
+
+        // the frames that were framed ahead of time are sent along, the unreliable ones
+        // are kept apart so they are never resent.
+        let (reliable, unreliable): (Vec<Frame>, Vec<Frame>) = connection
+            .rakhandler
+            .outbound
+            .drain(..)
+            .partition(|frame| frame.reliability.is_reliable());
+        frames.extend(reliable);
+        Self::send_frames(connection, frames, Reliability::ReliableOrd);
+        Self::send_frames(connection, unreliable, Reliability::Unreliable);
+
+        if connection.state.is_connected() || connection.state == ConnectionState::TimingOut {
+            // request the packets we never got.
+            if !connection.rakhandler.nack.is_empty() {
+                let missing = connection.rakhandler.nack.drain().collect::<Vec<_>>();
+                let nack = Ack::from_sequences(missing, true);
+
+                #[cfg(feature = "debug")]
+                rak_debug!("NACK: {:#?}", nack);
+
+                let max_size = (connection.mtu - UDP_HEADER_SIZE) as usize;
+                for nack in nack.split(max_size) {
+                    if connection.send(nack.fparse(), true).is_err() {
+                        break;
+                    }
                 }
             }
+
+            // send the acks to the client that we got some packets
+            Self::flush_acks(connection);
+
+            // resend the packets the client hasn't acknowledged in time,
+            // the ones that have been resent too often are dropped.
+            let timeout = connection.current_resend_timeout();
+            let max_resends = connection.max_resends;
+            // they're resent under a new sequence, just like the ones the client NACKed.
+            for sequence in connection.rakhandler.ack.expired(timeout, max_resends) {
+                Self::resend(connection, sequence);
+            }
+            Self::expire_receipts(connection, timeout);
+        }
+
+        connection.rakhandler.free_fragment_ids();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::connection::SendCommand;
+    use crate::protocol::mcpe::motd::Motd;
+    use crate::server::RakNetVersion;
+
+    fn connection() -> (Connection, tokio::sync::mpsc::Receiver<SendCommand>) {
+        let (send, recv) = tokio::sync::mpsc::channel::<SendCommand>(2048);
+        let mut connection = Connection::new(
+            "127.0.0.1:19133".into(),
+            Arc::new(send),
+            SystemTime::now(),
+            1234,
+            Arc::new(RwLock::new(Motd::new(1234, "19132"))),
+            Arc::new(RwLock::new(None)),
+            RakNetVersion::V10,
+        );
+        connection.state = ConnectionState::Connected;
+        (connection, recv)
+    }
+
+    #[test]
+    fn ack_ranges_wrap_around() {
+        let (mut connection, _recv) = connection();
+        connection.rakhandler.send_seq = 0xfffffc;
+        // one datagram each for 0xfffffc up to 1.
+        for index in 0..6 {
+            RakConnHandler::send_framed(&mut connection, vec![0xfe, index], Reliability::Reliable);
         }
+
+        // acknowledges 0xfffffe, 0xffffff, 0 and 1.
+        RakConnHandler::handle(
+            &mut connection,
+            &[0xc0, 0, 1, 0, 0xfe, 0xff, 0xff, 0x01, 0x00, 0x00],
+        )
+        .unwrap();
+
+        let mut pending = connection
+            .rakhandler
+            .ack
+            .store
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        pending.sort_unstable();
+        assert_eq!(pending, vec![0xfffffc, 0xfffffd]);
     }
 }
@@ -3,9 +3,15 @@ pub mod ack;
 /// Frame related.
 pub mod frame;
 
+/// Anti-spoofing cookies for the handshake.
+pub mod cookie;
+
 /// A internal handler for connections
 pub mod handler;
 
+/// Rate limiting for offline packets.
+pub mod rate_limit;
+
 /// Queues
 #[allow(dead_code)]
 pub mod queue;
@@ -18,8 +24,13 @@ pub use self::handler::*;
 #[macro_export]
 macro_rules! rak_debug {
     ($($arg:tt)*) => {
-        if cfg!(feature = "dbg") {
-            println!($($arg)*);
-        }
+        $crate::log::dispatch($crate::log::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! rak_warn {
+    ($($arg:tt)*) => {
+        $crate::log::dispatch($crate::log::LogLevel::Warn, format_args!($($arg)*))
     };
 }
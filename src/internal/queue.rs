@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+
+use super::util::{u24_before, u24_distance, u24_next, MAX_U24};
 /// A packet queue, this is used to store packets that are waiting to be sent.
 /// This is internal use for Sessions.
 
@@ -74,13 +76,16 @@ pub enum SendPriority {
     Low,
 }
 
+/// A queue that releases packets by their order index.
+/// Packets that arrive ahead of the index we expect are held until
+/// every packet before them has arrived.
 #[derive(Debug)]
 pub struct OrderedQueue<T> {
-    /// The queue of packets that are in order. Mapped to the time they were received.
+    /// The queue of packets that are waiting for the packets before them.
     queue: HashMap<u32, T>,
-    /// The current starting scope for the queue.
-    /// A start scope or "window start" is the range of packets that we are currently allowing.
-    /// Older packets will be ignored simply because they are old.
+    /// The current scope for the queue.
+    /// The start of the scope is the next index we expect, while the end is one past
+    /// the highest index we've received. Older packets will be ignored simply because they are old.
     scope: (u32, u32),
 }
 
@@ -108,68 +113,55 @@ where
     }
 
     /// Inserts the given packet into the queue.
-    /// This will return `false` if the packet is out of scope.
+    /// This will return `false` if the packet is out of scope, or was already received.
     pub fn insert(&mut self, packet: T, id: u32) -> bool {
         // if the packet id is lower than our scope, ignore it
         // this packet is way to old for us to handle.
-        if id < self.scope.0 {
+        // order indexes wrap around, so they're compared within half the range.
+        if u24_before(id, self.scope.0) || self.queue.contains_key(&id) {
             return false;
         }
 
         // If the packet is higher than our current scope, we need to adjust our scope.
         // This is because we are now allowing packets that are newer than our current scope.
-        if id > self.scope.1 {
-            self.scope.1 = id + 1;
+        if !u24_before(id, self.scope.1) {
+            self.scope.1 = u24_next(id);
         }
 
         self.queue.insert(id, packet);
         return true;
     }
 
-    /// Drains the current queue by removing all packets from the queue.
-    /// This will return the packets in order only if they were within the current scope.
-    /// This method will also update the scope and adjust it to the newest window.
+    /// Drains every packet that is now in order, starting at the index we expect.
+    /// The scope is moved past the packets that were released.
     pub fn flush(&mut self) -> Vec<T> {
-        // clear all packets not within our scope
-        self.clear_out_of_scope();
-
-        // now drain the queue
-        let mut map = HashMap::new();
-        std::mem::swap(&mut map, &mut self.queue);
-
-        let mut clean = map.iter().collect::<Vec<_>>();
-        clean.sort_by_key(|m| m.0);
-
-        return clean.iter().map(|m| m.1.clone()).collect::<Vec<T>>();
+        let mut ready = Vec::new();
+        while let Some(packet) = self.queue.remove(&self.scope.0) {
+            ready.push(packet);
+            self.scope.0 = u24_next(self.scope.0);
+        }
+        ready
     }
 
-    /// Clears all packets that are out of scope.
-    /// Returning only the ones that have not been recieved.
-    pub fn flush_missing(&mut self) -> Vec<u32> {
-        let mut missing: Vec<u32> = Vec::new();
-        // we need to get the amount of ids that are missing from the queue.
-        for i in self.scope.0..self.scope.1 {
-            if !self.queue.contains_key(&i) {
-                missing.push(i);
-            }
-        }
+    /// Returns the indexes within the current scope that have not been received yet.
+    pub fn flush_missing(&self) -> Vec<u32> {
+        (0..self.get_scope())
+            .map(|i| (self.scope.0 + i) & MAX_U24)
+            .filter(|i| !self.queue.contains_key(i))
+            .collect()
+    }
 
-        // we can safely update the scope
-        self.scope.0 = missing.get(0).unwrap_or(&self.scope.0).clone();
-        return missing;
+    /// The index of the packet the queue is waiting for.
+    pub fn expected(&self) -> u32 {
+        self.scope.0
     }
 
-    fn clear_out_of_scope(&mut self) {
-        // clear all packets not within our current scope.
-        // this is done by removing all packets that are older than our current scope.
-        for (id, _) in self.queue.clone().iter() {
-            if *id < self.scope.0 {
-                self.queue.remove(id);
-            }
-        }
+    /// The amount of packets held in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.len()
     }
 
     pub fn get_scope(&self) -> u32 {
-        self.scope.1 - self.scope.0
+        u24_distance(self.scope.0, self.scope.1)
     }
 }
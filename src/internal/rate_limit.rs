@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// A token bucket for a single ip.
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket rate limiter, keyed by ip.
+///
+/// Every ip may send `burst` packets at once, after which they're limited
+/// to `rate` packets per second.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    last_evicted: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            burst: burst.max(1) as f64,
+            buckets: HashMap::new(),
+            last_evicted: Instant::now(),
+        }
+    }
+
+    /// Whether or not the ip may send another packet, this takes a token if it can.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.evict(now);
+
+        let burst = self.burst;
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes the buckets that have been idle long enough to be full again,
+    /// these are no different from a new bucket.
+    fn evict(&mut self, now: Instant) {
+        let refill = Duration::from_secs_f64(self.burst / self.rate);
+        if now.duration_since(self.last_evicted) < refill {
+            return;
+        }
+
+        self.buckets
+            .retain(|_, bucket| now.duration_since(bucket.updated) < refill);
+        self.last_evicted = now;
+    }
+}
@@ -1,15 +1,44 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{AddrParseError, SocketAddr};
+
+/// Datagram sequences, reliable indexes and order indexes are 24-bit integers,
+/// they wrap around after this.
+pub const MAX_U24: u32 = 0x00ff_ffff;
 
 pub fn to_address_token(remote: SocketAddr) -> String {
-    let mut address = remote.ip().to_string();
-    address.push_str(":");
-    address.push_str(remote.port().to_string().as_str());
-    return address;
+    // IPv6 addresses are bracketed, so the token can be parsed back.
+    remote.to_string()
+}
+
+/// Parses the given address token back into a `SocketAddr`.
+/// This will fail if the token is malformed, rather than panicking.
+pub fn from_address_token(remote: String) -> Result<SocketAddr, AddrParseError> {
+    remote.parse::<SocketAddr>()
+}
+
+/// The index that follows the given one, wrapping around after `MAX_U24`.
+pub fn u24_next(index: u32) -> u32 {
+    index.wrapping_add(1) & MAX_U24
 }
 
-pub fn from_address_token(remote: String) -> SocketAddr {
-    let mut parsed = remote
-        .to_socket_addrs()
-        .expect("Could not parse remote address.");
-    SocketAddr::from(parsed.next().unwrap())
+/// How far `to` is ahead of `from`, taking the wraparound into account.
+pub fn u24_distance(from: u32, to: u32) -> u32 {
+    to.wrapping_sub(from) & MAX_U24
+}
+
+/// Whether `index` comes before `other`. Indexes that are more than half the range apart
+/// are assumed to have wrapped around, so `0xffffff` comes before `0`.
+pub fn u24_before(index: u32, other: u32) -> bool {
+    u24_distance(index, other).wrapping_sub(1) < MAX_U24 / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn garbage_tokens_are_an_error() {
+        for token in ["", "garbage", "127.0.0.1", "::1:19132", "127.0.0.1:port"] {
+            assert!(from_address_token(token.to_string()).is_err());
+        }
+    }
 }
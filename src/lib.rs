@@ -6,6 +6,29 @@ pub const MAGIC: [u8; 16] = [
     0x00, 0xff, 0xff, 0x0, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
 ];
 
+/// The smallest MTU size a client may negotiate.
+/// This is the minimum datagram size every IPv4 host must be able to accept.
+pub const MIN_MTU_SIZE: u16 = 576;
+
+/// The default largest MTU size the server will negotiate with a client.
+pub const MAX_MTU_SIZE: u16 = 1400;
+
+/// The largest MTU size the server can negotiate with a client.
+/// Frames write the length of their body in bits as a `u16`, so a frame can't
+/// hold more than 8191 bytes.
+pub const MAX_FRAMED_MTU_SIZE: u16 = u16::MAX / 8 + RAKNET_HEADER_FRAME_OVERHEAD;
+
+/// The size of the UDP (8 bytes) and IPv4 (20 bytes) headers wrapping every datagram.
+pub const UDP_HEADER_SIZE: u16 = 28;
+
+/// The room reserved in every datagram for the headers around its frames,
+/// `40 (Datagram Protocol) + 20 (Raknet)`.
+pub const RAKNET_HEADER_FRAME_OVERHEAD: u16 = 60;
+
+/// The largest header a single frame can have: flags (1), length (2), reliable index (3),
+/// sequence index (3), order index and channel (4) and the fragment info (10).
+pub const MAX_FRAME_HEADER_SIZE: u16 = 23;
+
 /// Internal utilities for raknet
 /// These are used in rakrs to parse packets and are not exposed to the user.
 pub(crate) mod internal;
@@ -26,5 +49,14 @@ pub mod connection;
 /// This is the main entry point for the server.
 pub mod server;
 
+/// Diagnostics.
+/// These can be routed to your own logger with `set_logger`.
+pub mod log;
+
 // Export the entire server module for ease of use
 pub use self::server::*;
+
+/// The reliability packets can be sent with.
+pub use self::internal::frame::reliability::Reliability;
+
+pub use self::log::{clear_logger, set_logger, LogLevel, Logger};
@@ -0,0 +1,51 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// How important a diagnostic message is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Information that is only useful while debugging, such as the packets that are handled.
+    Debug,
+    /// Something went wrong, but RakNet can carry on, such as a packet that could not be sent.
+    Warn,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Debug => write!(f, "Debug"),
+            LogLevel::Warn => write!(f, "Warn"),
+        }
+    }
+}
+
+/// A sink for the diagnostics of RakNet.
+pub type Logger = Arc<dyn Fn(LogLevel, &str) + Send + Sync>;
+
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+
+/// Routes every diagnostic message of RakNet to the given logger, for example to hand them
+/// to `log` or `tracing`. Without a logger, messages are only printed with the `dbg` feature.
+pub fn set_logger(logger: Logger) {
+    *LOGGER.write().unwrap() = Some(logger);
+}
+
+/// Removes the logger, messages are printed with the `dbg` feature again.
+pub fn clear_logger() {
+    *LOGGER.write().unwrap() = None;
+}
+
+/// Hands the message to the logger. This is used by `rak_debug!` and `rak_warn!`.
+#[doc(hidden)]
+pub fn dispatch(level: LogLevel, args: fmt::Arguments) {
+    // the lock is released before calling the logger, so it may log or set a logger itself.
+    let logger = LOGGER.read().unwrap().clone();
+    match logger {
+        Some(logger) => logger(level, &args.to_string()),
+        None => {
+            if cfg!(feature = "dbg") {
+                println!("{}", args);
+            }
+        }
+    }
+}
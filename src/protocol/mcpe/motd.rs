@@ -1,3 +1,6 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
 use binary_utils::Streamable;
 
 #[repr(u8)]
@@ -38,6 +41,8 @@ impl std::fmt::Display for Gamemode {
 pub struct Motd {
     /// The name of the server
     pub name: String,
+    /// The second line of the server name, shown in the world list.
+    pub sub_name: String,
     /// The protocol version
     pub protocol: u16,
     /// The version of the server
@@ -61,6 +66,7 @@ impl Motd {
     pub fn new<S: Into<String>>(server_guid: u64, port: S) -> Self {
         Self {
             name: "Netrex Server".into(),
+            sub_name: "Netrex".into(),
             player_count: 10,
             player_max: 100,
             protocol: 448,
@@ -83,7 +89,7 @@ impl Motd {
             self.player_count.to_string(),
             self.player_max.to_string(),
             self.server_guid.to_string(),
-            "Netrex".to_string(),
+            self.sub_name.clone(),
             self.gamemode.as_str().to_string(),
             "1".to_string(),
             // Todo: Figure out why this is not working
@@ -95,108 +101,101 @@ impl Motd {
     }
 }
 
-impl Streamable for Motd {
-    fn compose(
-        source: &[u8],
-        position: &mut usize,
-    ) -> Result<Self, binary_utils::error::BinaryError> {
-        let motd = String::compose(source, position)?;
-        let parts = motd
-            .split(";")
-            .map(|c| c.to_string())
-            .collect::<Vec<String>>();
-        let name = parts
-            .get(1)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd name".into(),
-            ))?;
-        let protocol = parts
-            .get(2)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd protocol".into(),
-            ))?;
-        let version = parts
-            .get(3)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd version".into(),
-            ))?;
-        let player_count =
-            parts
-                .get(4)
-                .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                    "Invalid motd player count".into(),
-                ))?;
-        let player_max = parts
-            .get(5)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd player maxmium".into(),
-            ))?;
-        let server_guid =
-            parts
-                .get(6)
-                .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                    "Invalid motd server guid".into(),
-                ))?;
-        let _ = parts
-            .get(7)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd software name".into(),
-            ))?;
-        let _ = parts
-            .get(8)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd gamemode string".into(),
-            ))?;
-        let gamemode = parts
-            .get(9)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd gamemode".into(),
-            ))?;
-        let port = parts
-            .get(10)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd port".into(),
-            ))?;
-        let ipv6_port = parts
-            .get(11)
-            .ok_or(binary_utils::error::BinaryError::RecoverableKnown(
-                "Invalid motd port".into(),
-            ))?;
+impl Default for Motd {
+    fn default() -> Self {
+        Self::new(0, "19132")
+    }
+}
+
+impl std::fmt::Display for Motd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.write())
+    }
+}
+
+impl std::str::FromStr for Motd {
+    type Err = binary_utils::error::BinaryError;
+
+    /// Parses a semicolon separated MCPE motd string.
+    fn from_str(motd: &str) -> Result<Self, Self::Err> {
+        let parts = motd.split(";").collect::<Vec<&str>>();
+        let field = |index: usize, name: &str| {
+            parts.get(index).map(|part| part.to_string()).ok_or(
+                binary_utils::error::BinaryError::RecoverableKnown(format!(
+                    "Invalid motd {}",
+                    name
+                )),
+            )
+        };
+        let number = |index: usize, name: &str| {
+            field(index, name)?.as_str().parse::<u64>().map_err(|_| {
+                binary_utils::error::BinaryError::RecoverableKnown(format!(
+                    "Motd {} is not a number",
+                    name
+                ))
+            })
+        };
 
         Ok(Motd {
-            name: name.clone(),
-            protocol: protocol
-                .as_str()
-                .parse::<u16>()
-                .expect("Invalid motd protocol"),
-            version: version.clone(),
-            player_count: player_count
-                .as_str()
-                .parse::<u16>()
-                .expect("Player count is not a number"),
-            player_max: player_max
-                .as_str()
-                .parse::<u16>()
-                .expect("Player Maximum is not a number"),
-            server_guid: server_guid
-                .as_str()
-                .parse::<u64>()
-                .expect("Server GUID is not a number"),
-            port: port.clone(),
-            ipv6_port: ipv6_port.clone(),
-            gamemode: match gamemode
-                .as_str()
-                .parse::<u8>()
-                .expect("Gamemode is not a byte")
-            {
+            name: field(1, "name")?,
+            protocol: number(2, "protocol")? as u16,
+            version: field(3, "version")?,
+            player_count: number(4, "player count")? as u16,
+            player_max: number(5, "player maximum")? as u16,
+            server_guid: number(6, "server guid")?,
+            sub_name: field(7, "software name")?,
+            // the gamemode string (index 8) is derived from the numeric gamemode.
+            gamemode: match number(9, "gamemode")? {
                 0 => Gamemode::Survival,
                 1 => Gamemode::Creative,
                 2 => Gamemode::Adventure,
                 3 => Gamemode::Spectator,
                 _ => Gamemode::Survival,
             },
+            port: field(10, "port")?,
+            ipv6_port: field(11, "ipv6 port")?,
         })
     }
+}
+
+/// A callback that builds the `Motd` for every ping the server recieves.
+/// The callback is given the address of the client that pinged the server.
+#[derive(Clone)]
+pub struct MotdGenerator(Arc<dyn Fn(SocketAddr) -> Motd + Send + Sync>);
+
+impl MotdGenerator {
+    pub fn new<F>(generator: F) -> Self
+    where
+        F: Fn(SocketAddr) -> Motd + Send + Sync + 'static,
+    {
+        Self(Arc::new(generator))
+    }
+
+    /// Generates the `Motd` for the given client.
+    pub fn generate(&self, address: SocketAddr) -> Motd {
+        (self.0)(address)
+    }
+}
+
+impl From<Box<dyn Fn(SocketAddr) -> Motd + Send + Sync>> for MotdGenerator {
+    fn from(generator: Box<dyn Fn(SocketAddr) -> Motd + Send + Sync>) -> Self {
+        Self(Arc::from(generator))
+    }
+}
+
+impl std::fmt::Debug for MotdGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MotdGenerator")
+    }
+}
+
+impl Streamable for Motd {
+    fn compose(
+        source: &[u8],
+        position: &mut usize,
+    ) -> Result<Self, binary_utils::error::BinaryError> {
+        String::compose(source, position)?.as_str().parse::<Motd>()
+    }
 
     fn parse(&self) -> Result<Vec<u8>, binary_utils::error::BinaryError> {
         self.write().parse()
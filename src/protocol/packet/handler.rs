@@ -1,14 +1,22 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::SystemTime;
+use binary_utils::Streamable;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
 
 use crate::connection::state::ConnectionState;
+use crate::connection::{Connection, SendError};
+use crate::internal::frame::reliability::Reliability;
 use crate::internal::queue::SendPriority;
 use crate::internal::util::from_address_token;
+use crate::internal::RakConnHandler;
+use crate::protocol::mcpe::motd::{Motd, MotdGenerator};
 use crate::protocol::util::Magic;
 use crate::rak_debug;
-use crate::{connection::Connection, server::RakEvent};
+use crate::server::RakEvent;
+use crate::MIN_MTU_SIZE;
 
-use super::offline::{IncompatibleProtocolVersion, OpenConnectReply, SessionInfoReply};
+use super::offline::{
+    AlreadyConnected, IncompatibleProtocolVersion, OpenConnectReply, SessionInfoReply,
+};
 use super::online::{ConnectedPong, ConnectionAccept, OnlinePacket};
 use super::OfflinePacket;
 use super::{offline::UnconnectedPong, Packet};
@@ -18,78 +26,143 @@ use super::{offline::UnconnectedPong, Packet};
 pub fn handle_offline(connection: &mut Connection, packet: Packet) {
     // check if the type of packet, we'll use a match statement
     let result = match packet.get_offline() {
-        OfflinePacket::UnconnectedPing(_) => {
-            // if the packet is a ping, we'll send a pong
-            // and dispatch an event to update the Motd.
-            connection.event_dispatch.push_back(RakEvent::Motd(
-                connection.address.clone(),
-                connection.motd.clone(),
-            ));
-
-            // send the pong to the server, and parse it!
-            // we could compensate for decoding time, but there isn't
-            // too much overhead there, so we'll just send as is.
-            let pong = UnconnectedPong {
-                server_id: connection.server_guid,
-                timestamp: connection.start_time.elapsed().unwrap().as_millis() as u64,
-                magic: Magic::new(),
-                #[cfg(feature = "mcpe")]
-                motd: connection.motd.clone(),
-            };
-            connection.send_packet(pong.into(), SendPriority::Immediate);
-            Ok(())
+        OfflinePacket::UnconnectedPing(pk) => reply_to_ping(connection, pk.timestamp, pk.client_id)
+            .map_err(|_| "The pong could not be sent!"),
+        OfflinePacket::UnconnectedPingOpenConnections(pk) => {
+            // the server only passes this on when there is room for another connection.
+            reply_to_ping(connection, pk.timestamp, pk.client_id)
+                .map_err(|_| "The pong could not be sent!")
         }
         OfflinePacket::OpenConnectRequest(pk) => {
-            if pk.protocol != connection.raknet_version.to_u8() {
+            if !connection.supports_protocol(pk.protocol) {
+                // let the client know which protocol we're using.
                 let incompatible = IncompatibleProtocolVersion {
-                    protocol: pk.protocol,
+                    protocol: connection.raknet_version.to_u8(),
                     magic: Magic::new(),
                     server_id: connection.server_guid,
                 };
-                connection.send_packet(incompatible.into(), SendPriority::Immediate);
+                // a reply that can't be sent is already logged, the client is dropped either way.
+                let _ = connection.send_packet(incompatible.into(), SendPriority::Immediate);
+                // the client can't connect, so there's no reason to keep the connection around.
+                connection.disconnect("Incompatible protocol version.", false);
+                return;
             }
 
+            // The client requests an mtu by padding the request, we need to make sure
+            // it's within the range we support.
+            let mtu = pk.mtu_size.min(connection.max_mtu).max(MIN_MTU_SIZE);
+
+            // If we're using cookies, the client has to echo this back to prove
+            // they actually own the address they're connecting from.
+            let cookie = match (
+                &connection.cookies,
+                from_address_token(connection.address.clone()),
+            ) {
+                (Some(cookies), Ok(address)) => Some(cookies.generate(&address)),
+                _ => None,
+            };
+
             // The version is valid, we can send the reply.
             let reply = OpenConnectReply {
                 server_id: connection.server_guid,
-                // todo: Make this optional
-                security: false,
+                security: cookie.is_some(),
+                cookie,
                 magic: Magic::new(),
-                mtu_size: pk.mtu_size,
+                mtu_size: mtu,
             };
 
-            // we can actually save the requested mtu size from the client
-            connection.mtu = pk.mtu_size;
-            connection.send_packet(reply.into(), SendPriority::Immediate);
-            Ok(())
+            // we can actually save the negotiated mtu size for the client
+            connection.mtu = mtu;
+            connection
+                .send_packet(reply.into(), SendPriority::Immediate)
+                .map_err(|_| "The reply could not be sent!")
         }
         OfflinePacket::SessionInfoRequest(pk) => {
-            // todo: Actually check if we want the client to join the server!
-            // todo: And disconnect them if we don't!
-            let reply = SessionInfoReply {
-                server_id: connection.server_guid,
-                client_address: from_address_token(connection.address.clone()),
-                magic: Magic::new(),
-                mtu_size: pk.mtu_size,
-                // todo: Again, make this optional
-                security: false,
-            };
-            // the client is now officially in the "Connecting State"
-            // let's validate the mtu
-            if pk.mtu_size != connection.mtu {
-                connection.mtu = pk.mtu_size;
-                #[cfg(feature = "dbg")]
+            if connection.state == ConnectionState::Connected {
+                if !connection.allow_reconnect {
+                    // the client already completed the handshake, we're not going to
+                    // touch the existing session.
+                    let reply = AlreadyConnected {
+                        magic: Magic::new(),
+                        server_id: connection.server_guid,
+                    };
+                    let _ = connection.send_packet(reply.into(), SendPriority::Immediate);
+                    return;
+                }
+
+                // the client is reconnecting from the same address, drop the old session.
+                connection.reset_session("Client reconnected.");
+            }
+
+            if let Ok(client_address) = from_address_token(connection.address.clone()) {
+                if let Some(cookies) = &connection.cookies {
+                    let valid = pk
+                        .cookie
+                        .is_some_and(|cookie| cookies.verify(&client_address, cookie));
+
+                    if !valid {
+                        // the client never received our reply, they're most likely
+                        // spoofing their address, so we won't reply.
+                        rak_debug!(
+                            "[RakNet] [{}] Dropping SessionInfoRequest with an invalid cookie.",
+                            connection.address
+                        );
+                        return;
+                    }
+                }
+
                 rak_debug!(
-                    "[RakNet] [{}] Recieved two different MTU sizes, setting to {}",
+                    "[RakNet] [{}] Client is connecting to {}",
                     connection.address,
-                    connection.mtu
+                    pk.address
                 );
-            }
+                if connection.validate_handshake_address
+                    && !connection.is_server_address(&pk.address)
+                {
+                    // the client is trying to connect to someone else.
+                    connection.disconnect("Handshake address mismatch.", false);
+                    return;
+                }
 
-            // the client is actually trying to connect.
-            connection.state = ConnectionState::Connecting;
-            connection.send_packet(reply.into(), SendPriority::Immediate);
-            Ok(())
+                // the client may not raise the mtu we negotiated in the first reply,
+                // nor exceed the maximum we allow.
+                let mtu = pk
+                    .mtu_size
+                    .min(connection.mtu)
+                    .min(connection.max_mtu)
+                    .max(MIN_MTU_SIZE);
+
+                if mtu != pk.mtu_size {
+                    rak_debug!(
+                        "[RakNet] [{}] Client requested an mtu of {}, clamping to {}",
+                        connection.address,
+                        pk.mtu_size,
+                        mtu
+                    );
+                }
+
+                // todo: Actually check if we want the client to join the server!
+                // todo: And disconnect them if we don't!
+                let reply = SessionInfoReply {
+                    server_id: connection.server_guid,
+                    client_address,
+                    magic: Magic::new(),
+                    mtu_size: mtu,
+                    // todo: Again, make this optional
+                    security: false,
+                };
+                // the client is now officially in the "Connecting State"
+                connection.mtu = mtu;
+                connection.client_guid = Some(pk.client_id);
+
+                // the client is actually trying to connect.
+                connection.state = ConnectionState::Connecting;
+                connection
+                    .send_packet(reply.into(), SendPriority::Immediate)
+                    .map_err(|_| "The reply could not be sent!")
+            } else {
+                Err("The address of the connection could not be parsed!")
+            }
         }
         _ => {
             Err("A client can not send this packet, or the packet is not implemented for offline!")
@@ -99,13 +172,64 @@ pub fn handle_offline(connection: &mut Connection, packet: Packet) {
     if let Err(e) = result {
         // we're not going to panic because that would be bad in prod, so we'll just log it.
         rak_debug!(
-            "[RakNet] [{}] Received an offline packet that is not! {:?}",
+            "[RakNet] [{}] Failed to handle an offline packet: {}",
             connection.address,
             e
         );
     };
 }
 
+/// Sends the pong for an unconnected ping, and dispatches an event to update the Motd.
+fn reply_to_ping(
+    connection: &mut Connection,
+    timestamp: u64,
+    client_id: i64,
+) -> Result<(), SendError> {
+    let (pong, events) = build_pong(
+        connection.address.clone(),
+        timestamp,
+        client_id,
+        connection.server_guid,
+        &connection.motd,
+        &connection.motd_generator,
+    );
+    connection.event_dispatch.extend(events);
+    connection.send_packet(pong.into(), SendPriority::Immediate)
+}
+
+/// Builds the pong for an unconnected ping from the client with the given address token,
+/// along with the `Ping` and `Motd` events to dispatch for it. The Motd is generated for
+/// the client if there is a generator.
+pub(crate) fn build_pong(
+    address: String,
+    timestamp: u64,
+    client_id: i64,
+    server_id: u64,
+    motd: &RwLock<Motd>,
+    generator: &RwLock<Option<MotdGenerator>>,
+) -> (UnconnectedPong, [RakEvent; 2]) {
+    let generator = generator.read().unwrap().clone();
+    let motd = match (generator, from_address_token(address.clone())) {
+        (Some(generator), Ok(client)) => generator.generate(client),
+        _ => motd.read().unwrap().clone(),
+    };
+    let events = [
+        RakEvent::Ping(address.clone(), client_id, timestamp),
+        RakEvent::Motd(address, motd.clone()),
+    ];
+
+    // the client uses the timestamp we echo back to work out its latency,
+    // so it has to be the exact time the client sent.
+    let pong = UnconnectedPong {
+        server_id,
+        timestamp,
+        magic: Magic::new(),
+        #[cfg(feature = "mcpe")]
+        motd,
+    };
+    (pong, events)
+}
+
 pub fn handle_online(connection: &mut Connection, packet: Packet) -> Result<(), &str> {
     match packet.get_online() {
         OnlinePacket::ConnectedPing(pk) => {
@@ -116,30 +240,93 @@ pub fn handle_online(connection: &mut Connection, packet: Packet) -> Result<(),
                     .unwrap()
                     .as_millis() as i64,
             };
-            connection.send_packet(response.into(), SendPriority::Immediate);
+            connection
+                .send_packet(response.into(), SendPriority::Immediate)
+                .map_err(|_| "The pong could not be sent!")
+        }
+        OnlinePacket::ConnectedPong(pk) => {
+            // even a late pong tells us the client is still there.
+            connection.missed_pings = 0;
+
+            // only the reply to our last ping counts for the latency, anything else is
+            // late or made up.
+            if connection.outstanding_ping != Some(pk.ping_time) {
+                return Ok(());
+            }
+            connection.outstanding_ping = None;
+
+            // the time it took is the round trip.
+            let now = SystemTime::now()
+                .duration_since(connection.start_time)
+                .unwrap()
+                .as_millis() as i64;
+            let sample = Duration::from_millis(now.saturating_sub(pk.ping_time).max(0) as u64);
+            connection.add_latency_sample(sample);
+            Ok(())
+        }
+        OnlinePacket::DetectLostConnections(_) => {
+            // the client wants to know we're still here, a ping tells them just that.
+            connection.ping();
             Ok(())
         }
         OnlinePacket::ConnectionRequest(pk) => {
-            let response = ConnectionAccept {
-                system_index: 0,
-                client_address: from_address_token(connection.address.clone()),
-                internal_id: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)), 19132),
-                request_time: pk.time,
-                timestamp: SystemTime::now()
+            if connection.state != ConnectionState::Connecting {
+                // the client has to open the connection first, and once it's connected
+                // there is nothing left to accept.
+                return Ok(());
+            }
+            if let Some(datagrams) = connection.connection_accept.clone() {
+                // the client didn't get our reply yet, we send the exact same datagrams
+                // so the reply doesn't take up any new indexes.
+                for datagram in datagrams {
+                    connection
+                        .send_immediate(datagram)
+                        .map_err(|_| "The connection accept could not be sent!")?;
+                }
+                return Ok(());
+            }
+
+            let response = ConnectionAccept::new(
+                from_address_token(connection.address.clone())
+                    .map_err(|_| "The address of the connection could not be parsed!")?,
+                pk.time,
+                SystemTime::now()
                     .duration_since(connection.start_time)
                     .unwrap()
                     .as_millis() as i64,
-            };
-            connection.send_packet(response.into(), SendPriority::Immediate);
+            );
+            let response = Packet::from(response)
+                .parse()
+                .map_err(|_| "The connection accept could not be encoded!")?;
+            connection.connection_accept = Some(RakConnHandler::send_framed(
+                connection,
+                response,
+                Reliability::ReliableOrd,
+            ));
             Ok(())
         }
         OnlinePacket::Disconnect(_) => {
-            // Disconnect the client immediately.
+            // the client is leaving, acknowledge what we've got so it doesn't resend the
+            // notification, then disconnect the client immediately.
+            RakConnHandler::flush_acks(connection);
             connection.disconnect("Client disconnected.", false);
+            connection.state = ConnectionState::Disconnected;
             Ok(())
         }
         OnlinePacket::NewConnection(_) => {
-            connection.state = ConnectionState::Connected;
+            if connection.state != ConnectionState::Connecting {
+                // the client repeated itself, or never asked us to accept the connection.
+                return Ok(());
+            }
+            connection.complete_handshake();
+            Ok(())
+        }
+        OnlinePacket::Unknown(id) => {
+            rak_debug!(
+                "[RakNet] [{}] Ignoring unknown packet: {:#04x}",
+                connection.address,
+                id
+            );
             Ok(())
         }
         _ => Err("A client can not send this packet, or the packet is not implemented for online!"),
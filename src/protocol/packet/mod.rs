@@ -16,12 +16,13 @@ use binary_utils::Streamable;
 use byteorder::WriteBytesExt;
 
 use self::offline::{
-    IncompatibleProtocolVersion, OpenConnectReply, OpenConnectRequest, SessionInfoReply,
-    SessionInfoRequest, UnconnectedPing, UnconnectedPong,
+    AlreadyConnected, ConnectionBanned, IncompatibleProtocolVersion, NoFreeIncomingConnections,
+    OpenConnectReply, OpenConnectRequest, SessionInfoReply, SessionInfoRequest, UnconnectedPing,
+    UnconnectedPingOpenConnections, UnconnectedPong,
 };
 use self::online::{
-    ConnectedPing, ConnectedPong, ConnectionAccept, ConnectionRequest, Disconnect, LostConnection,
-    NewConnection,
+    ConnectedPing, ConnectedPong, ConnectionAccept, ConnectionRequest, DetectLostConnections,
+    Disconnect, NewConnection,
 };
 
 use super::offline::OfflinePacket;
@@ -105,6 +106,12 @@ impl Streamable for Payload {
                     OfflinePacket::UnconnectedPing(UnconnectedPing::compose(source, position)?);
                 Ok(Payload::Offline(packet))
             }
+            x if x == UnconnectedPingOpenConnections::id() => {
+                let packet = OfflinePacket::UnconnectedPingOpenConnections(
+                    UnconnectedPingOpenConnections::compose(source, position)?,
+                );
+                Ok(Payload::Offline(packet))
+            }
             x if x == UnconnectedPong::id() => {
                 let packet =
                     OfflinePacket::UnconnectedPong(UnconnectedPong::compose(source, position)?);
@@ -138,6 +145,22 @@ impl Streamable for Payload {
                 );
                 Ok(Payload::Offline(packet))
             }
+            x if x == AlreadyConnected::id() => {
+                let packet =
+                    OfflinePacket::AlreadyConnected(AlreadyConnected::compose(source, position)?);
+                Ok(Payload::Offline(packet))
+            }
+            x if x == ConnectionBanned::id() => {
+                let packet =
+                    OfflinePacket::ConnectionBanned(ConnectionBanned::compose(source, position)?);
+                Ok(Payload::Offline(packet))
+            }
+            x if x == NoFreeIncomingConnections::id() => {
+                let packet = OfflinePacket::NoFreeIncomingConnections(
+                    NoFreeIncomingConnections::compose(source, position)?,
+                );
+                Ok(Payload::Offline(packet))
+            }
             x if x == ConnectedPing::id() => {
                 let packet = OnlinePacket::ConnectedPing(ConnectedPing::compose(source, position)?);
                 Ok(Payload::Online(packet))
@@ -146,9 +169,10 @@ impl Streamable for Payload {
                 let packet = OnlinePacket::ConnectedPong(ConnectedPong::compose(source, position)?);
                 Ok(Payload::Online(packet))
             }
-            x if x == LostConnection::id() => {
-                let packet =
-                    OnlinePacket::LostConnection(LostConnection::compose(source, position)?);
+            x if x == DetectLostConnections::id() => {
+                let packet = OnlinePacket::DetectLostConnections(DetectLostConnections::compose(
+                    source, position,
+                )?);
                 Ok(Payload::Online(packet))
             }
             x if x == ConnectionRequest::id() => {
@@ -169,6 +193,8 @@ impl Streamable for Payload {
                 let packet = OnlinePacket::Disconnect(Disconnect::compose(source, position)?);
                 Ok(Payload::Online(packet))
             }
+            // anything below the frame ids is reserved for raknet itself.
+            x if x < 0x80 => Ok(Payload::Online(OnlinePacket::Unknown(x))),
             _ => Err(binary_utils::error::BinaryError::RecoverableKnown(format!(
                 "Id is not a valid raknet packet: {}",
                 id
@@ -183,20 +209,26 @@ impl Streamable for Payload {
             Payload::Online(packet) => match packet {
                 OnlinePacket::ConnectedPing(pk) => pk.parse()?,
                 OnlinePacket::ConnectedPong(pk) => pk.parse()?,
-                OnlinePacket::LostConnection(pk) => pk.parse()?,
+                OnlinePacket::DetectLostConnections(pk) => pk.parse()?,
                 OnlinePacket::ConnectionRequest(pk) => pk.parse()?,
                 OnlinePacket::ConnectionAccept(pk) => pk.parse()?,
                 OnlinePacket::NewConnection(pk) => pk.parse()?,
                 OnlinePacket::Disconnect(pk) => pk.parse()?,
+                // we don't know what the packet holds, so there's nothing more to write.
+                OnlinePacket::Unknown(_) => Vec::new(),
             },
             Payload::Offline(packet) => match packet {
                 OfflinePacket::UnconnectedPing(pk) => pk.parse()?,
+                OfflinePacket::UnconnectedPingOpenConnections(pk) => pk.parse()?,
                 OfflinePacket::UnconnectedPong(pk) => pk.parse()?,
                 OfflinePacket::OpenConnectRequest(pk) => pk.parse()?,
                 OfflinePacket::OpenConnectReply(pk) => pk.parse()?,
                 OfflinePacket::SessionInfoRequest(pk) => pk.parse()?,
                 OfflinePacket::SessionInfoReply(pk) => pk.parse()?,
                 OfflinePacket::IncompatibleProtocolVersion(pk) => pk.parse()?,
+                OfflinePacket::AlreadyConnected(pk) => pk.parse()?,
+                OfflinePacket::ConnectionBanned(pk) => pk.parse()?,
+                OfflinePacket::NoFreeIncomingConnections(pk) => pk.parse()?,
             },
         };
         if let Err(_) = buffer.write_all(&payload) {
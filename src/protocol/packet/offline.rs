@@ -11,13 +11,14 @@ pub use crate::protocol::mcpe::packet::UnconnectedPong;
 use super::Packet;
 use super::PacketId;
 use super::Payload;
-use crate::protocol::util::Magic;
-use crate::{packet_id, register_packets};
+use crate::protocol::util::{read_address, write_address, Magic};
+use crate::{packet_id, register_packets, UDP_HEADER_SIZE};
 
 /// A enum that represents all offline packets.
 #[derive(Clone, Debug)]
 pub enum OfflinePacket {
     UnconnectedPing(UnconnectedPing),
+    UnconnectedPingOpenConnections(UnconnectedPingOpenConnections),
     OpenConnectRequest(OpenConnectRequest),
     OpenConnectReply(OpenConnectReply),
     SessionInfoRequest(SessionInfoRequest),
@@ -27,17 +28,24 @@ pub enum OfflinePacket {
     #[cfg(not(feature = "mcpe"))]
     UnconnectedPong(UnconnectedPong),
     IncompatibleProtocolVersion(IncompatibleProtocolVersion),
+    AlreadyConnected(AlreadyConnected),
+    ConnectionBanned(ConnectionBanned),
+    NoFreeIncomingConnections(NoFreeIncomingConnections),
 }
 
 register_packets![
     Offline is OfflinePacket,
     UnconnectedPing,
+    UnconnectedPingOpenConnections,
     UnconnectedPong,
     OpenConnectRequest,
     OpenConnectReply,
     SessionInfoRequest,
     SessionInfoReply,
-    IncompatibleProtocolVersion
+    IncompatibleProtocolVersion,
+    AlreadyConnected,
+    ConnectionBanned,
+    NoFreeIncomingConnections
 ];
 
 /// Unconnected Ping
@@ -49,6 +57,16 @@ pub struct UnconnectedPing {
 }
 packet_id!(UnconnectedPing, 0x01);
 
+/// Unconnected Ping, only answered when the server has room for another connection.
+/// Clients use this for LAN discovery, so a full server isn't advertised.
+#[derive(Debug, Clone, BinaryStream)]
+pub struct UnconnectedPingOpenConnections {
+    pub timestamp: u64,
+    pub magic: Magic,
+    pub client_id: i64,
+}
+packet_id!(UnconnectedPingOpenConnections, 0x02);
+
 /// Unconnected Pong
 #[cfg(not(feature = "mcpe"))]
 #[derive(Debug, Clone, BinaryStream)]
@@ -61,6 +79,9 @@ pub struct UnconnectedPong {
 packet_id!(UnconnectedPong, 0x1c);
 
 /// This packet is the equivelant of the `OpenConnectRequest` packet in RakNet.
+///
+/// The client requests an mtu size by padding this packet, the mtu size is the size
+/// of the entire datagram, including the UDP and IP headers.
 #[derive(Debug, Clone)]
 pub struct OpenConnectRequest {
     pub magic: Magic,
@@ -72,7 +93,7 @@ impl Streamable for OpenConnectRequest {
         Ok(Self {
             magic: Magic::compose(source, position)?,
             protocol: u8::compose(source, position)?,
-            mtu_size: (source.len() + 1 + 28) as u16,
+            mtu_size: (source.len() as u16).saturating_add(UDP_HEADER_SIZE),
         })
     }
 
@@ -82,8 +103,9 @@ impl Streamable for OpenConnectRequest {
             .write(&self.magic.parse()?[..])
             .expect("Failed to parse open connect request");
         stream.write_u8(self.protocol)?;
-        // padding
-        for _ in 0..self.mtu_size {
+        // pad the packet up to the mtu size, accounting for the id, magic and protocol.
+        let header = UDP_HEADER_SIZE as usize + 1 + stream.len();
+        for _ in header..(self.mtu_size as usize) {
             stream.write_u8(0)?;
         }
         Ok(stream)
@@ -94,27 +116,120 @@ packet_id!(OpenConnectRequest, 0x05);
 // Open Connection Reply
 /// Sent to the client when the server accepts a client.
 /// This packet is the equivalent of the `Open Connect Reply 1` packet.
-#[derive(Debug, Clone, BinaryStream)]
+///
+/// When `security` is set, the reply carries a `cookie` the client has to echo
+/// back in the `SessionInfoRequest`.
+///
+/// The reply is padded up to the negotiated mtu size, so the client knows
+/// the path can actually carry datagrams of that size.
+#[derive(Debug, Clone)]
 pub struct OpenConnectReply {
     pub magic: Magic,
     pub server_id: u64,
     pub security: bool,
+    pub cookie: Option<u32>,
     pub mtu_size: u16,
 }
+impl Streamable for OpenConnectReply {
+    fn compose(source: &[u8], position: &mut usize) -> Result<Self, BinaryError> {
+        let magic = Magic::compose(source, position)?;
+        let server_id = u64::compose(source, position)?;
+        let security = bool::compose(source, position)?;
+        let cookie = if security {
+            Some(u32::compose(source, position)?)
+        } else {
+            None
+        };
+
+        let mtu_size = u16::compose(source, position)?;
+
+        // the rest of the datagram is padding.
+        *position = source.len();
+
+        Ok(Self {
+            magic,
+            server_id,
+            security,
+            cookie,
+            mtu_size,
+        })
+    }
+
+    fn parse(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut stream = Vec::<u8>::new();
+        stream.write_all(&self.magic.parse()?[..])?;
+        stream.write_all(&self.server_id.parse()?[..])?;
+        stream.write_all(&self.security.parse()?[..])?;
+        if self.security {
+            stream.write_all(&self.cookie.unwrap_or(0).parse()?[..])?;
+        }
+        stream.write_all(&self.mtu_size.parse()?[..])?;
+        // pad the packet up to the mtu size, accounting for the id.
+        let header = UDP_HEADER_SIZE as usize + 1 + stream.len();
+        for _ in header..(self.mtu_size as usize) {
+            stream.write_u8(0)?;
+        }
+        Ok(stream)
+    }
+}
 packet_id!(OpenConnectReply, 0x06);
 
 /// Session info, also known as Open Connect Request 2
-#[derive(Debug, Clone, BinaryStream)]
+///
+/// If the server sent a cookie in the `OpenConnectReply`, the client echoes it here.
+#[derive(Debug, Clone)]
 pub struct SessionInfoRequest {
     pub magic: Magic,
+    pub cookie: Option<u32>,
     pub address: SocketAddr,
     pub mtu_size: u16,
     pub client_id: i64,
 }
+impl Streamable for SessionInfoRequest {
+    fn compose(source: &[u8], position: &mut usize) -> Result<Self, BinaryError> {
+        let magic = Magic::compose(source, position)?;
+
+        // the cookie is only present if the server asked for it, which we can only tell
+        // by the size of the packet. Without it, the rest of the packet is an address
+        // (7 bytes for IPv4, 29 for IPv6), the mtu and the client id.
+        let cookie = match source.len().saturating_sub(*position) {
+            17 | 39 => None,
+            _ => {
+                let cookie = u32::compose(source, position)?;
+                // whether or not the client wrote a challenge, we don't support these.
+                if bool::compose(source, position)? {
+                    *position += 64;
+                }
+                Some(cookie)
+            }
+        };
+
+        Ok(Self {
+            magic,
+            cookie,
+            address: read_address(source, position)?,
+            mtu_size: u16::compose(source, position)?,
+            client_id: i64::compose(source, position)?,
+        })
+    }
+
+    fn parse(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut stream = Vec::<u8>::new();
+        stream.write_all(&self.magic.parse()?[..])?;
+        if let Some(cookie) = self.cookie {
+            stream.write_all(&cookie.parse()?[..])?;
+            stream.write_all(&false.parse()?[..])?;
+        }
+        stream.write_all(&write_address(&self.address)?[..])?;
+        stream.write_all(&self.mtu_size.parse()?[..])?;
+        stream.write_all(&self.client_id.parse()?[..])?;
+        Ok(stream)
+    }
+}
 packet_id!(SessionInfoRequest, 0x07);
 
 /// Session Info Reply, also known as Open Connect Reply 2
-#[derive(Debug, Clone, BinaryStream)]
+#[derive(Debug, Clone)]
 pub struct SessionInfoReply {
     pub magic: Magic,
     pub server_id: u64,
@@ -122,6 +237,27 @@ pub struct SessionInfoReply {
     pub mtu_size: u16,
     pub security: bool,
 }
+impl Streamable for SessionInfoReply {
+    fn compose(source: &[u8], position: &mut usize) -> Result<Self, BinaryError> {
+        Ok(Self {
+            magic: Magic::compose(source, position)?,
+            server_id: u64::compose(source, position)?,
+            client_address: read_address(source, position)?,
+            mtu_size: u16::compose(source, position)?,
+            security: bool::compose(source, position)?,
+        })
+    }
+
+    fn parse(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut stream = Vec::<u8>::new();
+        stream.write_all(&self.magic.parse()?[..])?;
+        stream.write_all(&self.server_id.parse()?[..])?;
+        stream.write_all(&write_address(&self.client_address)?[..])?;
+        stream.write_all(&self.mtu_size.parse()?[..])?;
+        stream.write_all(&self.security.parse()?[..])?;
+        Ok(stream)
+    }
+}
 packet_id!(SessionInfoReply, 0x08);
 
 #[derive(Debug, Clone, BinaryStream)]
@@ -131,3 +267,27 @@ pub struct IncompatibleProtocolVersion {
     pub server_id: u64,
 }
 packet_id!(IncompatibleProtocolVersion, 0x19);
+
+/// Sent to a client that is already connected, but tries to open another session.
+#[derive(Debug, Clone, BinaryStream)]
+pub struct AlreadyConnected {
+    pub magic: Magic,
+    pub server_id: u64,
+}
+packet_id!(AlreadyConnected, 0x12);
+
+/// Sent to a client that is banned from the server when it tries to connect.
+#[derive(Debug, Clone, BinaryStream)]
+pub struct ConnectionBanned {
+    pub magic: Magic,
+    pub server_id: u64,
+}
+packet_id!(ConnectionBanned, 0x17);
+
+/// Sent to a client that tries to connect while the server is full.
+#[derive(Debug, Clone, BinaryStream)]
+pub struct NoFreeIncomingConnections {
+    pub magic: Magic,
+    pub server_id: u64,
+}
+packet_id!(NoFreeIncomingConnections, 0x14);
@@ -11,6 +11,7 @@ use byteorder::WriteBytesExt;
 use super::Packet;
 use super::PacketId;
 use super::Payload;
+use crate::protocol::util::{read_address, write_address};
 use crate::{packet_id, register_packets};
 
 /// A enum that represents all online packets.
@@ -18,17 +19,20 @@ use crate::{packet_id, register_packets};
 pub enum OnlinePacket {
     ConnectedPing(ConnectedPing),
     ConnectedPong(ConnectedPong),
-    LostConnection(LostConnection),
+    DetectLostConnections(DetectLostConnections),
     ConnectionRequest(ConnectionRequest),
     ConnectionAccept(ConnectionAccept),
     NewConnection(NewConnection),
     Disconnect(Disconnect),
+    /// An internal RakNet packet we don't implement, by its id.
+    Unknown(u8),
 }
 
 register_packets![
     Online is OnlinePacket,
     ConnectedPing,
     ConnectedPong,
+    DetectLostConnections,
     ConnectionRequest,
     ConnectionAccept,
     NewConnection,
@@ -62,46 +66,76 @@ pub struct ConnectionRequest {
 }
 packet_id!(ConnectionRequest, 0x09);
 
+/// The amount of system addresses sent in `ConnectionAccept`.
+pub const SYSTEM_ADDRESS_COUNT: usize = 10;
+
 /// A connection Accept packet, this is sent by the server to the client.
 /// This is sent by the server and contains information about the server.
 #[derive(Clone, Debug)]
 pub struct ConnectionAccept {
-    /// The address of the client connecting (locally?).
+    /// The address of the client connecting, as the server sees it.
     pub client_address: SocketAddr,
     /// The system index is the index of the system that the client is connected to.
     /// This is the index of the server on the client.
     /// (Not sure why this is useful)
     pub system_index: i16,
-    /// The internal id's of the server or alternative IP's of the server.
-    /// These are addresses the client will use if it can't connect to the server.
-    /// (Not sure why this is useful)
-    pub internal_id: SocketAddr,
+    /// The internal addresses of the server, there are always `SYSTEM_ADDRESS_COUNT` of these.
+    /// The server doesn't expose its own, so these are placeholders.
+    pub system_addresses: Vec<SocketAddr>,
     /// The time of the timestamp the client sent with `ConnectionRequest`.
     pub request_time: i64,
     /// The time on the server.
     pub timestamp: i64,
 }
 
+impl ConnectionAccept {
+    /// Creates the reply to a `ConnectionRequest`, with the placeholder system addresses.
+    /// The first of these is the loopback address, every other one is unassigned.
+    pub fn new(client_address: SocketAddr, request_time: i64, timestamp: i64) -> Self {
+        let mut system_addresses =
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0); SYSTEM_ADDRESS_COUNT];
+        system_addresses[0] = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+        Self {
+            client_address,
+            system_index: 0,
+            system_addresses,
+            request_time,
+            timestamp,
+        }
+    }
+}
+
 impl Streamable for ConnectionAccept {
     fn parse(&self) -> Result<Vec<u8>, BinaryError> {
         let mut stream = Vec::new();
-        stream.write_all(&self.client_address.parse()?[..])?;
+        stream.write_all(&write_address(&self.client_address)?[..])?;
         stream.write_i16::<BigEndian>(self.system_index)?;
-        for _ in 0..10 {
-            stream.write_all(&self.internal_id.parse()?[..])?;
+        for address in self.system_addresses.iter() {
+            stream.write_all(&write_address(address)?[..])?;
         }
         stream.write_i64::<BigEndian>(self.request_time)?;
         stream.write_i64::<BigEndian>(self.timestamp)?;
         Ok(stream)
     }
 
-    fn compose(_source: &[u8], _position: &mut usize) -> Result<Self, BinaryError> {
+    fn compose(source: &[u8], position: &mut usize) -> Result<Self, BinaryError> {
+        let client_address = read_address(source, position)?;
+        let system_index = i16::compose(source, position)?;
+
+        // not every implementation sends the same amount of addresses,
+        // so we read them until only the timestamps are left.
+        let mut system_addresses = Vec::new();
+        while source.len().saturating_sub(*position) > 16 {
+            system_addresses.push(read_address(source, position)?);
+        }
+
         Ok(Self {
-            client_address: SocketAddr::new(IpAddr::from(Ipv4Addr::new(192, 168, 0, 1)), 9120),
-            system_index: 0,
-            internal_id: SocketAddr::new(IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)), 1920),
-            request_time: 0,
-            timestamp: 0,
+            client_address,
+            system_index,
+            system_addresses,
+            request_time: i64::compose(source, position)?,
+            timestamp: i64::compose(source, position)?,
         })
     }
 }
@@ -109,7 +143,7 @@ packet_id!(ConnectionAccept, 0x10);
 
 /// Going to be completely Honest here, I have no idea what this is used for right now,
 /// even after reading the source code.
-#[derive(Clone, Debug, BinaryStream)]
+#[derive(Clone, Debug)]
 pub struct NewConnection {
     /// The external IP Address of the server.
     pub server_address: SocketAddr,
@@ -120,6 +154,26 @@ pub struct NewConnection {
     /// The time on the server.
     pub timestamp: i64,
 }
+
+impl Streamable for NewConnection {
+    fn parse(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut stream = Vec::new();
+        stream.write_all(&write_address(&self.server_address)?[..])?;
+        stream.write_all(&write_address(&self.system_address)?[..])?;
+        stream.write_i64::<BigEndian>(self.request_time)?;
+        stream.write_i64::<BigEndian>(self.timestamp)?;
+        Ok(stream)
+    }
+
+    fn compose(source: &[u8], position: &mut usize) -> Result<Self, BinaryError> {
+        Ok(Self {
+            server_address: read_address(source, position)?,
+            system_address: read_address(source, position)?,
+            request_time: i64::compose(source, position)?,
+            timestamp: i64::compose(source, position)?,
+        })
+    }
+}
 packet_id!(NewConnection, 0x13);
 
 /// A disconnect notification. Tells the client to disconnect.
@@ -127,8 +181,7 @@ packet_id!(NewConnection, 0x13);
 pub struct Disconnect {}
 packet_id!(Disconnect, 0x15);
 
-/// A connection lost notification.
-/// This is sent by the client when it loses connection to the server.
+/// A keepalive probe, some clients send this to check whether the server is still there.
 #[derive(Clone, Debug, BinaryStream)]
-pub struct LostConnection {}
-packet_id!(LostConnection, 0x04);
+pub struct DetectLostConnections {}
+packet_id!(DetectLostConnections, 0x04);
@@ -1,7 +1,19 @@
+use std::io::{Cursor, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
 use binary_utils::{error::BinaryError, Streamable};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::protocol::offline::{
+    OpenConnectRequest, SessionInfoRequest, UnconnectedPing, UnconnectedPingOpenConnections,
+};
+use crate::protocol::PacketId;
 use crate::MAGIC;
 
+/// The address family RakNet writes for IPv6 addresses.
+/// This is `AF_INET6` as defined on Windows, where RakNet originates.
+pub const AF_INET6: u16 = 23;
+
 #[derive(Debug, Clone)]
 pub struct Magic(pub Vec<u8>);
 
@@ -18,11 +30,15 @@ impl Streamable for Magic {
 
     fn compose(source: &[u8], position: &mut usize) -> Result<Self, BinaryError> {
         // magic is 16 bytes
-        let pos = *position + (16 as usize);
-        let magic = &source[*position..pos];
-        *position += 16;
+        let pos = *position + MAGIC.len();
+        let magic = source
+            .get(*position..pos)
+            .ok_or(BinaryError::RecoverableKnown(
+                "Could not construct magic, the buffer is too short.".to_string(),
+            ))?;
+        *position += MAGIC.len();
 
-        if magic.to_vec() != MAGIC.to_vec() {
+        if magic != MAGIC {
             Err(BinaryError::RecoverableKnown(
                 "Could not construct magic from malformed bytes.".to_string(),
             ))
@@ -31,3 +47,91 @@ impl Streamable for Magic {
         }
     }
 }
+
+/// Verifies that the offline packet in the buffer carries `MAGIC` where its id says it should.
+///
+/// Only packets a client may send offline are accepted, anything else
+/// (or a packet that is too short) will fail verification.
+pub fn verify_magic(buffer: &[u8]) -> bool {
+    let offset = match buffer.first() {
+        // the pings are prefixed by a timestamp.
+        Some(&id) if id == UnconnectedPing::id() || id == UnconnectedPingOpenConnections::id() => 9,
+        Some(&id) if id == OpenConnectRequest::id() || id == SessionInfoRequest::id() => 1,
+        _ => return false,
+    };
+
+    buffer.get(offset..offset + MAGIC.len()) == Some(&MAGIC[..])
+}
+
+/// Reads a RakNet encoded address from the source at the given position.
+///
+/// IPv4 addresses are written as `4`, the four (inverted) octets and the port.
+/// IPv6 addresses are written as `6` followed by a `sockaddr_in6` structure:
+/// the family, port, flow info, the 16 address bytes and the scope id.
+pub fn read_address(source: &[u8], position: &mut usize) -> Result<SocketAddr, BinaryError> {
+    let mut stream = Cursor::new(source);
+    stream.set_position(*position as u64);
+
+    let address = match stream.read_u8()? {
+        4 => {
+            let mut octets = [0u8; 4];
+            for octet in octets.iter_mut() {
+                *octet = !stream.read_u8()?;
+            }
+            let port = stream.read_u16::<BigEndian>()?;
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port)
+        }
+        6 => {
+            // the family is not useful to us, we already know this is IPv6.
+            stream.read_u16::<LittleEndian>()?;
+            let port = stream.read_u16::<BigEndian>()?;
+            let flow_info = stream.read_u32::<BigEndian>()?;
+            let mut octets = [0u8; 16];
+            for octet in octets.iter_mut() {
+                *octet = stream.read_u8()?;
+            }
+            let scope_id = stream.read_u32::<BigEndian>()?;
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(octets),
+                port,
+                flow_info,
+                scope_id,
+            ))
+        }
+        version => {
+            return Err(BinaryError::RecoverableKnown(format!(
+                "Unknown address version: {}",
+                version
+            )));
+        }
+    };
+
+    *position = stream.position() as usize;
+    Ok(address)
+}
+
+/// Writes the given address in the format RakNet expects.
+/// See [`read_address`] for the layout of each address version.
+pub fn write_address(address: &SocketAddr) -> Result<Vec<u8>, BinaryError> {
+    let mut stream: Vec<u8> = Vec::new();
+
+    match address {
+        SocketAddr::V4(addr) => {
+            stream.write_u8(4)?;
+            for octet in addr.ip().octets() {
+                stream.write_u8(!octet)?;
+            }
+            stream.write_u16::<BigEndian>(addr.port())?;
+        }
+        SocketAddr::V6(addr) => {
+            stream.write_u8(6)?;
+            stream.write_u16::<LittleEndian>(AF_INET6)?;
+            stream.write_u16::<BigEndian>(addr.port())?;
+            stream.write_u32::<BigEndian>(addr.flowinfo())?;
+            stream.write_all(&addr.ip().octets())?;
+            stream.write_u32::<BigEndian>(addr.scope_id())?;
+        }
+    }
+
+    Ok(stream)
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use super::{RakNetServer, RakNetVersion};
+
+/// A builder to configure a `RakNetServer` in one place before it is started.
+///
+/// Anything that isn't set keeps the default of `RakNetServer::new`.
+/// Events are received through the channel given to `start`.
+///
+/// ```rust ignore
+/// let server = RakNetServerBuilder::new()
+///     .address("0.0.0.0:19132")
+///     .max_connections(20)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct RakNetServerBuilder {
+    address: String,
+    version: Option<RakNetVersion>,
+    max_connections: Option<usize>,
+    tick_interval: Option<Duration>,
+    max_mtu: Option<u16>,
+    guid: Option<u64>,
+}
+
+impl RakNetServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            address: "0.0.0.0:19132".into(),
+            version: None,
+            max_connections: None,
+            tick_interval: None,
+            max_mtu: None,
+            guid: None,
+        }
+    }
+
+    /// The address the server binds to.
+    pub fn address<S: Into<String>>(mut self, address: S) -> Self {
+        self.address = address.into();
+        self
+    }
+
+    /// The RakNet protocol version clients have to use.
+    pub fn version(mut self, version: RakNetVersion) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// The most connections the server will keep track of at once.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// How long the server waits between ticking its connections.
+    pub fn tick_interval(mut self, tick_interval: Duration) -> Self {
+        self.tick_interval = Some(tick_interval);
+        self
+    }
+
+    /// The largest mtu size the server will negotiate with clients.
+    pub fn max_mtu(mut self, max_mtu: u16) -> Self {
+        self.max_mtu = Some(max_mtu);
+        self
+    }
+
+    /// The guid the server identifies itself with, by default this is random.
+    /// Set this to keep the same identity across restarts.
+    pub fn guid(mut self, guid: u64) -> Self {
+        self.guid = Some(guid);
+        self
+    }
+
+    /// Creates the server with everything that was configured.
+    pub fn build(self) -> RakNetServer {
+        let mut server = RakNetServer::new(self.address);
+
+        if let Some(version) = self.version {
+            server.version = version;
+        }
+        if let Some(max_connections) = self.max_connections {
+            server.set_max_connections(max_connections);
+        }
+        if let Some(tick_interval) = self.tick_interval {
+            server.set_tick_interval(tick_interval);
+        }
+        if let Some(max_mtu) = self.max_mtu {
+            server.set_max_mtu(max_mtu);
+        }
+        if let Some(guid) = self.guid {
+            server.set_guid(guid);
+        }
+        server
+    }
+}
+
+impl Default for RakNetServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
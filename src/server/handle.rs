@@ -0,0 +1,56 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::connection::Connection;
+use crate::internal::frame::reliability::Reliability;
+
+/// The streams queued through a `ServerHandle`, these are handed to their connection
+/// on the next tick.
+pub(crate) type Outbox = Arc<Mutex<VecDeque<(String, Vec<u8>, Reliability)>>>;
+
+/// A lightweight handle to a server, to send to any of its clients from an event listener.
+///
+/// Listeners are called while the connection the event is about is locked, so they can't
+/// use `RakNetServer::send_stream`. The handle never locks a connection, streams are queued
+/// and sent on the next tick instead.
+///
+/// ```rust no_run
+/// use netrex_events::Channel;
+/// use rakrs::{start, RakEvent, RakNetServer, RakResult, Reliability};
+///
+/// # async fn run() {
+/// let server = RakNetServer::new("0.0.0.0:19132".into());
+/// let handle = server.handle();
+/// let mut listener = move |event: RakEvent, _: Option<RakResult>| {
+///     if let RakEvent::GamePacket(_, stream) = event {
+///         handle.send("127.0.0.1:19133", stream, Reliability::ReliableOrd);
+///     }
+///     None
+/// };
+/// let mut channel = Channel::new();
+/// channel.receive(&mut listener);
+///
+/// let (tasks, _, _) = start(server, channel).await;
+/// tasks.await;
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ServerHandle {
+    pub(crate) connections: Arc<RwLock<HashMap<String, Arc<Mutex<Connection>>>>>,
+    pub(crate) outbox: Outbox,
+}
+
+impl ServerHandle {
+    /// Queues the stream for the client with the given `ip:port` with the given reliability,
+    /// the stream is sent on the next tick. Returns whether or not there was such a client.
+    pub fn send(&self, address: &str, stream: Vec<u8>, reliability: Reliability) -> bool {
+        if !self.connections.read().unwrap().contains_key(address) {
+            return false;
+        }
+        self.outbox
+            .lock()
+            .unwrap()
+            .push_back((address.to_string(), stream, reliability));
+        true
+    }
+}
@@ -1,9 +1,28 @@
+/// Server statistics.
+mod stats;
+
+pub use self::stats::*;
+
 #[cfg(feature = "async_tokio")]
 mod tokio;
 
 #[cfg(feature = "async_tokio")]
 pub use self::tokio::*;
 
+/// A builder for the server.
+#[cfg(feature = "async_tokio")]
+mod builder;
+
+#[cfg(feature = "async_tokio")]
+pub use self::builder::*;
+
+/// A handle to send to clients from event listeners.
+#[cfg(feature = "async_tokio")]
+mod handle;
+
+#[cfg(feature = "async_tokio")]
+pub use self::handle::*;
+
 #[cfg(feature = "async_std")]
 mod std;
 
@@ -0,0 +1,80 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::rak_warn;
+
+/// The counters the server updates while it's running.
+#[derive(Debug, Default)]
+pub(crate) struct ServerCounters {
+    pub(crate) rate_limited: AtomicU64,
+    pub(crate) handshake_timeouts: AtomicU64,
+    pub(crate) send_errors: AtomicU64,
+}
+
+impl ServerCounters {
+    pub(crate) fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the whole datagram made it out of the socket. Failed and partial sends are
+    /// logged and counted as send errors.
+    pub(crate) fn record_send(&self, address: &str, result: io::Result<usize>, len: usize) -> bool {
+        match result {
+            Ok(sent) if sent == len => return true,
+            Ok(sent) => rak_warn!(
+                "[RakNet] [{}] Only sent {} of {} bytes.",
+                address,
+                sent,
+                len
+            ),
+            Err(e) => rak_warn!("[RakNet] [{}] Error sending packet: {}", address, e),
+        }
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+/// A snapshot of the server's statistics.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerStats {
+    /// The amount of offline packets dropped because their sender exceeded the rate limit.
+    pub rate_limited: u64,
+    /// The amount of connections removed because they never finished the handshake in time.
+    pub handshake_timeouts: u64,
+    /// The amount of datagrams the socket failed to send, or only sent in part.
+    pub send_errors: u64,
+    /// The amount of bytes sent to the connections the server currently has.
+    pub bytes_sent: u64,
+    /// The amount of bytes received from the connections the server currently has.
+    pub bytes_recv: u64,
+    /// The amount of datagrams sent to the connections the server currently has.
+    pub packets_sent: u64,
+    /// The amount of datagrams received from the connections the server currently has.
+    pub packets_recv: u64,
+    /// The amount of fragmented frames the connections the server currently has dropped,
+    /// because they weren't completed in time.
+    pub fragments_dropped: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_writes_are_send_errors() {
+        let counters = ServerCounters::default();
+        assert!(counters.record_send("127.0.0.1:19133", Ok(5), 5));
+        assert!(!counters.record_send("127.0.0.1:19133", Ok(3), 5));
+        assert!(!counters.record_send(
+            "127.0.0.1:19133",
+            Err(io::ErrorKind::AddrNotAvailable.into()),
+            5
+        ));
+        assert_eq!(counters.snapshot().send_errors, 2);
+    }
+}
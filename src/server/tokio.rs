@@ -1,25 +1,60 @@
 use futures::Future;
 use netrex_events::Channel;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::time::Duration;
 use std::time::SystemTime;
 use tokio::net::UdpSocket;
 use tokio::time::sleep;
 
 use crate::connection::state::ConnectionState;
-use crate::connection::Connection;
+use crate::connection::{
+    Connection, ConnectionInfo, DEFAULT_FRAGMENT_TIMEOUT, DEFAULT_HANDSHAKE_TIMEOUT,
+    DEFAULT_MAX_FRAGMENTED_FRAMES, DEFAULT_MAX_FRAGMENTED_SIZE, DEFAULT_MAX_FRAGMENTS,
+    DEFAULT_MAX_MISSED_PINGS, DEFAULT_MAX_ORDERED_PACKETS, DEFAULT_PING_INTERVAL, DEFAULT_TIMEOUT,
+    DEFAULT_USER_PACKET_IDS,
+};
+use crate::internal::cookie::CookieJar;
+use crate::internal::frame::reliability::Reliability;
 use crate::internal::queue::SendPriority;
+use crate::internal::rate_limit::RateLimiter;
 use crate::internal::util::from_address_token;
 use crate::internal::util::to_address_token;
-use crate::protocol::mcpe::motd::Motd;
-use crate::rak_debug;
+use crate::protocol::handler::build_pong;
+use crate::protocol::mcpe::motd::{Motd, MotdGenerator};
+use crate::protocol::offline::{
+    ConnectionBanned, NoFreeIncomingConnections, OfflinePacket, UnconnectedPing,
+    UnconnectedPingOpenConnections,
+};
+use crate::protocol::util::Magic;
+use crate::protocol::{Packet, PacketId};
+use crate::{rak_debug, rak_warn};
+use crate::{MAX_FRAMED_MTU_SIZE, MAX_MTU_SIZE};
+
+use super::handle::{Outbox, ServerHandle};
+use super::stats::{ServerCounters, ServerStats};
+
+/// The amount of offline packets a single ip may send per second by default.
+pub const DEFAULT_RATE_LIMIT: u32 = 20;
+
+/// The amount of offline packets a single ip may send at once by default.
+pub const DEFAULT_RATE_LIMIT_BURST: u32 = 40;
+
+/// The smallest buffer datagrams are received into, this is the ethernet mtu.
+/// Clients probe for an mtu up to this size before one is negotiated.
+const MIN_RECV_BUFFER_SIZE: usize = 1500;
+
+/// How long the server waits between ticking its connections by default.
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 #[repr(u8)]
 pub enum RakNetVersion {
+    V11 = 11,
     V10 = 10,
     V6 = 6,
 }
@@ -27,12 +62,16 @@ pub enum RakNetVersion {
 impl RakNetVersion {
     pub fn to_u8(&self) -> u8 {
         match self {
+            RakNetVersion::V11 => 11,
             RakNetVersion::V10 => 10,
             RakNetVersion::V6 => 6,
         }
     }
 }
 
+/// The events the server dispatches to the listeners of the channel given to `start`.
+/// Every connection gets a `Connect` once its handshake completes, a `GamePacket` for
+/// each game packet it sends, and a `Disconnect` when it leaves.
 #[derive(Clone, Debug)]
 pub enum RakEvent {
     /// When a connection is created
@@ -42,6 +81,12 @@ pub enum RakEvent {
     /// **Tuple Values**:
     /// 1. The parsed `ip:port` address of the connection.
     ConnectionCreated(String),
+    /// When a client completes the handshake and is connected to the server.
+    ///
+    /// **Tuple Values**:
+    /// 1. The parsed `ip:port` address of the connection.
+    /// 2. The guid of the client.
+    Connect(String, i64),
     /// When a connection disconnects from the server
     /// Or the server forces the connection to disconnect
     ///
@@ -56,12 +101,45 @@ pub enum RakEvent {
     /// 1. The parsed `ip:port` address of the connection.
     /// 2. The `Motd` that might be sent.
     Motd(String, Motd),
+    /// When the latency of a client exceeds the `latency_threshold` of the server.
+    /// This is dispatched for every ping while the client stays above it.
+    ///
+    /// **Tuple Values**:
+    /// 1. The parsed `ip:port` address of the connection.
+    /// 2. The smoothed latency of the client in milliseconds.
+    Latency(String, u32),
+    /// When a client pings the server, usually to refresh its server list.
+    ///
+    /// **Tuple Values**:
+    /// 1. The parsed `ip:port` address of the client.
+    /// 2. The guid of the client.
+    /// 3. The timestamp the client sent the ping at.
+    Ping(String, i64, u64),
     /// When a game packet is recieved.
     ///
     /// **Tuple Values**:
     /// 1. The parsed `ip:port` address of the connection.
     /// 2. The packet `Vec<u8>` recieved from the connection.
     GamePacket(String, Vec<u8>),
+    /// When the client acknowledged a packet that was sent with a receipt.
+    ///
+    /// **Tuple Values**:
+    /// 1. The parsed `ip:port` address of the connection.
+    /// 2. The receipt the packet was sent with.
+    AckReceipt(String, u32),
+    /// When a packet that was sent with a receipt was never acknowledged by the client.
+    ///
+    /// **Tuple Values**:
+    /// 1. The parsed `ip:port` address of the connection.
+    /// 2. The receipt the packet was sent with.
+    LossReceipt(String, u32),
+    /// When a client breaks the protocol, such as by holding back too many ordered packets.
+    /// The offending packet is dropped.
+    ///
+    /// **Tuple Values**:
+    /// 1. The parsed `ip:port` address of the connection.
+    /// 2. What the client did wrong.
+    ProtocolViolation(String, String),
     /// When RakNet Errors in some way that is recoverable.
     ///
     /// **Tuple Values**:
@@ -82,9 +160,15 @@ impl RakEvent {
     pub fn get_name(&self) -> String {
         match self {
             RakEvent::ConnectionCreated(_) => "ConnectionCreated".into(),
+            RakEvent::Connect(_, _) => "Connect".into(),
             RakEvent::Disconnect(_, _) => "Disconnect".into(),
             RakEvent::GamePacket(_, _) => "GamePacket".into(),
             RakEvent::Motd(_, _) => "Motd".into(),
+            RakEvent::Ping(_, _, _) => "Ping".into(),
+            RakEvent::Latency(_, _) => "Latency".into(),
+            RakEvent::AckReceipt(_, _) => "AckReceipt".into(),
+            RakEvent::LossReceipt(_, _) => "LossReceipt".into(),
+            RakEvent::ProtocolViolation(_, _) => "ProtocolViolation".into(),
             RakEvent::Error(_) => "Error".into(),
             RakEvent::ComplexBinaryError(_, _, _) => "ComplexBinaryError".into(),
         }
@@ -93,10 +177,11 @@ impl RakEvent {
 
 #[derive(Clone, Debug)]
 pub enum RakResult {
-    /// Update the Motd for that specific client.
+    /// Update the Motd advertised by the server.
+    /// This is reflected on the next ping of any client.
     ///
     /// **Tuple Values**:
-    /// 1. The `Motd` for the current connection.
+    /// 1. The new `Motd` for the server.
     Motd(Motd),
     /// Force the raknet server to invoke `panic!`.
     ///
@@ -112,26 +197,494 @@ pub enum RakResult {
 
 pub struct RakNetServer {
     pub address: String,
+    /// Further addresses the server binds to alongside `address`, for example an IPv6 address
+    /// to serve both stacks. Clients are answered from the address they sent to.
+    pub extra_addresses: Vec<String>,
     pub version: RakNetVersion,
-    pub connections: Arc<RwLock<HashMap<String, Connection>>>,
+    /// Additional protocol versions accepted alongside `version`.
+    /// Clients requesting any other version are sent `IncompatibleProtocolVersion`.
+    pub allowed_versions: Vec<RakNetVersion>,
+    /// The connections keyed by their `ip:port`. Each connection has a lock of its own,
+    /// so one connection can be ticked while another is receiving.
+    pub connections: Arc<RwLock<HashMap<String, Arc<Mutex<Connection>>>>>,
     pub start_time: SystemTime,
+    /// The guid the server identifies itself with, this is random for every server
+    /// unless set with `set_guid`.
     pub server_guid: u64,
+    /// The message of the day sent to clients in the `UnconnectedPong`.
+    /// This is shared with every connection, so updating it while the server
+    /// is running will be reflected on the next ping.
+    pub motd: Arc<RwLock<Motd>>,
+    /// An optional callback used to generate the `motd` for every ping.
+    /// If this is not set, the `motd` above is used.
+    pub motd_generator: Arc<RwLock<Option<MotdGenerator>>>,
+    /// Whether or not the player count of the `motd` should be kept in sync
+    /// with the amount of connected clients.
+    pub track_player_count: bool,
+    /// The largest mtu size the server will negotiate with a client.
+    pub max_mtu: u16,
+    /// How long a connection may go without sending a packet before it is disconnected.
+    pub timeout: Duration,
+    /// How long a client has to complete the handshake before it is dropped.
+    pub handshake_timeout: Duration,
+    /// How often connected clients are sent a `ConnectedPing` to keep them alive.
+    pub ping_interval: Duration,
+    /// How many `ConnectedPing`s in a row a client may leave unanswered before it is disconnected.
+    pub max_missed_pings: u32,
+    /// The latency above which a `Latency` event is dispatched for a client.
+    /// Disabled by default.
+    pub latency_threshold: Option<Duration>,
+    /// How long the server waits for the remaining fragments of a frame before dropping them.
+    pub fragment_timeout: Duration,
+    /// The most fragments a client may split a single frame into.
+    pub max_fragments: u32,
+    /// The largest a fragmented frame of a client may be once it's reassembled, in bytes.
+    pub max_fragmented_size: usize,
+    /// The most fragmented frames a client may have waiting for reassembly at once.
+    pub max_fragmented_frames: usize,
+    /// The most packets held back on a single order channel of a client.
+    pub max_ordered_packets: usize,
+    /// How long the server waits between ticking its connections.
+    /// Acks, queued packets and timeouts are all handled on a tick.
+    pub tick_interval: Duration,
+    /// The most connections the server will keep track of at once.
+    /// Clients trying to connect while the server is full are sent `NoFreeIncomingConnections`.
+    /// Defaults to unlimited.
+    pub max_connections: usize,
+    /// Whether or not clients have to echo a cookie from the `OpenConnectReply` before
+    /// the server keeps track of them. This prevents spoofed addresses from filling
+    /// the server, but not every client supports it. Disabled by default.
+    pub use_cookies: bool,
+    /// The secret used to generate the cookies.
+    pub(crate) cookies: CookieJar,
+    /// The amount of offline packets (pings, handshakes) a single ip may send per second.
+    /// Packets exceeding this are dropped, a rate of `0` disables the limit.
+    /// Connected clients are not limited.
+    pub rate_limit: u32,
+    /// The amount of offline packets a single ip may send at once before being limited.
+    pub rate_limit_burst: u32,
+    /// The counters behind `stats`.
+    pub(crate) counters: Arc<ServerCounters>,
+    /// The events for clients the server doesn't keep track of, such as pings.
+    /// These are dispatched on the next tick.
+    pub(crate) offline_events: Mutex<VecDeque<RakEvent>>,
+    /// The streams queued through a `ServerHandle`, these are sent on the next tick.
+    pub(crate) outbox: Outbox,
+    /// The addresses that are banned from the server.
+    /// These are either an exact `ip:port` or a bare ip, banning every port.
+    pub bans: Arc<RwLock<HashSet<String>>>,
+    /// Whether or not clients have to send the address the server is bound to in their
+    /// `SessionInfoRequest`. Behind a proxy or NAT clients see a different address,
+    /// so this is disabled by default.
+    pub validate_handshake_address: bool,
+    /// Whether a connected client may start a new handshake from the same address,
+    /// replacing their old session. By default they are sent `AlreadyConnected`.
+    pub allow_reconnect: bool,
+    /// The ids of the packets that are passed on as a `GamePacket`, anything else is handled
+    /// by raknet. By default this is only the minecraft game packet, `0xfe`.
+    pub user_packet_ids: RangeInclusive<u8>,
+    /// Whether game packets a client sends before it completes the handshake are held
+    /// until it does. By default these are dropped.
+    pub buffer_early_packets: bool,
     pub stop: bool,
 }
 
 impl RakNetServer {
     pub fn new(address: String) -> Self {
+        let server_guid = rand::random::<u64>();
+        let port = address
+            .parse::<SocketAddr>()
+            .map(|addr| addr.port())
+            .unwrap_or(19132);
         Self {
             address,
+            extra_addresses: Vec::new(),
             version: RakNetVersion::V10,
+            allowed_versions: Vec::new(),
             connections: Arc::new(RwLock::new(HashMap::new())),
             start_time: SystemTime::now(),
-            server_guid: rand::random::<u64>(),
+            server_guid,
+            motd: Arc::new(RwLock::new(Motd::new(server_guid, port.to_string()))),
+            motd_generator: Arc::new(RwLock::new(None)),
+            track_player_count: true,
+            max_mtu: MAX_MTU_SIZE,
+            allow_reconnect: false,
+            user_packet_ids: DEFAULT_USER_PACKET_IDS,
+            buffer_early_packets: false,
+            validate_handshake_address: false,
+            timeout: DEFAULT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_missed_pings: DEFAULT_MAX_MISSED_PINGS,
+            latency_threshold: None,
+            fragment_timeout: DEFAULT_FRAGMENT_TIMEOUT,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+            max_fragmented_size: DEFAULT_MAX_FRAGMENTED_SIZE,
+            max_fragmented_frames: DEFAULT_MAX_FRAGMENTED_FRAMES,
+            max_ordered_packets: DEFAULT_MAX_ORDERED_PACKETS,
+            tick_interval: DEFAULT_TICK_INTERVAL,
+            max_connections: usize::MAX,
+            use_cookies: false,
+            cookies: CookieJar::new(),
+            rate_limit: DEFAULT_RATE_LIMIT,
+            rate_limit_burst: DEFAULT_RATE_LIMIT_BURST,
+            counters: Arc::new(ServerCounters::default()),
+            offline_events: Mutex::new(VecDeque::new()),
+            outbox: Arc::new(Mutex::new(VecDeque::new())),
+            bans: Arc::new(RwLock::new(HashSet::new())),
             stop: false,
         }
     }
+
+    /// The guid the server identifies itself with to clients.
+    pub fn guid(&self) -> u64 {
+        self.server_guid
+    }
+
+    /// Sets the guid the server identifies itself with, use this to keep the same
+    /// identity across restarts. This must be set before the server is started.
+    pub fn set_guid(&mut self, guid: u64) {
+        self.server_guid = guid;
+        self.motd.write().unwrap().server_guid = guid;
+    }
+
+    /// Updates the message of the day advertised by the server.
+    pub fn set_motd(&self, motd: Motd) {
+        *self.motd.write().unwrap() = motd;
+    }
+
+    /// Returns a copy of the current message of the day.
+    pub fn get_motd(&self) -> Motd {
+        self.motd.read().unwrap().clone()
+    }
+
+    /// Sets a callback that generates the message of the day every time a client pings the server.
+    /// The callback is given the address of the client pinging the server.
+    pub fn set_motd_generator(&self, generator: Box<dyn Fn(SocketAddr) -> Motd + Send + Sync>) {
+        *self.motd_generator.write().unwrap() = Some(generator.into());
+    }
+
+    /// Removes the motd generator, the static `motd` will be used again.
+    pub fn clear_motd_generator(&self) {
+        *self.motd_generator.write().unwrap() = None;
+    }
+
+    /// Accepts clients using the given protocol version alongside `version`.
+    pub fn allow_version(&mut self, version: RakNetVersion) {
+        if version != self.version && !self.allowed_versions.contains(&version) {
+            self.allowed_versions.push(version);
+        }
+    }
+
+    /// Binds the server to the given address alongside `address` once it is started.
+    pub fn add_address<S: Into<String>>(&mut self, address: S) {
+        self.extra_addresses.push(address.into());
+    }
+
+    /// Bans the given address from the server, this can either be an `ip:port` or a bare ip.
+    /// Clients that are already connected from this address are disconnected.
+    pub fn ban<S: Into<String>>(&self, address: S) {
+        self.bans.write().unwrap().insert(ban_token(address.into()));
+
+        for client in self.connections.read().unwrap().values() {
+            let mut client = client.lock().unwrap();
+            if self.is_banned(&client.address) && !client.is_disconnecting() {
+                client.disconnect("Banned", true);
+            }
+        }
+    }
+
+    /// Disconnects the client with the given `ip:port`, they are sent a `Disconnect` notification
+    /// before the connection is removed. Returns whether or not there was such a client.
+    pub fn kick(&self, address: &str) -> bool {
+        match self.connections.read().unwrap().get(address) {
+            Some(client) => {
+                let mut client = client.lock().unwrap();
+                if client.is_disconnecting() {
+                    return false;
+                }
+                client.disconnect("Kicked", true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends the stream to the client with the given `ip:port` with the given reliability,
+    /// the stream is sent on the next tick. Returns whether or not there was such a client.
+    pub fn send_stream(&self, address: &str, stream: Vec<u8>, reliability: Reliability) -> bool {
+        match self.connections.read().unwrap().get(address) {
+            // the default channel always exists.
+            Some(client) => client
+                .lock()
+                .unwrap()
+                .send_reliable(stream, reliability, None)
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Returns a handle to send to clients with from event listeners, this can be taken
+    /// before the server is started.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            connections: self.connections.clone(),
+            outbox: self.outbox.clone(),
+        }
+    }
+
+    /// Binds the server and drives it on the current tokio runtime until it stops, events are
+    /// dispatched to the listeners of `channel`. Take a `handle` first to send to clients,
+    /// or use `start` to get the running server back.
+    pub async fn start_async(self, channel: Channel<'_, RakEvent, RakResult>) {
+        let (tasks, _, _) = start(self, channel).await;
+        tasks.await;
+    }
+
+    /// Sends the stream to every connected client except `exclude`, with the given reliability.
+    /// The stream is sent on the next tick. Returns how many clients it was sent to.
+    pub fn broadcast(
+        &self,
+        stream: Vec<u8>,
+        reliability: Reliability,
+        exclude: Option<SocketAddr>,
+    ) -> usize {
+        let exclude = exclude.map(to_address_token);
+        let mut sent = 0;
+        for (address, client) in self.connections.read().unwrap().iter() {
+            let mut client = client.lock().unwrap();
+            if client.state != ConnectionState::Connected || exclude.as_ref() == Some(address) {
+                continue;
+            }
+            if client
+                .send_reliable(stream.clone(), reliability, None)
+                .is_ok()
+            {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// Lifts a ban previously placed with `ban`.
+    pub fn unban<S: Into<String>>(&self, address: S) {
+        self.bans
+            .write()
+            .unwrap()
+            .remove(&ban_token(address.into()));
+    }
+
+    /// Whether or not the given `ip:port` (or bare ip) is banned from the server.
+    pub fn is_banned(&self, address: &str) -> bool {
+        let bans = self.bans.read().unwrap();
+        if bans.contains(&ban_token(address.into())) {
+            return true;
+        }
+
+        match address.parse::<SocketAddr>() {
+            Ok(address) => bans.contains(&address.ip().to_string()),
+            Err(_) => false,
+        }
+    }
+
+    /// Sets how long a connection may go without sending a packet before it is disconnected.
+    /// This is only applied to connections created after this is called.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how long a client has to complete the handshake before it is dropped.
+    /// This is only applied to connections created after this is called.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Sets how often connected clients are sent a `ConnectedPing`.
+    /// This is only applied to connections created after this is called.
+    pub fn set_ping_interval(&mut self, interval: Duration) {
+        self.ping_interval = interval;
+    }
+
+    /// Sets how many `ConnectedPing`s in a row a client may leave unanswered before it is
+    /// disconnected. This is only applied to connections created after this is called.
+    pub fn set_max_missed_pings(&mut self, max: u32) {
+        self.max_missed_pings = max;
+    }
+
+    /// Dispatches a `Latency` event whenever the latency of a client exceeds the threshold.
+    /// This is only applied to connections created after this is called.
+    pub fn set_latency_threshold(&mut self, threshold: Duration) {
+        self.latency_threshold = Some(threshold);
+    }
+
+    /// Sets how long the server waits for the remaining fragments of a frame.
+    /// This is only applied to connections created after this is called.
+    pub fn set_fragment_timeout(&mut self, timeout: Duration) {
+        self.fragment_timeout = timeout;
+    }
+
+    /// Sets the most fragments a client may split a single frame into.
+    /// This is only applied to connections created after this is called.
+    pub fn set_max_fragments(&mut self, max: u32) {
+        self.max_fragments = max;
+    }
+
+    /// Sets the largest a fragmented frame of a client may be once it's reassembled, in bytes.
+    /// This is only applied to connections created after this is called.
+    pub fn set_max_fragmented_size(&mut self, max: usize) {
+        self.max_fragmented_size = max;
+    }
+
+    /// Sets the most fragmented frames a client may have waiting for reassembly at once.
+    /// This is only applied to connections created after this is called.
+    pub fn set_max_fragmented_frames(&mut self, max: usize) {
+        self.max_fragmented_frames = max;
+    }
+
+    /// Sets the most packets held back on a single order channel of a client.
+    /// This is only applied to connections created after this is called.
+    pub fn set_max_ordered_packets(&mut self, max: usize) {
+        self.max_ordered_packets = max;
+    }
+
+    /// The smoothed latency of the connection with the given `ip:port` in milliseconds.
+    /// This is `None` if there is no such connection, or it hasn't answered a ping yet.
+    pub fn ping_of(&self, address: &str) -> Option<u32> {
+        self.connections
+            .read()
+            .unwrap()
+            .get(address)
+            .and_then(|connection| connection.lock().unwrap().latency())
+            .map(|latency| latency.as_millis() as u32)
+    }
+
+    /// Returns a snapshot of every connection the server currently has.
+    /// Each connection is only locked while its own snapshot is taken.
+    pub fn connections_snapshot(&self) -> Vec<ConnectionInfo> {
+        let connections = self
+            .connections
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        connections
+            .iter()
+            .map(|connection| connection.lock().unwrap().info())
+            .collect()
+    }
+
+    /// Sets how long the server waits between ticking its connections.
+    /// This must be set before the server is started.
+    ///
+    /// Panics if the interval is zero, the server would never stop ticking.
+    pub fn set_tick_interval(&mut self, tick_interval: Duration) {
+        assert!(
+            !tick_interval.is_zero(),
+            "The tick interval can not be zero"
+        );
+        self.tick_interval = tick_interval;
+    }
+
+    /// Sets how many offline packets a single ip may send per second, and at once.
+    /// A rate of `0` disables the limit. This must be set before the server is started.
+    pub fn set_rate_limit(&mut self, per_second: u32, burst: u32) {
+        self.rate_limit = per_second;
+        self.rate_limit_burst = burst;
+    }
+
+    /// Returns a snapshot of the server's statistics.
+    /// The traffic totals only cover the connections the server currently has.
+    pub fn stats(&self) -> ServerStats {
+        let mut stats = self.counters.snapshot();
+        for client in self.connections.read().unwrap().values() {
+            let traffic = client.lock().unwrap().stats();
+            stats.bytes_sent += traffic.bytes_sent;
+            stats.bytes_recv += traffic.bytes_recv;
+            stats.packets_sent += traffic.packets_sent;
+            stats.packets_recv += traffic.packets_recv;
+            stats.fragments_dropped += traffic.fragments_dropped;
+        }
+        stats
+    }
+
+    /// Sets the most connections the server will keep track of at once.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Sets the largest mtu size the server will negotiate with clients.
+    /// This is only applied to connections created after this is called.
+    ///
+    /// Datagrams are received into a buffer of this size (or at least 1500 bytes),
+    /// so this must be set before the server is started. Sizes above the ethernet mtu
+    /// are only useful on loopback or networks with jumbo frames.
+    /// Sizes above `MAX_FRAMED_MTU_SIZE` are clamped to it.
+    pub fn set_max_mtu(&mut self, mtu: u16) {
+        self.max_mtu = mtu.min(MAX_FRAMED_MTU_SIZE);
+    }
+}
+
+/// Normalizes an `ip:port` or bare ip so it can be looked up in the ban list.
+fn ban_token(address: String) -> String {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        to_address_token(address)
+    } else if let Ok(ip) = address.parse::<IpAddr>() {
+        ip.to_string()
+    } else {
+        address
+    }
+}
+
+/// Whether or not the datagram is an unconnected ping with the given id.
+fn is_ping(data: &[u8], id: u8) -> bool {
+    data.first() == Some(&id)
 }
 
+/// Whether or not the datagram is an `OpenConnectRequest`, the first packet of a handshake.
+fn is_open_connect_request(data: &[u8]) -> bool {
+    use binary_utils::Streamable;
+
+    match Packet::compose(data, &mut 0) {
+        Ok(packet) if packet.is_offline() => {
+            matches!(packet.get_offline(), OfflinePacket::OpenConnectRequest(_))
+        }
+        _ => false,
+    }
+}
+
+/// Answers an unconnected ping without keeping track of the client, most pings come from
+/// server lists that never connect. Returns the pong to send back, if this is a ping.
+fn answer_ping(server: &RakNetServer, address: SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+    use binary_utils::Streamable;
+
+    let packet = Packet::compose(data, &mut 0).ok()?;
+    if !packet.is_offline() {
+        return None;
+    }
+    let (timestamp, client_id) = match packet.get_offline() {
+        OfflinePacket::UnconnectedPing(pk) => (pk.timestamp, pk.client_id),
+        OfflinePacket::UnconnectedPingOpenConnections(pk) => (pk.timestamp, pk.client_id),
+        _ => return None,
+    };
+
+    let (pong, events) = build_pong(
+        to_address_token(address),
+        timestamp,
+        client_id,
+        server.server_guid,
+        &server.motd,
+        &server.motd_generator,
+    );
+    server.offline_events.lock().unwrap().extend(events);
+    Packet::from(pong).parse().ok()
+}
+
+/// Binds the server and returns the future driving it, along with the server itself and
+/// a sender to send packets to clients with.
+///
+/// Listeners registered on `send_channel` with `receive` are called for every `RakEvent`,
+/// the `RakResult` they return is applied to the connection the event is about.
 pub async fn start<'a>(
     s: RakNetServer,
     send_channel: Channel<'a, RakEvent, RakResult>,
@@ -154,21 +707,26 @@ pub async fn start<'a>(
     // While the sender should already have this, the server does become
     // owned and pushed out of scope after execution.
     let ret_server = send_server.clone();
-    let sock = UdpSocket::bind(
-        server
-            .address
-            .parse::<SocketAddr>()
-            .expect("Failed to bind to address."),
-    )
-    .await
-    .unwrap();
-    let port = server.address.parse::<SocketAddr>().unwrap().port();
+    // Every address the server is bound to has a socket of its own.
+    let mut sockets = Vec::new();
+    for address in std::iter::once(&server.address).chain(server.extra_addresses.iter()) {
+        let sock = UdpSocket::bind(
+            address
+                .parse::<SocketAddr>()
+                .expect("Failed to bind to address."),
+        )
+        .await
+        .unwrap();
+        sockets.push(Arc::new(sock));
+    }
     // The socket of the server for sending packets (ticking client thread).
-    let send_sock = Arc::new(sock);
-    // The socket for the recieving thread.
-    let socket = send_sock.clone();
-    // The socket for the internal server sending thread.
-    let send_sock_internal = send_sock.clone();
+    let send_sock = sockets[0].clone();
+    // The sockets by the address they're bound to, clients are answered from the one they
+    // sent to.
+    let send_socks = sockets
+        .iter()
+        .filter_map(|sock| Some((sock.local_addr().ok()?, sock.clone())))
+        .collect::<HashMap<_, _>>();
     // The time we're going to say raknet actually started.
     let start_time = server.start_time.clone();
     // The id of the server
@@ -177,34 +735,19 @@ pub async fn start<'a>(
     let version = server.version.clone();
     // The channels being used to send packets to the client (externally).
     let (send, mut recv) = tokio::sync::mpsc::channel::<(String, Vec<u8>, bool)>(2048);
-    // The internal channels being used to dispatch packets with `connection.send`.
-    let (im_send, mut im_recv) = tokio::sync::mpsc::channel::<(String, Vec<u8>)>(2048);
 
     let tasks = async move {
-        // This task is solely responsible for internal immediate sending.
-        // Nothing else, this is not used externally, nor should it be.
-        tokio::spawn(async move {
-            loop {
-                if let Some(data) = im_recv.recv().await {
-                    if let Ok(_) = send_sock_internal
-                        .send_to(&data.1, from_address_token(data.0))
-                        .await
-                    {
-                        continue;
-                    } else {
-                        rak_debug!("Failed to send immediate packet.");
-                    }
-                }
-            }
-        });
-
         tokio::spawn(async move {
             loop {
                 if let Some((address, buf, instant)) = recv.recv().await {
-                    let mut clients = task_server.connections.write().unwrap();
-                    if clients.contains_key(&address) {
-                        let client = clients.get_mut(&address).unwrap();
-                        client.send_stream(
+                    let client = task_server
+                        .connections
+                        .read()
+                        .unwrap()
+                        .get(&address)
+                        .cloned();
+                    if let Some(client) = client {
+                        client.lock().unwrap().send_stream(
                             buf,
                             if instant {
                                 SendPriority::Immediate
@@ -212,71 +755,286 @@ pub async fn start<'a>(
                                 SendPriority::Normal
                             },
                         );
-                        drop(client);
-                        drop(clients);
                     } else {
                         println!("ERR: Client not found: {}", address);
-                        drop(clients);
                     }
                 }
             }
         });
 
-        tokio::spawn(async move {
-            let internal_send = Arc::new(im_send);
-            loop {
-                if let Err(_) = socket.readable().await {
-                    continue;
-                };
+        // offline packets are limited per ip, no matter which address they're sent to.
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(
+            server.rate_limit,
+            server.rate_limit_burst,
+        )));
 
-                let mut buf = [0; 2048];
-                if let Ok((len, addr)) = socket.recv_from(&mut buf).await {
-                    let data = &buf[..len];
-                    let address_token = to_address_token(addr);
+        for socket in sockets {
+            // The internal channels being used to dispatch packets with `connection.send`,
+            // these are sent from the socket the client connected through.
+            let (im_send, mut im_recv) = tokio::sync::mpsc::channel::<(String, Vec<u8>)>(2048);
+            // The socket for the internal server sending thread.
+            let send_sock_internal = socket.clone();
+            let server = server.clone();
+            let limiter = limiter.clone();
+            let version = version.clone();
+            let counters = server.counters.clone();
 
-                    // // rak_debug!("[RakNet] [{}] Received packet: Packet(ID={:#04x})", addr, &data[0]);
+            // This task is solely responsible for internal immediate sending.
+            // Nothing else, this is not used externally, nor should it be.
+            tokio::spawn(async move {
+                loop {
+                    if let Some(data) = im_recv.recv().await {
+                        let address = match from_address_token(data.0.clone()) {
+                            Ok(address) => address,
+                            Err(_) => {
+                                rak_debug!(
+                                    "[RakNet] [{}] Skipping malformed address token.",
+                                    data.0
+                                );
+                                continue;
+                            }
+                        };
 
-                    if let Ok(mut clients) = server.connections.write() {
-                        if let Some(c) = clients.get_mut(&address_token) {
-                            c.recv(&data.to_vec());
+                        let result = send_sock_internal.send_to(&data.1, address).await;
+                        counters.record_send(&data.0, result, data.1.len());
+                    }
+                }
+            });
+
+            tokio::spawn(async move {
+                use binary_utils::Streamable;
+
+                let internal_send = Arc::new(im_send);
+                let server_address = socket.local_addr().ok();
+                // no client may send a datagram larger than the mtu we allow.
+                let mut buf = vec![0; (server.max_mtu as usize).max(MIN_RECV_BUFFER_SIZE)];
+                loop {
+                    if socket.readable().await.is_err() {
+                        continue;
+                    };
+
+                    if let Ok((len, addr)) = socket.recv_from(&mut buf).await {
+                        let data = &buf[..len];
+                        let address_token = to_address_token(addr);
+
+                        if len == buf.len() {
+                            // anything past the buffer is silently discarded by the socket.
+                            rak_debug!(
+                                "[RakNet] [{}] Received a datagram of {} bytes, it may have been truncated.",
+                                address_token,
+                                len
+                            );
+                        }
+
+                        // // rak_debug!("[RakNet] [{}] Received packet: Packet(ID={:#04x})", addr, &data[0]);
+
+                        // offline packets are limited per ip, these are all packets that aren't
+                        // a frame packet or an ack from a connected client.
+                        let offline = data.first().is_some_and(|id| *id < 0x80);
+                        if offline
+                            && server.rate_limit > 0
+                            && !limiter.lock().unwrap().allow(addr.ip())
+                        {
+                            server.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+
+                        if server.is_banned(&address_token) {
+                            // banned clients are only told that they're banned when they
+                            // try to connect, everything else is dropped.
+                            if is_open_connect_request(data) {
+                                let banned = ConnectionBanned {
+                                    magic: Magic::new(),
+                                    server_id,
+                                };
+                                if let Ok(buf) = Packet::from(banned).parse() {
+                                    if socket.send_to(&buf, addr).await.is_err() {
+                                        rak_warn!(
+                                            "[RakNet] [{}] Failed to send ConnectionBanned.",
+                                            address_token
+                                        );
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+
+                        // whether or not the client tried to connect while the server is full.
+                        let mut full = false;
+
+                        let (client, at_capacity) = {
+                            let clients = server.connections.read().unwrap();
+                            (
+                                clients.get(&address_token).cloned(),
+                                clients.len() >= server.max_connections,
+                            )
+                        };
+
+                        if at_capacity && is_ping(data, UnconnectedPingOpenConnections::id()) {
+                            // this ping is only answered when there's room for another
+                            // client, so a full server isn't advertised.
+                        } else if let Some(client) = client {
+                            // only this connection is locked, the others can still be ticked.
+                            client.lock().unwrap().recv(&data.to_vec());
+                        } else if is_ping(data, UnconnectedPing::id())
+                            || is_ping(data, UnconnectedPingOpenConnections::id())
+                        {
+                            // we don't keep track of clients that only ping us.
+                            if let Some(pong) = answer_ping(&server, addr, data) {
+                                if socket.send_to(&pong, addr).await.is_err() {
+                                    rak_warn!(
+                                        "[RakNet] [{}] Failed to send UnconnectedPong.",
+                                        address_token
+                                    );
+                                }
+                            }
+                        } else if at_capacity {
+                            // we're full, we won't keep track of anyone else
+                            // until a connection is removed.
+                            full = is_open_connect_request(data);
                         } else {
                             // add the client!
                             // we need to add cooldown here eventually.
-                            if !clients.contains_key(&address_token) {
-                                let mut c = Connection::new(
-                                    address_token.clone(),
-                                    internal_send.clone(),
-                                    start_time,
-                                    server_id,
-                                    port.to_string(),
-                                    version.clone(),
-                                );
-                                c.recv(&data.to_vec());
-                                clients.insert(address_token, c);
-                            } else {
-                                // throw an error, this should never happen.
+                            let mut c = Connection::new(
+                                address_token.clone(),
+                                internal_send.clone(),
+                                start_time,
+                                server_id,
+                                server.motd.clone(),
+                                server.motd_generator.clone(),
+                                version.clone(),
+                            );
+                            c.max_mtu = server.max_mtu;
+                            c.allowed_versions = server.allowed_versions.clone();
+                            c.allow_reconnect = server.allow_reconnect;
+                            c.user_packet_ids = server.user_packet_ids.clone();
+                            c.buffer_early_packets = server.buffer_early_packets;
+                            c.timeout = server.timeout;
+                            c.ping_interval = server.ping_interval;
+                            c.max_missed_pings = server.max_missed_pings;
+                            c.latency_threshold = server.latency_threshold;
+                            c.fragment_timeout = server.fragment_timeout;
+                            c.max_fragments = server.max_fragments;
+                            c.max_fragmented_size = server.max_fragmented_size;
+                            c.max_fragmented_frames = server.max_fragmented_frames;
+                            c.max_ordered_packets = server.max_ordered_packets;
+                            c.server_address = server_address;
+                            c.validate_handshake_address = server.validate_handshake_address;
+                            c.handshake_deadline =
+                                Some(SystemTime::now() + server.handshake_timeout);
+                            if server.use_cookies {
+                                c.cookies = Some(server.cookies.clone());
+                            }
+                            c.recv(&data.to_vec());
+
+                            // the connection may have been rejected while handling
+                            // the packet, in which case we don't keep track of it.
+                            // When using cookies, we only keep track of clients that
+                            // echoed theirs back and are now connecting.
+                            let verified =
+                                !server.use_cookies || c.state == ConnectionState::Connecting;
+                            if !c.is_disconnected() && verified {
+                                server
+                                    .connections
+                                    .write()
+                                    .unwrap()
+                                    .insert(address_token.clone(), Arc::new(Mutex::new(c)));
+                            }
+                        }
+
+                        if full {
+                            let reply = NoFreeIncomingConnections {
+                                magic: Magic::new(),
+                                server_id,
+                            };
+                            if let Ok(buf) = Packet::from(reply).parse() {
+                                if socket.send_to(&buf, addr).await.is_err() {
+                                    rak_warn!(
+                                        "[RakNet] [{}] Failed to send NoFreeIncomingConnections.",
+                                        address_token
+                                    );
+                                }
                             }
                         }
+                    } else {
+                        // log error in future!
+                        // rak_debug!("[RakNet] Unknown error decoding packet!");
+                        continue;
                     }
-                } else {
-                    // log error in future!
-                    // rak_debug!("[RakNet] Unknown error decoding packet!");
-                    continue;
                 }
-            }
-        });
+            });
+        }
 
         while !&send_server.stop {
-            if let Err(_) = send_sock.writable().await {
+            if send_sock.writable().await.is_err() {
                 continue;
             };
 
             // sleep an entire tick
-            sleep(Duration::from_millis(50)).await;
+            sleep(send_server.tick_interval).await;
+
+            // the events of clients we don't keep track of only have the server to update.
+            let offline_events = send_server
+                .offline_events
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<_>>();
+            for event in offline_events {
+                match send_channel.send(event) {
+                    Some(RakResult::Motd(v)) => *send_server.motd.write().unwrap() = v,
+                    Some(RakResult::Error(v)) => panic!("{}", v),
+                    _ => {}
+                }
+            }
+
+            // the streams queued through a handle go out on this tick.
+            let outbox = send_server
+                .outbox
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<_>>();
+            for (address, stream, reliability) in outbox {
+                send_server.send_stream(&address, stream, reliability);
+            }
+
+            // only the handles are copied, the table itself is only locked to remove
+            // connections, so receiving isn't blocked while we tick.
+            let clients = send_server
+                .connections
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(addr, client)| (addr.clone(), client.clone()))
+                .collect::<Vec<_>>();
+
+            if send_server.track_player_count {
+                send_server.motd.write().unwrap().player_count = clients
+                    .iter()
+                    .filter(|(_, c)| c.lock().unwrap().state.is_connected())
+                    .count()
+                    as u16;
+            }
+
+            // the datagrams are only sent once every client is ticked, so no connection
+            // is kept locked while we wait on the socket.
+            let mut outgoing = Vec::new();
+
+            for (addr, handle) in clients.iter() {
+                let mut client = handle.lock().unwrap();
+
+                if client.is_handshake_expired() {
+                    // the client never finished connecting, so there's no disconnect to report.
+                    drop(client);
+                    send_server.connections.write().unwrap().remove(addr);
+                    send_server
+                        .counters
+                        .handshake_timeouts
+                        .fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
 
-            let mut clients = send_server.connections.write().unwrap();
-            for (addr, _) in clients.clone().iter() {
-                let client = clients.get_mut(addr).expect("Could not get connection");
                 client.tick();
 
                 let dispatch = client.event_dispatch.clone();
@@ -288,7 +1046,7 @@ pub async fn start<'a>(
                     if let Some(result) = send_channel.send(event.clone()) {
                         match result {
                             RakResult::Motd(v) => {
-                                client.motd = v;
+                                *client.motd.write().unwrap() = v;
                             }
                             RakResult::Error(v) => {
                                 // Calling error forces an error to raise.
@@ -306,7 +1064,8 @@ pub async fn start<'a>(
                 // This is after the packet sending because we may want to send packets if
                 // the disconnect notification is server sided.
                 if client.is_disconnected() {
-                    clients.remove(addr);
+                    drop(client);
+                    send_server.connections.write().unwrap().remove(addr);
                     continue;
                 }
 
@@ -315,41 +1074,47 @@ pub async fn start<'a>(
                 }
 
                 let packets = client.queue.flush();
+                let connected = client.state.is_connected();
+                // the client is answered from the address it sent to.
+                let socket = client
+                    .server_address
+                    .and_then(|address| send_socks.get(&address))
+                    .unwrap_or(&send_sock);
+                let address = match from_address_token(addr.clone()) {
+                    Ok(address) => address,
+                    Err(_) => {
+                        rak_debug!("[RakNet] [{}] Skipping malformed address token.", addr);
+                        continue;
+                    }
+                };
+                outgoing.push((addr, address, socket, connected, packets));
+            }
 
+            for (addr, address, socket, connected, packets) in outgoing {
                 for pk in packets.into_iter() {
-                    match send_sock
-                        .send_to(&pk[..], &from_address_token(addr.clone()))
-                        .await
-                    {
-                        // Add proper handling!
-                        Err(e) => rak_debug!("[RakNet] [{}] Error sending packet: {}", addr, e),
-                        Ok(_) => {
-                            if client.state.is_connected() {
-                                if cfg!(any(test, feature = "dbg-verbose")) {
-                                    rak_debug!(
-                                        "[ONLINE PACKET] [{}] Sent packet: {:?}\n",
-                                        addr,
-                                        &pk
-                                    );
-                                } else {
-                                    rak_debug!(
-                                        "[ONLINE PACKET] [{}] Sent packet: {}",
-                                        addr,
-                                        *pk.get(0).unwrap_or(&0)
-                                    );
-                                }
-                            } else {
-                                rak_debug!(
-                                    "[OFFLINE] [{}] Sent packet: {}",
-                                    addr,
-                                    *pk.get(0).unwrap_or(&0)
-                                );
-                            }
+                    let result = socket.send_to(&pk[..], &address).await;
+                    if !send_server.counters.record_send(addr, result, pk.len()) {
+                        continue;
+                    }
+                    if connected {
+                        if cfg!(any(test, feature = "dbg-verbose")) {
+                            rak_debug!("[ONLINE PACKET] [{}] Sent packet: {:?}\n", addr, &pk);
+                        } else {
+                            rak_debug!(
+                                "[ONLINE PACKET] [{}] Sent packet: {}",
+                                addr,
+                                *pk.first().unwrap_or(&0)
+                            );
                         }
+                    } else {
+                        rak_debug!(
+                            "[OFFLINE] [{}] Sent packet: {}",
+                            addr,
+                            *pk.first().unwrap_or(&0)
+                        );
                     }
                 }
             }
-            drop(clients);
         }
     };
 
@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+
+use rakrs::protocol::util::{read_address, write_address};
+
+#[test]
+fn ipv4_round_trip() {
+    let address: SocketAddr = "192.168.0.12:19132".parse().unwrap();
+    let buffer = write_address(&address).unwrap();
+    // version, 4 octets and the port
+    assert_eq!(buffer.len(), 7);
+    assert_eq!(buffer[0], 4);
+    assert_eq!(buffer[1], !192);
+
+    let mut position = 0;
+    assert_eq!(read_address(&buffer, &mut position).unwrap(), address);
+    assert_eq!(position, buffer.len());
+}
+
+#[test]
+fn ipv6_round_trip() {
+    let address: SocketAddr = "[2001:db8::ff00:42:8329]:19133".parse().unwrap();
+    let buffer = write_address(&address).unwrap();
+    // version, family, port, flow info, 16 octets and the scope id
+    assert_eq!(buffer.len(), 29);
+    assert_eq!(buffer[0], 6);
+
+    let mut position = 0;
+    assert_eq!(read_address(&buffer, &mut position).unwrap(), address);
+    assert_eq!(position, buffer.len());
+}
@@ -0,0 +1,2379 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use binary_utils::Streamable;
+use rakrs::connection::state::ConnectionState;
+use rakrs::connection::{Connection, SendCommand, SendError};
+use rakrs::protocol::mcpe::motd::{Motd, MotdGenerator};
+use rakrs::protocol::offline::{
+    OfflinePacket, OpenConnectRequest, SessionInfoRequest, UnconnectedPing,
+};
+use rakrs::protocol::online::{NewConnection, OnlinePacket, SYSTEM_ADDRESS_COUNT};
+use rakrs::protocol::util::Magic;
+use rakrs::protocol::Packet;
+use rakrs::{
+    clear_logger, set_logger, LogLevel, RakEvent, RakNetVersion, Reliability, UDP_HEADER_SIZE,
+};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Creates a connection for `127.0.0.1:19133` along with the receiver
+/// for every packet it sends immediately.
+pub fn connection() -> (Connection, Receiver<SendCommand>) {
+    let (send, recv) = channel::<SendCommand>(2048);
+    let connection = Connection::new(
+        "127.0.0.1:19133".into(),
+        Arc::new(send),
+        SystemTime::now(),
+        1234,
+        Arc::new(RwLock::new(Motd::new(1234, "19132"))),
+        Arc::new(RwLock::new(None)),
+        RakNetVersion::V10,
+    );
+    (connection, recv)
+}
+
+/// Creates a connection like `connection`, for a client that already completed the handshake.
+pub fn connected() -> (Connection, Receiver<SendCommand>) {
+    let (mut connection, recv) = connection();
+    connection.state = ConnectionState::Connected;
+    (connection, recv)
+}
+
+#[test]
+fn malformed_frames_are_dropped() {
+    let (mut connection, _recv) = connection();
+    // an empty datagram
+    connection.recv(&vec![]);
+    // a frame packet without a sequence
+    connection.recv(&vec![0x84]);
+    // a frame claiming a 16 byte body with only 2 bytes left
+    connection.recv(&vec![0x84, 0, 0, 0, 0x00, 0x00, 0x80, 1, 2]);
+    // a truncated ack
+    connection.recv(&vec![0xc0, 0x00]);
+}
+
+/// Reads the next offline packet the connection sent.
+pub fn next_offline(recv: &mut Receiver<SendCommand>) -> OfflinePacket {
+    let (_, buffer) = recv.try_recv().expect("No packet was sent");
+    Packet::compose(&buffer, &mut 0).unwrap().get_offline()
+}
+
+fn open_connect_request(mtu_size: u16) -> Vec<u8> {
+    open_connect_request_with(10, mtu_size)
+}
+
+fn open_connect_request_with(protocol: u8, mtu_size: u16) -> Vec<u8> {
+    Packet::from(OpenConnectRequest {
+        magic: Magic::new(),
+        protocol,
+        mtu_size,
+    })
+    .parse()
+    .unwrap()
+}
+
+fn session_info_request(mtu_size: u16) -> Vec<u8> {
+    Packet::from(SessionInfoRequest {
+        magic: Magic::new(),
+        cookie: None,
+        address: "127.0.0.1:19132".parse().unwrap(),
+        mtu_size,
+        client_id: 1,
+    })
+    .parse()
+    .unwrap()
+}
+
+#[test]
+fn mtu_is_negotiated_from_padding() {
+    for (requested, negotiated) in [(1400, 1400), (1200, 1200), (1492, 1400), (400, 576)] {
+        let (mut connection, mut recv) = connection();
+        connection.recv(&open_connect_request(requested));
+        assert_eq!(connection.mtu, negotiated);
+
+        match next_offline(&mut recv) {
+            OfflinePacket::OpenConnectReply(reply) => assert_eq!(reply.mtu_size, negotiated),
+            packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+        }
+    }
+}
+
+#[test]
+fn open_connect_reply_is_padded_to_mtu() {
+    for mtu in [576, 1200, 1400] {
+        let (mut connection, mut recv) = connection();
+        connection.recv(&open_connect_request(mtu));
+
+        // the reply is as large as the mtu, without the udp and ip headers.
+        let (_, reply) = recv.try_recv().unwrap();
+        assert_eq!(reply.len(), mtu as usize - 28);
+        match Packet::compose(&reply, &mut 0).unwrap().get_offline() {
+            OfflinePacket::OpenConnectReply(reply) => assert_eq!(reply.mtu_size, mtu),
+            packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+        }
+    }
+}
+
+#[test]
+fn session_info_reply_clamps_inflated_mtu() {
+    let (mut connection, mut recv) = connection();
+    connection.max_mtu = 1200;
+    connection.recv(&open_connect_request(1400));
+    next_offline(&mut recv);
+
+    connection.recv(&session_info_request(9000));
+    assert_eq!(connection.mtu, 1200);
+
+    match next_offline(&mut recv) {
+        OfflinePacket::SessionInfoReply(reply) => assert_eq!(reply.mtu_size, 1200),
+        packet => panic!("Expected SessionInfoReply, got {:?}", packet),
+    }
+}
+
+#[test]
+fn session_info_request_can_lower_mtu() {
+    let (mut connection, mut recv) = connection();
+    connection.recv(&open_connect_request(1400));
+    next_offline(&mut recv);
+
+    // the client settled on a smaller mtu than it first probed with.
+    connection.recv(&session_info_request(1200));
+    assert_eq!(connection.mtu, 1200);
+    assert_eq!(connection.max_frame_size(), 1140);
+
+    match next_offline(&mut recv) {
+        OfflinePacket::SessionInfoReply(reply) => assert_eq!(reply.mtu_size, 1200),
+        packet => panic!("Expected SessionInfoReply, got {:?}", packet),
+    }
+}
+
+/// Collects the bodies of every game packet the connection dispatched.
+pub fn game_packets(connection: &Connection) -> Vec<Vec<u8>> {
+    connection
+        .event_dispatch
+        .iter()
+        .filter_map(|event| match event {
+            RakEvent::GamePacket(_, body) => Some(body.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Encodes a frame packet with the given datagram sequence.
+pub fn frame_packet(sequence: u32, frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut packet = vec![0x84];
+    packet.extend_from_slice(&sequence.to_le_bytes()[..3]);
+    for frame in frames {
+        packet.extend_from_slice(frame);
+    }
+    packet
+}
+
+/// Encodes a reliable ordered frame on the given channel.
+pub fn ordered_frame(reliable_index: u32, order_index: u32, channel: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x60];
+    frame.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+    frame.extend_from_slice(&reliable_index.to_le_bytes()[..3]);
+    frame.extend_from_slice(&order_index.to_le_bytes()[..3]);
+    frame.push(channel);
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Encodes an unreliable sequenced frame on the given channel.
+pub fn sequenced_frame(sequence_index: u32, order_index: u32, channel: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x20];
+    frame.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+    frame.extend_from_slice(&sequence_index.to_le_bytes()[..3]);
+    frame.extend_from_slice(&order_index.to_le_bytes()[..3]);
+    frame.push(channel);
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Encodes an unreliable frame.
+pub fn unreliable_frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x00];
+    frame.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Encodes a single unreliable fragment of a split frame.
+pub fn fragment_frame(id: u16, index: u32, size: u32, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x10];
+    frame.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+    frame.extend_from_slice(&size.to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&index.to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Drains every datagram the connection sent immediately.
+pub fn sent(recv: &mut Receiver<SendCommand>) -> Vec<Vec<u8>> {
+    let mut sent = Vec::new();
+    while let Ok((_, buffer)) = recv.try_recv() {
+        sent.push(buffer);
+    }
+    sent
+}
+
+/// Decodes the sequences an ACK or NACK covers, ranges are expanded.
+pub fn ack_sequences(buffer: &[u8]) -> Vec<u32> {
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let count = u16::from_be_bytes([buffer[1], buffer[2]]);
+    let mut position = 3;
+    let mut sequences = Vec::new();
+    for _ in 0..count {
+        if buffer[position] == 1 {
+            sequences.push(u24(&buffer[position + 1..]));
+            position += 4;
+        } else {
+            sequences.extend(u24(&buffer[position + 1..])..=u24(&buffer[position + 4..]));
+            position += 7;
+        }
+    }
+    sequences.sort();
+    sequences
+}
+
+#[test]
+fn frame_packet_with_two_frames() {
+    let (mut connection, _recv) = connected();
+    // frame packet with sequence 0, followed by two unreliable frames
+    // of 24 and 8 bits respectively.
+    let mut packet = vec![0x84, 0, 0, 0];
+    packet.extend_from_slice(&[0x00, 0x00, 0x18, 0xfe, 1, 2]);
+    packet.extend_from_slice(&[0x00, 0x00, 0x08, 0xfe]);
+    connection.recv(&packet);
+
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 1, 2], vec![0xfe]]
+    );
+}
+
+#[test]
+fn incompatible_protocol_is_rejected() {
+    let (mut connection, mut recv) = connection();
+    connection.recv(&open_connect_request_with(5, 1400));
+
+    match next_offline(&mut recv) {
+        OfflinePacket::IncompatibleProtocolVersion(pk) => {
+            assert_eq!(pk.protocol, 10);
+            assert_eq!(pk.server_id, 1234);
+        }
+        packet => panic!("Expected IncompatibleProtocolVersion, got {:?}", packet),
+    }
+    // no reply should follow, and the server won't keep the connection.
+    assert!(recv.try_recv().is_err());
+    assert!(connection.is_disconnected());
+}
+
+#[test]
+fn allowed_protocol_is_accepted() {
+    let (mut connection, mut recv) = connection();
+    connection.allowed_versions.push(RakNetVersion::V11);
+    connection.recv(&open_connect_request_with(11, 1400));
+
+    match next_offline(&mut recv) {
+        OfflinePacket::OpenConnectReply(_) => {}
+        packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+    }
+    assert!(!connection.is_disconnected());
+}
+
+#[test]
+fn frame_packet_with_three_reliabilities() {
+    let (mut connection, _recv) = connected();
+    let mut packet = vec![0x84, 0, 0, 0];
+    // unreliable frame
+    packet.extend_from_slice(&[0x00, 0x00, 0x10, 0xfe, 1]);
+    // reliable frame, reliable index 0
+    packet.extend_from_slice(&[0x40, 0x00, 0x10, 0, 0, 0, 0xfe, 2]);
+    // reliable ordered frame, reliable index 1, order index 0 on channel 0
+    packet.extend_from_slice(&[0x60, 0x00, 0x10, 1, 0, 0, 0, 0, 0, 0, 0xfe, 3]);
+    connection.recv(&packet);
+
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 1], vec![0xfe, 2], vec![0xfe, 3]]
+    );
+}
+
+#[test]
+fn connected_client_is_already_connected() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&session_info_request(1400));
+
+    match next_offline(&mut recv) {
+        OfflinePacket::AlreadyConnected(pk) => assert_eq!(pk.server_id, 1234),
+        packet => panic!("Expected AlreadyConnected, got {:?}", packet),
+    }
+    assert_eq!(connection.state, ConnectionState::Connected);
+}
+
+#[test]
+fn connected_client_may_reconnect() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.allow_reconnect = true;
+    connection.recv(&session_info_request(1400));
+
+    match next_offline(&mut recv) {
+        OfflinePacket::SessionInfoReply(_) => {}
+        packet => panic!("Expected SessionInfoReply, got {:?}", packet),
+    }
+    assert_eq!(connection.state, ConnectionState::Connecting);
+}
+
+#[test]
+fn every_reliability_is_decoded() {
+    // the reliability is stored in the top 3 bits of the flags.
+    for reliability in 0u8..8 {
+        let reliable = [2, 3, 4, 6, 7].contains(&reliability);
+        let sequenced = [1, 4].contains(&reliability);
+        let ordered = [1, 3, 4, 7].contains(&reliability);
+
+        let mut frame = vec![reliability << 5, 0x00, 0x10];
+        if reliable {
+            frame.extend_from_slice(&[0, 0, 0]);
+        }
+        if sequenced {
+            frame.extend_from_slice(&[0, 0, 0]);
+        }
+        if ordered {
+            frame.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        frame.extend_from_slice(&[0xfe, reliability]);
+
+        let (mut connection, _recv) = connection();
+        connection.state = ConnectionState::Connected;
+        connection.recv(&frame_packet(0, &[frame]));
+        assert_eq!(
+            game_packets(&connection),
+            vec![vec![0xfe, reliability]],
+            "reliability {}",
+            reliability
+        );
+    }
+}
+
+#[test]
+fn every_reliability_round_trips() {
+    let reliabilities = [
+        Reliability::Unreliable,
+        Reliability::UnreliableSeq,
+        Reliability::Reliable,
+        Reliability::ReliableOrd,
+        Reliability::ReliableSeq,
+        Reliability::UnreliableAck,
+        Reliability::ReliableAck,
+        Reliability::ReliableOrdAck,
+    ];
+    for (index, reliability) in reliabilities.into_iter().enumerate() {
+        assert_eq!(Reliability::from_flags(reliability.to_flags()), reliability);
+
+        let (mut sender, mut recv) = connection();
+        sender
+            .send_reliable(vec![0xfe, index as u8], reliability, None)
+            .unwrap();
+        sender.tick();
+        let datagram = sent(&mut recv).remove(0);
+        assert_eq!(datagram[4] >> 5, index as u8);
+
+        let (mut receiver, _recv) = connection();
+        receiver.state = ConnectionState::Connected;
+        receiver.recv(&datagram);
+        assert_eq!(
+            game_packets(&receiver),
+            vec![vec![0xfe, index as u8]],
+            "{:?}",
+            reliability
+        );
+    }
+}
+
+#[test]
+fn ordered_frames_are_released_in_order() {
+    let (mut connection, _recv) = connected();
+    connection.recv(&frame_packet(0, &[ordered_frame(0, 2, 0, &[0xfe, 2])]));
+    connection.recv(&frame_packet(1, &[ordered_frame(1, 1, 0, &[0xfe, 1])]));
+    assert!(game_packets(&connection).is_empty());
+
+    connection.recv(&frame_packet(2, &[ordered_frame(2, 0, 0, &[0xfe, 0])]));
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 1], vec![0xfe, 2]]
+    );
+
+    // a duplicate of an index that was already released is dropped.
+    connection.recv(&frame_packet(3, &[ordered_frame(3, 1, 0, &[0xfe, 1])]));
+    assert_eq!(game_packets(&connection).len(), 3);
+}
+
+#[test]
+fn resent_reliable_frames_are_handled_once() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    let reliable = |index: u32, body: &[u8]| {
+        let mut frame = vec![0x40];
+        frame.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+        frame.extend_from_slice(&index.to_le_bytes()[..3]);
+        frame.extend_from_slice(body);
+        frame
+    };
+
+    // the first frame arrives late, then both are resent.
+    connection.recv(&frame_packet(0, &[reliable(1, &[0xfe, 1])]));
+    connection.recv(&frame_packet(1, &[reliable(0, &[0xfe, 0])]));
+    connection.recv(&frame_packet(2, &[reliable(1, &[0xfe, 1])]));
+    connection.recv(&frame_packet(3, &[reliable(0, &[0xfe, 0])]));
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 1], vec![0xfe, 0]]
+    );
+
+    // every datagram is still acknowledged.
+    connection.tick();
+    let acks = sent(&mut recv)
+        .into_iter()
+        .find(|datagram| datagram[0] == 0xc0)
+        .expect("No ACK was sent");
+    assert_eq!(ack_sequences(&acks), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn reliable_frames_far_ahead_are_dropped() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    let reliable = |index: u32| {
+        let mut frame = vec![0x40, 0x00, 0x10];
+        frame.extend_from_slice(&index.to_le_bytes()[..3]);
+        frame.extend_from_slice(&[0xfe, index as u8]);
+        frame
+    };
+
+    // we won't remember an unbounded amount of indexes.
+    connection.recv(&frame_packet(0, &[reliable(0x10000)]));
+    connection.recv(&frame_packet(1, &[reliable(0xffff)]));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 0xff]]);
+}
+
+#[test]
+fn ordered_frames_are_delivered_in_order() {
+    let (mut connection, _recv) = connected();
+    for (sequence, index) in [2, 0, 1].into_iter().enumerate() {
+        connection.recv(&frame_packet(
+            sequence as u32,
+            &[ordered_frame(
+                sequence as u32,
+                index,
+                0,
+                &[0xfe, index as u8],
+            )],
+        ));
+    }
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 1], vec![0xfe, 2]]
+    );
+}
+
+/// Whether or not the connection dispatched a `ProtocolViolation` event.
+fn violated_protocol(connection: &Connection) -> bool {
+    connection
+        .event_dispatch
+        .iter()
+        .any(|event| matches!(event, RakEvent::ProtocolViolation(..)))
+}
+
+#[test]
+fn ordered_channels_hold_back_a_limited_amount() {
+    let (mut connection, _recv) = connected();
+    connection.max_ordered_packets = 2;
+    for index in 1..3 {
+        connection.recv(&frame_packet(
+            index,
+            &[ordered_frame(index, index, 0, &[0xfe, index as u8])],
+        ));
+    }
+    assert!(!violated_protocol(&connection));
+
+    connection.recv(&frame_packet(3, &[ordered_frame(3, 3, 0, &[0xfe, 3])]));
+    assert!(violated_protocol(&connection));
+
+    // the packet everything is waiting on is still accepted.
+    connection.recv(&frame_packet(4, &[ordered_frame(0, 0, 0, &[0xfe, 0])]));
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 1], vec![0xfe, 2]]
+    );
+}
+
+#[test]
+fn frames_on_invalid_channels_are_dropped() {
+    let (mut connection, _recv) = connection();
+    connection.recv(&frame_packet(0, &[ordered_frame(0, 0, 32, &[0xfe, 0])]));
+    connection.recv(&frame_packet(1, &[sequenced_frame(0, 0, 200, &[0xfe, 1])]));
+    assert!(game_packets(&connection).is_empty());
+    assert!(violated_protocol(&connection));
+}
+
+#[test]
+fn invalid_frames_do_not_drop_the_rest_of_the_datagram() {
+    let (mut connection, _recv) = connected();
+    connection.recv(&frame_packet(
+        0,
+        &[
+            ordered_frame(0, 0, 32, &[0xfe, 0]),
+            unreliable_frame(&[0xfe, 1]),
+        ],
+    ));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 1]]);
+    assert!(violated_protocol(&connection));
+}
+
+#[test]
+fn ordered_channels_are_independent() {
+    let (mut connection, _recv) = connected();
+    connection.recv(&frame_packet(0, &[ordered_frame(0, 1, 0, &[0xfe, 1])]));
+    connection.recv(&frame_packet(1, &[ordered_frame(1, 0, 1, &[0xfe, 0])]));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 0]]);
+}
+
+#[test]
+fn stale_sequenced_frames_are_dropped() {
+    let (mut connection, _recv) = connected();
+    for (sequence, index) in [0, 2, 1].into_iter().enumerate() {
+        connection.recv(&frame_packet(
+            sequence as u32,
+            &[sequenced_frame(index, 0, 0, &[0xfe, index as u8])],
+        ));
+    }
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 2]]
+    );
+}
+
+#[test]
+fn ordered_frames_restart_the_sequence() {
+    let (mut connection, _recv) = connected();
+    connection.recv(&frame_packet(0, &[sequenced_frame(0, 0, 0, &[0xfe, 0])]));
+    connection.recv(&frame_packet(1, &[sequenced_frame(1, 0, 0, &[0xfe, 1])]));
+    connection.recv(&frame_packet(2, &[ordered_frame(0, 0, 0, &[0xfe, 2])]));
+    // the sequence starts over for the next order index, but stale packets are still dropped.
+    connection.recv(&frame_packet(3, &[sequenced_frame(0, 1, 0, &[0xfe, 3])]));
+    connection.recv(&frame_packet(4, &[sequenced_frame(0, 1, 0, &[0xfe, 4])]));
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 1], vec![0xfe, 2], vec![0xfe, 3]]
+    );
+}
+
+#[test]
+fn sequenced_frames_wait_for_the_ordered_stream() {
+    let (mut connection, _recv) = connected();
+    connection.recv(&frame_packet(0, &[sequenced_frame(0, 0, 0, &[0xfe, 0])]));
+    // this was sent after the first ordered packet, which hasn't arrived yet.
+    connection.recv(&frame_packet(1, &[sequenced_frame(0, 1, 0, &[0xfe, 1])]));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 0]]);
+
+    connection.recv(&frame_packet(2, &[ordered_frame(0, 0, 0, &[0xfe, 2])]));
+    // this was sent before the ordered packet, it's too late now.
+    connection.recv(&frame_packet(3, &[sequenced_frame(5, 0, 0, &[0xfe, 3])]));
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 2], vec![0xfe, 1]]
+    );
+}
+
+#[test]
+fn queued_packets_are_reliably_ordered() {
+    let (mut connection, mut recv) = connection();
+    connection.send(vec![0xfe, 0], false).unwrap();
+    connection.send(vec![0xfe, 1], false).unwrap();
+    connection.tick();
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let datagram = sent(&mut recv).remove(0);
+    // both frames are reliable ordered, each with their own reliable and order index.
+    let (first, second) = (&datagram[4..], &datagram[4 + 10 + 2..]);
+    for (index, frame) in [first, second].into_iter().enumerate() {
+        assert_eq!(frame[0], 0x60);
+        assert_eq!(u24(&frame[3..]), index as u32);
+        assert_eq!(u24(&frame[6..]), index as u32);
+        assert_eq!(frame[9], 0);
+        assert_eq!(frame[10..12], [0xfe, index as u8]);
+    }
+}
+
+#[test]
+fn reliable_packets_are_assigned_a_reliable_index() {
+    let (mut connection, mut recv) = connection();
+    connection
+        .send_reliable(vec![0xfe, 0], Reliability::Reliable, None)
+        .unwrap();
+    connection
+        .send_reliable(vec![0xfe, 1], Reliability::Reliable, None)
+        .unwrap();
+    connection.tick();
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let datagram = sent(&mut recv).remove(0);
+    // reliable frames only carry a reliable index.
+    let (first, second) = (&datagram[4..], &datagram[4 + 6 + 2..]);
+    for (index, frame) in [first, second].into_iter().enumerate() {
+        assert_eq!(frame[0], 0x40);
+        assert_eq!(u24(&frame[3..]), index as u32);
+        assert_eq!(frame[6..8], [0xfe, index as u8]);
+    }
+}
+
+#[test]
+fn packets_are_sent_with_their_reliability_and_channel() {
+    let (mut connection, mut recv) = connection();
+    connection
+        .send_reliable(vec![0xfe, 0], Reliability::Unreliable, None)
+        .unwrap();
+    connection
+        .send_reliable(vec![0xfe, 1], Reliability::ReliableOrd, Some(3))
+        .unwrap();
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    // the reliable frame is sent first, the unreliable one separately.
+    assert_eq!(datagrams.len(), 2);
+    let (ordered, unreliable) = (&datagrams[0][4..], &datagrams[1][4..]);
+    assert_eq!(ordered[0], 0x60);
+    assert_eq!(ordered[9], 3);
+    assert_eq!(ordered[10..12], [0xfe, 1]);
+    assert_eq!(unreliable[0], 0x00);
+    assert_eq!(unreliable[3..5], [0xfe, 0]);
+}
+
+#[test]
+fn order_indexes_increment_per_channel() {
+    let (mut connection, mut recv) = connection();
+    for (channel, body) in [(0, 0), (1, 1), (0, 2), (1, 3), (1, 4)] {
+        connection
+            .send_reliable(vec![0xfe, body], Reliability::ReliableOrd, Some(channel))
+            .unwrap();
+    }
+    connection.tick();
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let datagram = sent(&mut recv).remove(0);
+    let frames = datagram[4..].chunks(10 + 2).collect::<Vec<_>>();
+    assert_eq!(frames.len(), 5);
+    // the reliable index is shared by every channel, the order index is not.
+    let expected = [(0, 0, 0), (1, 0, 1), (2, 1, 0), (3, 1, 1), (4, 2, 1)];
+    for (frame, (reliable, order, channel)) in frames.into_iter().zip(expected) {
+        assert_eq!(u24(&frame[3..]), reliable);
+        assert_eq!(u24(&frame[6..]), order);
+        assert_eq!(frame[9], channel);
+        assert_eq!(frame[11], reliable as u8);
+    }
+}
+
+#[test]
+fn fragments_take_consecutive_reliable_indexes() {
+    let (mut connection, mut recv) = connection();
+    connection
+        .send_reliable(vec![0xfe, 0], Reliability::Reliable, None)
+        .unwrap();
+    let body = vec![0xfe; connection.max_frame_size() * 2 + 1];
+    connection
+        .send_reliable(body, Reliability::ReliableOrd, Some(2))
+        .unwrap();
+    connection
+        .send_reliable(vec![0xfe, 1], Reliability::Reliable, None)
+        .unwrap();
+    connection.tick();
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let mut indexes = Vec::new();
+    for datagram in sent(&mut recv) {
+        let mut position = 4;
+        while position < datagram.len() {
+            let frame = &datagram[position..];
+            let length = (u16::from_be_bytes([frame[1], frame[2]]) as usize + 7) / 8;
+            let ordered = frame[0] >> 5 == 3;
+            let split = frame[0] & 0x10 != 0;
+            indexes.push(u24(&frame[3..]));
+            if ordered {
+                // every fragment is on the same order index and channel.
+                assert_eq!(u24(&frame[6..]), 0);
+                assert_eq!(frame[9], 2);
+            }
+            position += 3 + 3 + if ordered { 4 } else { 0 } + if split { 10 } else { 0 } + length;
+        }
+    }
+    indexes.sort();
+    assert_eq!(indexes, (0..5).collect::<Vec<_>>());
+}
+
+#[test]
+fn packets_on_invalid_channels_are_rejected() {
+    let (mut connection, mut recv) = connection();
+    assert_eq!(
+        connection.send_reliable(vec![0xfe, 0], Reliability::ReliableOrd, Some(32)),
+        Err(SendError::InvalidChannel(32))
+    );
+    assert_eq!(
+        connection.send_with_receipt(vec![0xfe, 0], Reliability::ReliableOrdAck, Some(200), 1),
+        Err(SendError::InvalidChannel(200))
+    );
+    connection
+        .send_reliable(vec![0xfe, 1], Reliability::ReliableOrd, Some(31))
+        .unwrap();
+    connection.tick();
+
+    // only the packet on the last channel is sent.
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(datagrams[0][4 + 9], 31);
+    assert_eq!(datagrams[0][4 + 10..], [0xfe, 1]);
+}
+
+#[test]
+fn failed_sends_are_logged() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let captured = messages.clone();
+    set_logger(Arc::new(move |level, message: &str| {
+        captured.lock().unwrap().push((level, message.to_string()));
+    }));
+
+    // nobody is listening for the packets anymore.
+    let (mut connection, recv) = connection();
+    drop(recv);
+    assert_eq!(connection.send(vec![0xfe, 0], true), Err(SendError::Closed));
+    clear_logger();
+
+    let messages = messages.lock().unwrap();
+    assert!(messages.contains(&(
+        LogLevel::Warn,
+        "Failed to send packet to 127.0.0.1:19133".to_string()
+    )));
+}
+
+#[test]
+fn received_datagrams_are_acked_once_per_tick() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in 0..5 {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    // nothing is acked until the tick.
+    assert!(recv.try_recv().is_err());
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let acks = datagrams
+        .iter()
+        .filter(|b| b[0] == 0xc0)
+        .collect::<Vec<_>>();
+    assert_eq!(acks.len(), 1);
+    assert_eq!(ack_sequences(acks[0]), vec![0, 1, 2, 3, 4]);
+    assert!(!datagrams.iter().any(|b| b[0] == 0xa0));
+
+    // nothing new was received, so nothing is acked again.
+    connection.tick();
+    assert!(recv.try_recv().is_err());
+}
+
+#[test]
+fn consecutive_sequences_are_acked_as_ranges() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0, 1, 2, 3, 7, 9, 10] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let ack = datagrams.iter().find(|b| b[0] == 0xc0).unwrap();
+    // three records: the range 0-3, the single 7 and the range 9-10.
+    let mut expected = vec![0xc0, 0, 3];
+    expected.extend_from_slice(&[0, 0, 0, 0, 3, 0, 0]);
+    expected.extend_from_slice(&[1, 7, 0, 0]);
+    expected.extend_from_slice(&[0, 9, 0, 0, 10, 0, 0]);
+    assert_eq!(*ack, expected);
+
+    // the gaps are requested again the same way.
+    let nack = datagrams.iter().find(|b| b[0] == 0xa0).unwrap();
+    let mut expected = vec![0xa0, 0, 2];
+    expected.extend_from_slice(&[0, 4, 0, 0, 6, 0, 0]);
+    expected.extend_from_slice(&[1, 8, 0, 0]);
+    assert_eq!(*nack, expected);
+}
+
+#[test]
+fn acks_hold_a_record_per_run() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [1, 2, 3, 7, 9, 10] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let ack = datagrams.iter().find(|b| b[0] == 0xc0).unwrap();
+    assert_eq!(u16::from_be_bytes([ack[1], ack[2]]), 3);
+    assert_eq!(ack_sequences(ack), vec![1, 2, 3, 7, 9, 10]);
+}
+
+#[test]
+fn large_acks_are_split_by_mtu() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.mtu = 1200;
+    // every other sequence, so none of them can share a record.
+    for sequence in 0..10_000 {
+        connection.recv(&frame_packet(sequence * 2, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let acks = datagrams
+        .iter()
+        .filter(|b| b[0] == 0xc0)
+        .collect::<Vec<_>>();
+    assert!(acks.len() > 1);
+    assert!(datagrams
+        .iter()
+        .all(|datagram| datagram.len() <= (1200 - UDP_HEADER_SIZE).into()));
+
+    let acked = acks
+        .iter()
+        .flat_map(|ack| ack_sequences(ack))
+        .collect::<Vec<_>>();
+    assert_eq!(acked, (0..10_000).map(|i| i * 2).collect::<Vec<_>>());
+}
+
+#[test]
+fn skipped_datagrams_are_nacked() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0, 1, 3, 4] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let nacks = datagrams
+        .iter()
+        .filter(|b| b[0] == 0xa0)
+        .collect::<Vec<_>>();
+    assert_eq!(nacks.len(), 1);
+    assert_eq!(ack_sequences(nacks[0]), vec![2]);
+
+    // the missing datagram arrives, so it is acked instead.
+    connection.recv(&frame_packet(2, &[unreliable_frame(&[0xfe])]));
+    connection.tick();
+    let datagrams = sent(&mut recv);
+    assert!(!datagrams.iter().any(|b| b[0] == 0xa0));
+    assert_eq!(
+        ack_sequences(datagrams.iter().find(|b| b[0] == 0xc0).unwrap()),
+        vec![2]
+    );
+}
+
+#[test]
+fn gaps_are_nacked_as_a_range() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0, 3] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let nack = datagrams.iter().find(|b| b[0] == 0xa0).unwrap();
+    // a single record, the range 1-2.
+    assert_eq!(nack[..], [0xa0, 0, 1, 0, 1, 0, 0, 2, 0, 0]);
+}
+
+#[test]
+fn late_datagrams_are_no_longer_nacked() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0, 1, 3, 4, 2] {
+        connection.recv(&frame_packet(
+            sequence,
+            &[unreliable_frame(&[0xfe, sequence as u8])],
+        ));
+    }
+    connection.tick();
+
+    assert!(!sent(&mut recv).iter().any(|b| b[0] == 0xa0));
+    assert_eq!(game_packets(&connection).len(), 5);
+}
+
+#[test]
+fn duplicate_datagrams_are_acked_but_not_handled() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0, 2, 2, 0, 1, 1] {
+        connection.recv(&frame_packet(
+            sequence,
+            &[unreliable_frame(&[0xfe, sequence as u8])],
+        ));
+    }
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 2], vec![0xfe, 1]]
+    );
+
+    connection.tick();
+    let datagrams = sent(&mut recv);
+    let ack = datagrams.iter().find(|b| b[0] == 0xc0).unwrap();
+    assert_eq!(ack_sequences(ack), vec![0, 1, 2]);
+}
+
+#[test]
+fn datagrams_too_old_to_remember_are_dropped() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    // the jump is too large to NACK, but 990 is remembered as missing and 5 is too old to tell.
+    for (index, sequence) in [0, 1000, 990, 990, 5].into_iter().enumerate() {
+        connection.recv(&frame_packet(
+            sequence,
+            &[unreliable_frame(&[0xfe, index as u8])],
+        ));
+    }
+    // the second copy of 990 is a duplicate, and 5 is treated like one.
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0], vec![0xfe, 1], vec![0xfe, 2]]
+    );
+
+    connection.tick();
+    let datagrams = sent(&mut recv);
+    assert!(!datagrams.iter().any(|b| b[0] == 0xa0));
+    let ack = datagrams.iter().find(|b| b[0] == 0xc0).unwrap();
+    assert_eq!(ack_sequences(ack), vec![0, 5, 990, 1000]);
+}
+
+#[test]
+fn datagram_sequences_wrap_around() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0xfffffe, 0xffffff, 1] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let nack = datagrams.iter().find(|b| b[0] == 0xa0).unwrap();
+    assert_eq!(ack_sequences(nack), vec![0]);
+    assert_eq!(game_packets(&connection).len(), 3);
+}
+
+#[test]
+fn datagrams_across_the_wraparound_are_acked() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for sequence in [0xfffffe, 0xffffff, 0, 1] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    // these came before the wraparound, they were already handled.
+    for sequence in [0xfffffe, 0xffffff] {
+        connection.recv(&frame_packet(sequence, &[unreliable_frame(&[0xfe])]));
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    assert!(!datagrams.iter().any(|b| b[0] == 0xa0));
+    let ack = datagrams.iter().find(|b| b[0] == 0xc0).unwrap();
+    assert_eq!(ack_sequences(ack), vec![0, 1, 0xfffffe, 0xffffff]);
+    assert_eq!(game_packets(&connection).len(), 4);
+}
+
+#[test]
+fn disconnect_notification_disconnects() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&frame_packet(
+        0,
+        &[unreliable_frame(&[0x15]), unreliable_frame(&[0xfe])],
+    ));
+
+    assert_eq!(connection.state, ConnectionState::Disconnected);
+    assert!(connection.is_disconnected());
+    assert!(connection.event_dispatch.iter().any(
+        |event| matches!(event, RakEvent::Disconnect(address, _) if address == "127.0.0.1:19133")
+    ));
+
+    // the notification is acknowledged right away.
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(ack_sequences(&datagrams[0]), vec![0]);
+
+    // anything after the notification is dropped.
+    connection.recv(&frame_packet(1, &[unreliable_frame(&[0xfe])]));
+    assert!(game_packets(&connection).is_empty());
+    assert_eq!(connection.state, ConnectionState::Disconnected);
+    connection.tick();
+    assert!(recv.try_recv().is_err());
+}
+
+#[test]
+fn disconnect_notification_cancels_server_disconnect() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection
+        .send_reliable(vec![0xfe, 0], Reliability::Reliable, None)
+        .unwrap();
+    connection.disconnect("Server closed.", true);
+    assert!(!connection.is_disconnected());
+
+    // the client leaves before our notification went out, so it's never sent.
+    connection.recv(&frame_packet(0, &[unreliable_frame(&[0x15])]));
+    assert!(connection.is_disconnected());
+    sent(&mut recv);
+    connection.tick();
+    assert!(recv.try_recv().is_err());
+}
+
+#[test]
+fn fragments_are_reassembled() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&frame_packet(0, &[fragment_frame(3, 1, 2, &[2, 3])]));
+    assert_eq!(connection.pending_fragments(), 1);
+    assert!(game_packets(&connection).is_empty());
+
+    connection.recv(&frame_packet(1, &[fragment_frame(3, 0, 2, &[0xfe, 1])]));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 1, 2, 3]]);
+    assert_eq!(connection.pending_fragments(), 0);
+}
+
+#[test]
+fn reassembled_frames_keep_their_place_in_the_order_channel() {
+    let (mut sender, mut recv) = connection();
+    let (mut receiver, _recv) = connection();
+    receiver.state = ConnectionState::Connected;
+    let mut large = vec![0xfe];
+    large.extend((1..sender.max_frame_size() * 2).map(|i| i as u8));
+    let bodies = vec![vec![0xfe, 0], large, vec![0xfe, 2]];
+
+    let mut datagrams = Vec::new();
+    for body in &bodies {
+        sender
+            .send_reliable(body.clone(), Reliability::ReliableOrd, Some(1))
+            .unwrap();
+        sender.tick();
+        datagrams.push(sent(&mut recv));
+    }
+    assert_eq!(datagrams[1].len(), 3);
+
+    // the last message and the end of the fragmented one arrive first, so both wait
+    // on the messages before them.
+    receiver.recv(&datagrams[2][0]);
+    receiver.recv(&datagrams[1][2]);
+    receiver.recv(&datagrams[1][0]);
+    assert!(game_packets(&receiver).is_empty());
+    receiver.recv(&datagrams[1][1]);
+    assert!(game_packets(&receiver).is_empty());
+    receiver.recv(&datagrams[0][0]);
+    assert_eq!(game_packets(&receiver), bodies);
+}
+
+#[test]
+fn oversized_fragment_counts_are_rejected() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&frame_packet(0, &[fragment_frame(3, 0, u32::MAX, &[0xfe])]));
+    connection.recv(&frame_packet(1, &[fragment_frame(4, 0, 0, &[0xfe])]));
+    assert_eq!(connection.pending_fragments(), 0);
+
+    // the limit is configurable.
+    connection.max_fragments = 2;
+    connection.recv(&frame_packet(2, &[fragment_frame(5, 0, 3, &[0xfe])]));
+    assert_eq!(connection.pending_fragments(), 0);
+    connection.recv(&frame_packet(3, &[fragment_frame(5, 0, 2, &[0xfe])]));
+    assert_eq!(connection.pending_fragments(), 1);
+}
+
+#[test]
+fn oversized_fragmented_frames_are_rejected() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    // even without a fragment limit, the frame can't claim to be larger than we allow.
+    connection.max_fragments = u32::MAX;
+    connection.recv(&frame_packet(0, &[fragment_frame(3, 0, u32::MAX, &[0xfe])]));
+    assert_eq!(connection.pending_fragments(), 0);
+    assert!(violated_protocol(&connection));
+
+    // the fragments may also turn out to be larger than the first one claimed.
+    connection.max_fragmented_size = 25;
+    connection.recv(&frame_packet(1, &[fragment_frame(4, 0, 2, &[0xfe; 10])]));
+    assert_eq!(connection.pending_fragments(), 1);
+    connection.recv(&frame_packet(2, &[fragment_frame(4, 1, 2, &[0xfe; 20])]));
+    assert_eq!(connection.pending_fragments(), 0);
+    assert!(game_packets(&connection).is_empty());
+
+    connection.recv(&frame_packet(3, &[fragment_frame(5, 0, 2, &[0xfe; 10])]));
+    connection.recv(&frame_packet(4, &[fragment_frame(5, 1, 2, &[1; 15])]));
+    assert_eq!(game_packets(&connection).len(), 1);
+}
+
+#[test]
+fn fragmented_frames_waiting_at_once_are_limited() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.max_fragmented_frames = 2;
+    for (sequence, id) in [3, 4, 5].into_iter().enumerate() {
+        connection.recv(&frame_packet(
+            sequence as u32,
+            &[fragment_frame(id, 0, 2, &[0xfe, id as u8])],
+        ));
+    }
+    assert_eq!(connection.pending_fragments(), 2);
+    assert!(violated_protocol(&connection));
+
+    // once a frame is completed there is room for another.
+    connection.recv(&frame_packet(3, &[fragment_frame(3, 1, 2, &[1])]));
+    connection.recv(&frame_packet(4, &[fragment_frame(5, 0, 2, &[0xfe, 5])]));
+    assert_eq!(connection.pending_fragments(), 2);
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 3, 1]]);
+}
+
+#[test]
+fn invalid_fragment_indexes_are_rejected() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&frame_packet(0, &[fragment_frame(3, 0, 2, &[0xfe, 1])]));
+    // out of range, and a duplicate that would otherwise complete the frame.
+    connection.recv(&frame_packet(1, &[fragment_frame(3, 2, 2, &[4])]));
+    connection.recv(&frame_packet(2, &[fragment_frame(3, 0, 2, &[0xfe, 1])]));
+    // a negative index off the wire.
+    connection.recv(&frame_packet(
+        3,
+        &[fragment_frame(3, -1i32 as u32, 2, &[4])],
+    ));
+    assert!(game_packets(&connection).is_empty());
+
+    connection.recv(&frame_packet(4, &[fragment_frame(3, 1, 2, &[2, 3])]));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 1, 2, 3]]);
+}
+
+#[test]
+fn fragments_are_reassembled_in_index_order() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    for (sequence, index) in [2, 0, 3, 1].into_iter().enumerate() {
+        assert!(game_packets(&connection).is_empty());
+        connection.recv(&frame_packet(
+            sequence as u32,
+            &[fragment_frame(3, index, 4, &[0xfe, index as u8])],
+        ));
+    }
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0xfe, 0, 0xfe, 1, 0xfe, 2, 0xfe, 3]]
+    );
+}
+
+#[test]
+fn incomplete_fragments_are_evicted() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.fragment_timeout = Duration::from_millis(50);
+    connection.recv(&frame_packet(0, &[fragment_frame(3, 0, 2, &[0xfe, 1])]));
+    assert_eq!(connection.pending_fragments(), 1);
+
+    // the group is still within its window.
+    connection.tick();
+    assert_eq!(connection.pending_fragments(), 1);
+
+    std::thread::sleep(Duration::from_millis(60));
+    connection.tick();
+    assert_eq!(connection.pending_fragments(), 0);
+    assert_eq!(connection.stats().fragments_dropped, 1);
+
+    // the rest of the frame arriving late doesn't complete anything.
+    connection.recv(&frame_packet(1, &[fragment_frame(3, 1, 2, &[2, 3])]));
+    assert!(game_packets(&connection).is_empty());
+}
+
+#[test]
+fn server_disconnect_is_sent_after_queued_packets() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.send(vec![0xfe, 1], false).unwrap();
+    connection.disconnect("Kicked", true);
+    assert_eq!(connection.state, ConnectionState::Disconnecting);
+    assert!(!connection.is_disconnected());
+
+    // nothing can be sent once the client is being disconnected, nor is it disconnected twice.
+    connection.send(vec![0xfe, 2], false).unwrap();
+    connection.disconnect("Kicked", true);
+    assert!(recv.try_recv().is_err());
+    let disconnects = connection
+        .event_dispatch
+        .iter()
+        .filter(|event| matches!(event, RakEvent::Disconnect(..)))
+        .count();
+    assert_eq!(disconnects, 1);
+
+    connection.tick();
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+    let datagram = &datagrams[0];
+    assert_eq!(datagram[0], 0x80);
+    // the queued packet comes first, the notification last.
+    assert_eq!(datagram[14..16], [0xfe, 1]);
+    assert_eq!(datagram.last(), Some(&0x15));
+    assert_eq!(connection.state, ConnectionState::Disconnected);
+    assert!(connection.is_disconnected());
+}
+
+#[test]
+fn invalid_magic_gets_no_reply() {
+    let (mut connection, mut recv) = connection();
+    let mut request = open_connect_request(1400);
+    // shift the magic by one byte.
+    request.insert(1, 0x00);
+    connection.recv(&request);
+    assert!(recv.try_recv().is_err());
+}
+
+#[test]
+fn idle_connection_times_out() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+
+    connection.recv_time = SystemTime::now() - Duration::from_secs(6);
+    connection.tick();
+    assert_eq!(connection.state, ConnectionState::TimingOut);
+    assert!(!connection.is_disconnected());
+
+    connection.recv_time = SystemTime::now() - Duration::from_secs(11);
+    connection.tick();
+    assert_eq!(connection.state, ConnectionState::Offline);
+    assert!(connection.is_disconnected());
+}
+
+#[test]
+fn timing_out_connection_is_kept_alive() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.send(vec![0xfe, 1], false).unwrap();
+    connection.recv_time = SystemTime::now() - Duration::from_secs(6);
+    connection.tick();
+    assert_eq!(connection.state, ConnectionState::TimingOut);
+
+    // the client is pinged, and still gets what was queued for it.
+    let datagrams = sent(&mut recv);
+    assert!(datagrams
+        .iter()
+        .any(|datagram| datagram[0] == 0x80 && datagram[14] == 0x00));
+    assert!(datagrams
+        .iter()
+        .any(|datagram| datagram.windows(2).any(|body| body == [0xfe, 1])));
+}
+
+#[test]
+fn timing_out_connection_resumes_its_state() {
+    for state in [ConnectionState::Connecting, ConnectionState::Connected] {
+        let (mut connection, _recv) = connection();
+        connection.state = state.clone();
+        connection.recv_time = SystemTime::now() - Duration::from_secs(6);
+        connection.tick();
+        assert_eq!(connection.state, ConnectionState::TimingOut);
+
+        connection.recv(&frame_packet(0, &[unreliable_frame(&[0xfe, 1])]));
+        assert_eq!(connection.state, state);
+        // only completing the handshake lifts the deadline.
+        assert!(connection.handshake_deadline.is_some());
+    }
+}
+
+#[test]
+fn timed_out_connection_dispatches_disconnect() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv_time = SystemTime::now() - Duration::from_secs(11);
+    connection.tick();
+
+    assert!(connection
+        .event_dispatch
+        .iter()
+        .any(|event| matches!(event, RakEvent::Disconnect(_, reason) if reason == "Timed Out")));
+}
+
+#[test]
+fn timeout_is_configurable() {
+    let (mut connection, _recv) = connection();
+    connection.timeout = Duration::from_secs(30);
+    connection.recv_time = SystemTime::now() - Duration::from_secs(11);
+    connection.tick();
+    assert!(!connection.is_disconnected());
+}
+
+#[test]
+fn connected_ping_measures_latency() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.tick();
+
+    // a single reliable ordered frame packet, the body starts after the frame header.
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(datagrams[0][0], 0x80);
+    assert_eq!(datagrams[0][14], 0x00);
+
+    // we won't ping again until the interval passed.
+    connection.tick();
+    assert!(recv.try_recv().is_err());
+    assert_eq!(connection.latency(), None);
+
+    std::thread::sleep(Duration::from_millis(25));
+    connection.recv(&pong(&datagrams[0], 0));
+
+    let latency = connection.latency().unwrap();
+    assert!(latency >= Duration::from_millis(25));
+    assert_eq!(connection.ping_ms(), latency.as_millis() as u32);
+}
+
+/// Answers the `ConnectedPing` in the datagram with a framed `ConnectedPong`.
+fn pong(ping: &[u8], sequence: u32) -> Vec<u8> {
+    let mut pong = vec![0x03];
+    pong.extend_from_slice(&ping[15..23]);
+    pong.extend_from_slice(&0i64.to_be_bytes());
+    frame_packet(sequence, &[unreliable_frame(&pong)])
+}
+
+/// Ticks the connection and returns the datagram carrying the ping it sent.
+fn tick_ping(connection: &mut Connection, recv: &mut Receiver<SendCommand>) -> Vec<u8> {
+    connection.tick();
+    sent(recv)
+        .into_iter()
+        .find(|datagram| datagram[0] == 0x80 && datagram[14] == 0x00)
+        .expect("No ping was sent")
+}
+
+#[test]
+fn latency_is_smoothed() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.ping_interval = Duration::ZERO;
+
+    let ping = tick_ping(&mut connection, &mut recv);
+    std::thread::sleep(Duration::from_millis(100));
+    connection.recv(&pong(&ping, 0));
+    let first = connection.latency().unwrap();
+    assert!(first >= Duration::from_millis(100));
+
+    // an instant reply only counts for an eighth.
+    let ping = tick_ping(&mut connection, &mut recv);
+    connection.recv(&pong(&ping, 1));
+    let smoothed = connection.latency().unwrap();
+    assert!(smoothed >= first * 7 / 8);
+    assert!(smoothed < first * 7 / 8 + Duration::from_millis(5));
+}
+
+#[test]
+fn detect_lost_connections_is_answered_with_a_ping() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&frame_packet(0, &[unreliable_frame(&[0x04])]));
+
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(datagrams[0][14], 0x00);
+}
+
+#[test]
+fn unknown_online_packets_are_ignored() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&frame_packet(0, &[unreliable_frame(&[0x7f, 1])]));
+
+    assert!(recv.try_recv().is_err());
+    assert!(game_packets(&connection).is_empty());
+    assert!(!connection.is_disconnected());
+}
+
+#[test]
+fn user_packet_ids_are_configurable() {
+    let (mut connection, _recv) = connection();
+    connection.state = ConnectionState::Connected;
+    // only the game packet is passed on by default.
+    connection.recv(&frame_packet(0, &[unreliable_frame(&[0x90, 1])]));
+    assert!(game_packets(&connection).is_empty());
+
+    connection.user_packet_ids = 0x86..=0xff;
+    connection.recv(&frame_packet(1, &[unreliable_frame(&[0x90, 1])]));
+    connection.recv(&frame_packet(2, &[unreliable_frame(&[0xfe, 2])]));
+    assert_eq!(
+        game_packets(&connection),
+        vec![vec![0x90, 1], vec![0xfe, 2]]
+    );
+}
+
+#[test]
+fn unexpected_pong_is_ignored() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+
+    let mut ping = tick_ping(&mut connection, &mut recv);
+    // a pong for a ping we never sent.
+    ping[22] ^= 0xff;
+    connection.recv(&pong(&ping, 0));
+    assert_eq!(connection.latency(), None);
+    assert_eq!(connection.ping_ms(), 0);
+}
+
+#[test]
+fn missed_pings_lose_the_connection() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.ping_interval = Duration::ZERO;
+
+    // the first ping isn't missed yet, every one after it is.
+    for _ in 0..3 {
+        tick_ping(&mut connection, &mut recv);
+    }
+    assert_eq!(connection.state, ConnectionState::Connected);
+
+    connection.tick();
+    assert_eq!(connection.state, ConnectionState::Offline);
+    assert!(connection.event_dispatch.iter().any(
+        |event| matches!(event, RakEvent::Disconnect(_, reason) if reason == "Connection lost")
+    ));
+}
+
+#[test]
+fn late_pong_resets_missed_pings() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.ping_interval = Duration::ZERO;
+
+    let first = tick_ping(&mut connection, &mut recv);
+    for _ in 0..2 {
+        tick_ping(&mut connection, &mut recv);
+    }
+    // the client answers the first ping after missing two.
+    connection.recv(&pong(&first, 0));
+
+    for _ in 0..2 {
+        tick_ping(&mut connection, &mut recv);
+    }
+    assert_eq!(connection.state, ConnectionState::Connected);
+}
+
+#[test]
+fn high_latency_dispatches_event() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.latency_threshold = Some(Duration::from_millis(10));
+
+    let ping = tick_ping(&mut connection, &mut recv);
+    std::thread::sleep(Duration::from_millis(30));
+    connection.recv(&pong(&ping, 0));
+
+    let latency = connection
+        .event_dispatch
+        .iter()
+        .find_map(|event| match event {
+            RakEvent::Latency(address, ms) => Some((address.clone(), *ms)),
+            _ => None,
+        });
+    let (address, ms) = latency.expect("Expected a Latency event");
+    assert_eq!(address, "127.0.0.1:19133");
+    assert!(ms >= 30);
+}
+
+#[test]
+fn unacknowledged_datagram_is_resent() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.resend_timeout = Duration::from_millis(50);
+
+    // the ping is reliable, so it waits for an ACK.
+    connection.tick();
+    let ping = sent(&mut recv).remove(0);
+
+    // it hasn't timed out yet.
+    connection.tick();
+    assert!(sent(&mut recv).is_empty());
+
+    // it's resent under the next sequence, as the client may have seen the first.
+    std::thread::sleep(Duration::from_millis(60));
+    connection.tick();
+    let resent = sent(&mut recv);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0][1..4], [1, 0, 0]);
+    assert_eq!(resent[0][4..], ping[4..]);
+}
+
+#[test]
+fn only_expired_datagrams_are_resent() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.resend_timeout = Duration::from_millis(100);
+    // the ping is reliable too, it's sent on the first tick.
+    connection.tick();
+    let mut datagrams = sent(&mut recv);
+    for index in 0..3 {
+        connection
+            .send_reliable(vec![0xfe, index], Reliability::Reliable, None)
+            .unwrap();
+        connection.tick();
+        datagrams.extend(sent(&mut recv));
+    }
+    assert_eq!(datagrams.len(), 4);
+
+    // none of them have waited long enough yet.
+    connection.tick();
+    assert!(sent(&mut recv).is_empty());
+
+    std::thread::sleep(Duration::from_millis(110));
+    connection.tick();
+    // the sequences are new, the frames are the same.
+    let mut resent = sent(&mut recv)
+        .into_iter()
+        .map(|datagram| datagram[4..].to_vec())
+        .collect::<Vec<_>>();
+    let mut datagrams = datagrams
+        .into_iter()
+        .map(|datagram| datagram[4..].to_vec())
+        .collect::<Vec<_>>();
+    resent.sort();
+    datagrams.sort();
+    assert_eq!(resent, datagrams);
+}
+
+#[test]
+fn acknowledged_datagrams_are_not_resent() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.resend_timeout = Duration::from_millis(50);
+
+    // the ping fits in a single datagram, the body is split over a few.
+    connection.tick();
+    let body = vec![0xfe; connection.max_frame_size() * 2];
+    connection
+        .send_reliable(body, Reliability::Reliable, None)
+        .unwrap();
+    connection.tick();
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 4);
+
+    // acks refer to the sequence of the datagrams.
+    let last = u32::from_le_bytes([datagrams[3][1], datagrams[3][2], datagrams[3][3], 0]);
+    assert_eq!(last, 3);
+    connection.recv(&vec![0xc0, 0, 1, 0, 0, 0, 0, 3, 0, 0]);
+
+    std::thread::sleep(Duration::from_millis(60));
+    connection.tick();
+    assert!(sent(&mut recv).is_empty());
+}
+
+#[test]
+fn datagram_is_dropped_after_max_resends() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.resend_timeout = Duration::from_millis(20);
+    connection.max_resends = 2;
+
+    connection.tick();
+    let ping = sent(&mut recv).remove(0);
+
+    let mut resent = 0;
+    for _ in 0..5 {
+        std::thread::sleep(Duration::from_millis(30));
+        connection.tick();
+        resent += sent(&mut recv)
+            .iter()
+            .filter(|d| d[4..] == ping[4..])
+            .count();
+    }
+    assert_eq!(resent, 2);
+}
+
+/// Finds the sequence of the datagram carrying the given body.
+fn sequence_of(datagrams: &[Vec<u8>], body: &[u8]) -> u32 {
+    let datagram = datagrams
+        .iter()
+        .find(|datagram| datagram.windows(body.len()).any(|w| w == body))
+        .expect("The body was never sent");
+    u32::from_le_bytes([datagram[1], datagram[2], datagram[3], 0])
+}
+
+fn receipts(connection: &Connection) -> Vec<RakEvent> {
+    connection
+        .event_dispatch
+        .iter()
+        .filter(|event| matches!(event, RakEvent::AckReceipt(..) | RakEvent::LossReceipt(..)))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn nacked_datagrams_are_resent_under_a_new_sequence() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.resend_timeout = Duration::from_millis(50);
+    connection
+        .send_with_receipt(vec![0xfe, 7], Reliability::Reliable, None, 3)
+        .unwrap();
+    connection.tick();
+    let datagrams = sent(&mut recv);
+    let original = datagrams
+        .iter()
+        .find(|datagram| datagram.ends_with(&[0xfe, 7]))
+        .unwrap()
+        .clone();
+    let sequence = sequence_of(&datagrams, &[0xfe, 7]);
+
+    let seq = sequence.to_le_bytes();
+    connection.recv(&vec![0xa0, 0, 1, 1, seq[0], seq[1], seq[2]]);
+    let resent = sent(&mut recv);
+    assert_eq!(resent.len(), 1);
+    // the same frame with the same reliable index, in a new datagram.
+    assert_eq!(resent[0][4..], original[4..]);
+    let resequence = sequence_of(&resent, &[0xfe, 7]);
+    assert!(resequence > sequence);
+
+    // the old sequence is gone, the new one is acknowledged instead.
+    connection.recv(&vec![0xa0, 0, 1, 1, seq[0], seq[1], seq[2]]);
+    assert!(recv.try_recv().is_err());
+    let seq = resequence.to_le_bytes();
+    connection.recv(&vec![0xc0, 0, 1, 1, seq[0], seq[1], seq[2]]);
+    assert!(matches!(
+        receipts(&connection).as_slice(),
+        [RakEvent::AckReceipt(_, 3)]
+    ));
+
+    std::thread::sleep(Duration::from_millis(60));
+    connection.tick();
+    assert!(!sent(&mut recv).iter().any(|d| d.ends_with(&[0xfe, 7])));
+}
+
+#[test]
+fn acks_and_nacks_for_unknown_sequences_are_ignored() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.recv(&vec![0xc0, 0, 1, 0, 0, 0, 0, 9, 0, 0]);
+    connection.recv(&vec![0xa0, 0, 1, 0, 0, 0, 0, 9, 0, 0]);
+    assert!(recv.try_recv().is_err());
+    assert!(!violated_protocol(&connection));
+
+    // neither is a datagram of its own, so there's nothing to acknowledge or nack.
+    connection.tick();
+    assert!(!sent(&mut recv)
+        .iter()
+        .any(|datagram| datagram[0] == 0xc0 || datagram[0] == 0xa0));
+}
+
+#[test]
+fn ranges_only_cover_datagrams_in_flight() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection
+        .send_with_receipt(vec![0xfe, 7], Reliability::Reliable, None, 3)
+        .unwrap();
+    connection.tick();
+    sent(&mut recv);
+
+    // a NACK for every sequence there is resends the datagram once, not its new sequence too.
+    connection.recv(&vec![0xa0, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0xff]);
+    let resent = sent(&mut recv);
+    assert_eq!(resent.iter().filter(|d| d.ends_with(&[0xfe, 7])).count(), 1);
+
+    connection.recv(&vec![0xc0, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0xff]);
+    assert!(matches!(
+        receipts(&connection).as_slice(),
+        [RakEvent::AckReceipt(_, 3)]
+    ));
+}
+
+#[test]
+fn acknowledged_packets_give_a_receipt() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection
+        .send_with_receipt(vec![0xfe, 7], Reliability::ReliableOrdAck, None, 42)
+        .unwrap();
+    connection.tick();
+    let sequence = sequence_of(&sent(&mut recv), &[0xfe, 7]);
+    assert!(receipts(&connection).is_empty());
+
+    let seq = sequence.to_le_bytes();
+    connection.recv(&vec![0xc0, 0, 1, 1, seq[0], seq[1], seq[2]]);
+    match receipts(&connection).as_slice() {
+        [RakEvent::AckReceipt(address, 42)] => assert_eq!(address, "127.0.0.1:19133"),
+        events => panic!("Expected an AckReceipt, got {:?}", events),
+    }
+}
+
+#[test]
+fn fragmented_packets_give_a_receipt_once_fully_acknowledged() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    let body = vec![0xfe; connection.max_frame_size() * 3];
+    connection
+        .send_with_receipt(body, Reliability::ReliableAck, None, 7)
+        .unwrap();
+    connection.tick();
+    let sequences = sent(&mut recv)
+        .iter()
+        .filter(|datagram| datagram[0] & 0x80 != 0 && datagram[4] & 0x10 != 0)
+        .map(|datagram| u32::from_le_bytes([datagram[1], datagram[2], datagram[3], 0]))
+        .collect::<Vec<_>>();
+    assert!(sequences.len() > 1);
+
+    for (i, sequence) in sequences.iter().enumerate() {
+        assert!(receipts(&connection).is_empty());
+        let seq = sequence.to_le_bytes();
+        connection.recv(&vec![0xc0, 0, 1, 1, seq[0], seq[1], seq[2]]);
+        if i + 1 < sequences.len() {
+            assert!(receipts(&connection).is_empty());
+        }
+    }
+    assert!(matches!(
+        receipts(&connection).as_slice(),
+        [RakEvent::AckReceipt(_, 7)]
+    ));
+}
+
+#[test]
+fn dropped_packets_give_a_loss_receipt() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.resend_timeout = Duration::from_millis(20);
+    connection.max_resends = 0;
+    connection
+        .send_with_receipt(vec![0xfe, 7], Reliability::UnreliableAck, None, 42)
+        .unwrap();
+    connection.tick();
+    sent(&mut recv);
+    assert!(receipts(&connection).is_empty());
+
+    std::thread::sleep(Duration::from_millis(30));
+    connection.tick();
+    assert!(matches!(
+        receipts(&connection).as_slice(),
+        [RakEvent::LossReceipt(_, 42)]
+    ));
+
+    // the receipt is only given once.
+    std::thread::sleep(Duration::from_millis(30));
+    connection.tick();
+    assert_eq!(receipts(&connection).len(), 1);
+}
+
+#[test]
+fn unconnected_ping_is_echoed() {
+    let (mut connection, mut recv) = connection();
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 123456,
+        magic: Magic::new(),
+        client_id: 42,
+    });
+    connection.recv(&ping.parse().unwrap());
+
+    match next_offline(&mut recv) {
+        OfflinePacket::UnconnectedPong(pk) => {
+            assert_eq!(pk.timestamp, 123456);
+            assert_eq!(pk.server_id, 1234);
+        }
+        packet => panic!("Expected UnconnectedPong, got {:?}", packet),
+    }
+
+    let ping = connection
+        .event_dispatch
+        .iter()
+        .find(|event| matches!(event, RakEvent::Ping(..)));
+    match ping {
+        Some(RakEvent::Ping(address, guid, time)) => {
+            assert_eq!(address, "127.0.0.1:19133");
+            assert_eq!(*guid, 42);
+            assert_eq!(*time, 123456);
+        }
+        _ => panic!("Expected a Ping event"),
+    }
+}
+
+#[test]
+fn queued_packets_share_a_frame_packet() {
+    let (mut connection, mut recv) = connection();
+    let bodies = (0..5).map(|i| vec![0xfe, i, i, i]).collect::<Vec<_>>();
+    for body in &bodies {
+        connection.send(body.clone(), false).unwrap();
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+
+    // the other side gets every packet, in the order they were queued.
+    let (mut other, _recv) = self::connected();
+    other.recv(&datagrams[0]);
+    assert_eq!(game_packets(&other), bodies);
+}
+
+#[test]
+fn queued_packets_are_split_by_mtu() {
+    let (mut connection, mut recv) = connection();
+    connection.mtu = 576;
+    let bodies = (0..30).map(|i| vec![0xfe; 100 + i]).collect::<Vec<_>>();
+    for body in &bodies {
+        connection.send(body.clone(), false).unwrap();
+    }
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    assert!(datagrams.len() > 1);
+    assert!(datagrams
+        .iter()
+        .all(|datagram| datagram.len() <= connection.max_frame_size() + 4));
+
+    let (mut other, _recv) = self::connected();
+    for datagram in &datagrams {
+        other.recv(datagram);
+    }
+    assert_eq!(game_packets(&other), bodies);
+}
+
+#[test]
+fn large_packets_are_fragmented() {
+    let (mut connection, mut recv) = connection();
+    let (mut other, _recv) = self::connected();
+    connection.mtu = 1400;
+
+    let mut bodies = Vec::new();
+    for len in [1400, 4096] {
+        let mut body = vec![0xfe];
+        body.extend((1..len).map(|i| i as u8));
+        connection
+            .send_reliable(body.clone(), Reliability::ReliableOrd, None)
+            .unwrap();
+        connection.tick();
+        bodies.push(body);
+
+        let datagrams = sent(&mut recv);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram.len() <= (1400 - UDP_HEADER_SIZE).into()));
+
+        // the other side puts the fragments back together.
+        for datagram in &datagrams {
+            other.recv(datagram);
+        }
+        assert_eq!(game_packets(&other), bodies);
+    }
+}
+
+#[test]
+fn fragment_count_follows_the_mtu() {
+    let (mut connection, mut recv) = connection();
+    connection.mtu = 1400;
+    let usable = connection.max_fragment_size();
+
+    // anything over the frame size is fragmented.
+    for len in [connection.max_frame_size() + 1, usable * 2, 4096] {
+        connection
+            .send_reliable(vec![0xfe; len], Reliability::ReliableOrd, None)
+            .unwrap();
+        connection.tick();
+
+        let datagrams = sent(&mut recv);
+        let expected = (len + usable - 1) / usable;
+        assert_eq!(datagrams.len(), expected, "length {}", len);
+        for datagram in datagrams {
+            // the fragment count follows the order channel.
+            let count = u32::from_be_bytes(datagram[14..18].try_into().unwrap());
+            assert_eq!(count as usize, expected);
+        }
+    }
+}
+
+#[test]
+fn fragments_fit_the_mtu_with_their_headers() {
+    for mtu in [576, 1400] {
+        for reliability in [
+            Reliability::Unreliable,
+            Reliability::ReliableOrd,
+            Reliability::ReliableSeq,
+        ] {
+            let (mut connection, mut recv) = connection();
+            connection.mtu = mtu;
+            connection
+                .send_reliable(vec![0xfe; 5000], reliability, None)
+                .unwrap();
+            connection.tick();
+
+            let datagrams = sent(&mut recv);
+            assert!(datagrams.len() > 1);
+            for datagram in datagrams {
+                // the frames never take up more than the room left by the headers.
+                assert!(datagram.len() <= connection.max_frame_size() + 4);
+                assert!(datagram.len() <= (mtu - UDP_HEADER_SIZE).into());
+            }
+        }
+    }
+}
+
+#[test]
+fn large_payloads_are_spread_over_datagrams() {
+    let (mut connection, mut recv) = connection();
+    connection.mtu = 1200;
+    connection
+        .send_reliable(vec![0xfe; 64 * 1024], Reliability::ReliableOrd, None)
+        .unwrap();
+    connection.tick();
+
+    let datagrams = sent(&mut recv);
+    let usable = connection.max_fragment_size();
+    assert_eq!(datagrams.len(), (64 * 1024 + usable - 1) / usable);
+    for (sequence, datagram) in datagrams.iter().enumerate() {
+        assert!(datagram.len() <= 1200 - UDP_HEADER_SIZE as usize);
+        // every datagram takes the next sequence.
+        let seq = u32::from_le_bytes([datagram[1], datagram[2], datagram[3], 0]);
+        assert_eq!(seq as usize, sequence);
+    }
+}
+
+#[test]
+fn fragments_share_the_metadata_of_their_packet() {
+    let (mut connection, mut recv) = connection();
+    let (mut other, _recv) = self::connection();
+    other.state = ConnectionState::Connected;
+    connection.mtu = 500;
+    connection
+        .send_reliable(vec![0xfe, 0], Reliability::ReliableOrd, Some(1))
+        .unwrap();
+
+    let mut body = vec![0xfe];
+    body.extend((1..10 * 1024).map(|i| (i % 251) as u8));
+    connection
+        .send_reliable(body.clone(), Reliability::ReliableOrd, Some(1))
+        .unwrap();
+    connection.tick();
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let datagrams = sent(&mut recv);
+    let fragments = datagrams
+        .iter()
+        .filter(|datagram| datagram[4] & 0x10 != 0)
+        .collect::<Vec<_>>();
+    let usable = connection.max_fragment_size();
+    assert_eq!(fragments.len(), (body.len() + usable - 1) / usable);
+    for (index, fragment) in fragments.iter().enumerate() {
+        assert!(fragment.len() <= 500 - UDP_HEADER_SIZE as usize);
+        // every fragment is on the second order index of the channel.
+        assert_eq!(u24(&fragment[10..]), 1);
+        assert_eq!(fragment[13], 1);
+        let count = u32::from_be_bytes(fragment[14..18].try_into().unwrap());
+        assert_eq!(count as usize, fragments.len());
+        assert_eq!(fragment[18..20], fragments[0][18..20]);
+        let position = u32::from_be_bytes(fragment[20..24].try_into().unwrap());
+        assert_eq!(position as usize, index);
+    }
+
+    for datagram in &datagrams {
+        other.recv(datagram);
+    }
+    assert_eq!(game_packets(&other), vec![vec![0xfe, 0], body]);
+}
+
+#[test]
+fn fragment_ids_in_flight_are_not_reused() {
+    let (mut connection, mut recv) = connection();
+    connection.timeout = Duration::from_secs(600);
+    let body = vec![0xfe; connection.max_frame_size() + 1];
+    // the fragment id follows the reliable index and the fragment count.
+    let id = |datagram: &[u8], reliable: bool| {
+        let at = if reliable { 14 } else { 11 };
+        u16::from_be_bytes([datagram[at], datagram[at + 1]])
+    };
+
+    // this one is never acknowledged, so its id stays in flight.
+    connection
+        .send_reliable(body.clone(), Reliability::Reliable, None)
+        .unwrap();
+    connection.tick();
+    let in_flight = id(&sent(&mut recv)[0], true);
+
+    // unreliable fragments are freed once they're sent, so these take every other id.
+    for i in 0..u16::MAX {
+        connection
+            .send_reliable(body.clone(), Reliability::Unreliable, None)
+            .unwrap();
+        if i % 512 == 0 {
+            connection.tick();
+            sent(&mut recv);
+        }
+    }
+    connection.tick();
+    sent(&mut recv);
+
+    // the ids wrap around, past the one still in flight.
+    connection
+        .send_reliable(body.clone(), Reliability::Reliable, None)
+        .unwrap();
+    connection.tick();
+    let next = id(&sent(&mut recv)[0], true);
+    assert_eq!(in_flight, 0);
+    assert_eq!(next, 1);
+}
+
+#[test]
+fn split_packets_are_sent_in_sequence() {
+    let (mut connection, mut recv) = connection();
+    connection.mtu = 576;
+    connection
+        .send_reliable(vec![0xfe; 2000], Reliability::ReliableOrd, None)
+        .unwrap();
+    connection.tick();
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let sequences = sent(&mut recv)
+        .iter()
+        .map(|datagram| u24(&datagram[1..]))
+        .collect::<Vec<_>>();
+    // the first datagram we send starts the sequence.
+    assert!(sequences.len() > 1);
+    assert_eq!(sequences, (0..sequences.len() as u32).collect::<Vec<_>>());
+}
+
+#[test]
+fn traffic_is_counted() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    let datagrams = [
+        frame_packet(0, &[unreliable_frame(&[0xfe, 0])]),
+        frame_packet(1, &[unreliable_frame(&[0xfe, 1, 2])]),
+    ];
+    for datagram in &datagrams {
+        connection.recv(datagram);
+    }
+    connection.send(vec![0xfe; 10], true).unwrap();
+    connection.tick();
+
+    let sent = sent(&mut recv);
+    let stats = connection.stats();
+    assert_eq!(stats.packets_recv, 2);
+    assert_eq!(
+        stats.bytes_recv,
+        datagrams.iter().map(|d| d.len() as u64).sum::<u64>()
+    );
+    assert_eq!(stats.packets_sent, sent.len() as u64);
+    assert_eq!(
+        stats.bytes_sent,
+        sent.iter().map(|d| d.len() as u64).sum::<u64>()
+    );
+}
+
+#[test]
+fn client_guid_is_stored() {
+    let (mut connection, _recv) = connection();
+    assert_eq!(connection.client_guid, None);
+
+    connection.recv(&open_connect_request(1400));
+    connection.recv(&session_info_request(1400));
+    assert_eq!(connection.client_guid, Some(1));
+}
+
+#[test]
+fn connection_request_is_accepted() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connecting;
+    let mut request = vec![0x09];
+    request.extend_from_slice(&1i64.to_be_bytes());
+    request.extend_from_slice(&0x1234i64.to_be_bytes());
+    connection.recv(&frame_packet(0, &[ordered_frame(0, 0, 0, &request)]));
+
+    let datagrams = sent(&mut recv);
+    assert_eq!(datagrams.len(), 1);
+    // a reliable ordered frame, so the body follows the order channel.
+    let datagram = &datagrams[0];
+    assert_eq!(datagram[4], 0x60);
+    match Packet::compose(&datagram[14..], &mut 0)
+        .unwrap()
+        .get_online()
+    {
+        OnlinePacket::ConnectionAccept(pk) => {
+            assert_eq!(pk.client_address, "127.0.0.1:19133".parse().unwrap());
+            assert_eq!(pk.system_addresses.len(), SYSTEM_ADDRESS_COUNT);
+            assert_eq!(pk.request_time, 0x1234);
+        }
+        packet => panic!("Expected ConnectionAccept, got {:?}", packet),
+    }
+}
+
+#[test]
+fn repeated_connection_request_is_answered_with_the_same_datagram() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connecting;
+    let mut request = vec![0x09];
+    request.extend_from_slice(&1i64.to_be_bytes());
+    request.extend_from_slice(&0i64.to_be_bytes());
+
+    let mut replies = Vec::new();
+    for index in 0..3 {
+        connection.recv(&frame_packet(
+            index,
+            &[ordered_frame(index, index, 0, &request)],
+        ));
+        replies.push(sent(&mut recv));
+    }
+    assert_eq!(replies[0].len(), 1);
+    assert_eq!(replies[1], replies[0]);
+    assert_eq!(replies[2], replies[0]);
+
+    // the next packet we send follows right after the accept.
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    connection.state = ConnectionState::Connected;
+    connection.tick();
+    let ping = sent(&mut recv)
+        .into_iter()
+        .find(|datagram| datagram[0] == 0x80)
+        .unwrap();
+    // the datagram sequence, reliable index and order index.
+    assert_eq!(u24(&ping[1..]), 1);
+    assert_eq!(u24(&ping[7..]), 1);
+    assert_eq!(u24(&ping[10..]), 1);
+}
+
+/// Encodes a `NewConnection` as the client sends it to complete the handshake.
+fn new_connection() -> Vec<u8> {
+    Packet::from(NewConnection {
+        server_address: "127.0.0.1:19132".parse().unwrap(),
+        system_address: "127.0.0.1:19133".parse().unwrap(),
+        request_time: 0,
+        timestamp: 0,
+    })
+    .parse()
+    .unwrap()
+}
+
+#[test]
+fn handshake_walks_through_every_state() {
+    let (mut connection, mut recv) = connection();
+    connection.recv(&open_connect_request(1400));
+    assert_eq!(connection.state, ConnectionState::Unidentified);
+
+    connection.recv(&session_info_request(1400));
+    assert_eq!(connection.state, ConnectionState::Connecting);
+
+    let mut request = vec![0x09];
+    request.extend_from_slice(&1i64.to_be_bytes());
+    request.extend_from_slice(&0i64.to_be_bytes());
+    connection.recv(&frame_packet(0, &[ordered_frame(0, 0, 0, &request)]));
+    assert_eq!(connection.state, ConnectionState::Connecting);
+    assert!(connection.connected_at.is_none());
+
+    // game packets sent too early are dropped by default.
+    connection.recv(&frame_packet(1, &[unreliable_frame(&[0xfe, 1])]));
+    assert!(game_packets(&connection).is_empty());
+
+    connection.recv(&frame_packet(
+        2,
+        &[ordered_frame(1, 1, 0, &new_connection())],
+    ));
+    assert_eq!(connection.state, ConnectionState::Connected);
+    assert!(connection.connected_at.is_some());
+    assert!(connection.handshake_deadline.is_none());
+    let connects = connection
+        .event_dispatch
+        .iter()
+        .filter(
+            |event| matches!(event, RakEvent::Connect(address, 1) if address == "127.0.0.1:19133"),
+        )
+        .count();
+    assert_eq!(connects, 1);
+    sent(&mut recv);
+
+    // repeating it doesn't connect the client twice.
+    connection.recv(&frame_packet(
+        3,
+        &[ordered_frame(2, 2, 0, &new_connection())],
+    ));
+    let connects = connection
+        .event_dispatch
+        .iter()
+        .filter(|event| matches!(event, RakEvent::Connect(..)))
+        .count();
+    assert_eq!(connects, 1);
+}
+
+#[test]
+fn early_game_packets_can_be_buffered() {
+    let (mut connection, _recv) = connection();
+    connection.buffer_early_packets = true;
+    connection.recv(&open_connect_request(1400));
+    connection.recv(&session_info_request(1400));
+    connection.recv(&frame_packet(0, &[unreliable_frame(&[0xfe, 1])]));
+    assert!(game_packets(&connection).is_empty());
+
+    connection.recv(&frame_packet(
+        1,
+        &[ordered_frame(0, 0, 0, &new_connection())],
+    ));
+    assert_eq!(game_packets(&connection), vec![vec![0xfe, 1]]);
+    // the client is connected before any of its packets are dispatched.
+    let connect = connection
+        .event_dispatch
+        .iter()
+        .position(|event| matches!(event, RakEvent::Connect(..)));
+    let packet = connection
+        .event_dispatch
+        .iter()
+        .position(|event| matches!(event, RakEvent::GamePacket(..)));
+    assert!(connect < packet);
+}
+
+#[test]
+fn game_packets_without_a_handshake_are_dropped() {
+    let (mut connection, _recv) = connection();
+    // the client never sent an `OpenConnectRequest`, let alone completed the handshake.
+    connection.recv(&frame_packet(0, &[unreliable_frame(&[0xfe, 1])]));
+    assert!(game_packets(&connection).is_empty());
+    assert_eq!(connection.state, ConnectionState::Unidentified);
+}
+
+#[test]
+fn handshake_packets_out_of_order_are_ignored() {
+    let (mut connection, mut recv) = connection();
+    // neither packet means anything before the client opened the connection.
+    let mut request = vec![0x09];
+    request.extend_from_slice(&1i64.to_be_bytes());
+    request.extend_from_slice(&0i64.to_be_bytes());
+    connection.recv(&frame_packet(0, &[ordered_frame(0, 0, 0, &request)]));
+    connection.recv(&frame_packet(
+        1,
+        &[ordered_frame(1, 1, 0, &new_connection())],
+    ));
+    assert_eq!(connection.state, ConnectionState::Unidentified);
+    assert!(connection.connected_at.is_none());
+    assert!(!connection
+        .event_dispatch
+        .iter()
+        .any(|event| matches!(event, RakEvent::Connect(..))));
+    // only the ACKs for the datagrams are sent back.
+    connection.tick();
+    assert!(sent(&mut recv).iter().all(|datagram| datagram[0] == 0xc0));
+}
+
+fn session_info_request_to(address: &str) -> Vec<u8> {
+    Packet::from(SessionInfoRequest {
+        magic: Magic::new(),
+        cookie: None,
+        address: address.parse().unwrap(),
+        mtu_size: 1400,
+        client_id: 1,
+    })
+    .parse()
+    .unwrap()
+}
+
+#[test]
+fn handshake_address_is_not_validated_by_default() {
+    let (mut connection, mut recv) = connection();
+    connection.server_address = Some("127.0.0.1:19132".parse().unwrap());
+    connection.recv(&open_connect_request(1400));
+    next_offline(&mut recv);
+
+    // behind a proxy the client sees a completely different address.
+    connection.recv(&session_info_request_to("10.0.0.1:1234"));
+    match next_offline(&mut recv) {
+        OfflinePacket::SessionInfoReply(_) => {}
+        packet => panic!("Expected SessionInfoReply, got {:?}", packet),
+    }
+    assert_eq!(connection.state, ConnectionState::Connecting);
+}
+
+#[test]
+fn handshake_address_is_validated_when_enabled() {
+    let (mut connection, mut recv) = connection();
+    connection.server_address = Some("0.0.0.0:19132".parse().unwrap());
+    connection.validate_handshake_address = true;
+    connection.recv(&open_connect_request(1400));
+    next_offline(&mut recv);
+
+    connection.recv(&session_info_request_to("10.0.0.1:1234"));
+    assert!(recv.try_recv().is_err());
+    assert!(connection.is_disconnected());
+
+    // any ip is fine when the server is bound to all of them, as long as the port matches.
+    let (mut connection, mut recv) = self::connection();
+    connection.server_address = Some("0.0.0.0:19132".parse().unwrap());
+    connection.validate_handshake_address = true;
+    connection.recv(&open_connect_request(1400));
+    next_offline(&mut recv);
+
+    connection.recv(&session_info_request_to("192.168.1.5:19132"));
+    match next_offline(&mut recv) {
+        OfflinePacket::SessionInfoReply(_) => {}
+        packet => panic!("Expected SessionInfoReply, got {:?}", packet),
+    }
+}
+
+#[test]
+fn pings_follow_the_ping_interval() {
+    let (mut connection, mut recv) = connection();
+    connection.state = ConnectionState::Connected;
+    connection.ping_interval = Duration::from_millis(50);
+
+    let pings = |recv: &mut Receiver<SendCommand>| {
+        sent(recv)
+            .iter()
+            .filter(|datagram| datagram[0] == 0x80 && datagram[14] == 0x00)
+            .count()
+    };
+
+    connection.tick();
+    assert_eq!(pings(&mut recv), 1);
+    connection.tick();
+    assert_eq!(pings(&mut recv), 0);
+
+    // even if the ticks fall behind, the pings don't stack up.
+    std::thread::sleep(Duration::from_millis(120));
+    connection.tick();
+    assert_eq!(pings(&mut recv), 1);
+}
+
+#[test]
+fn motd_generator_answers_pings() {
+    let (mut connection, mut recv) = connection();
+    *connection.motd_generator.write().unwrap() = Some(MotdGenerator::new(|address| {
+        let mut motd = Motd::new(1234, "19132");
+        motd.name = format!("Hello {}", address.port());
+        motd
+    }));
+
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 1,
+        magic: Magic::new(),
+        client_id: 42,
+    });
+    connection.recv(&ping.parse().unwrap());
+
+    let motd = connection
+        .event_dispatch
+        .iter()
+        .find_map(|event| match event {
+            RakEvent::Motd(_, motd) => Some(motd.clone()),
+            _ => None,
+        });
+    assert_eq!(motd.expect("Expected a Motd event").name, "Hello 19133");
+
+    // the motd is only sent to the client with the mcpe feature.
+    match next_offline(&mut recv) {
+        #[cfg(feature = "mcpe")]
+        OfflinePacket::UnconnectedPong(pk) => assert_eq!(pk.motd.name, "Hello 19133"),
+        #[cfg(not(feature = "mcpe"))]
+        OfflinePacket::UnconnectedPong(_) => {}
+        packet => panic!("Expected UnconnectedPong, got {:?}", packet),
+    }
+}
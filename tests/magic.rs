@@ -0,0 +1,49 @@
+use binary_utils::Streamable;
+use rakrs::protocol::util::{verify_magic, Magic};
+use rakrs::MAGIC;
+
+#[test]
+fn magic_accepts_valid_bytes() {
+    assert!(Magic::compose(&MAGIC, &mut 0).is_ok());
+}
+
+#[test]
+fn magic_rejects_wrong_bytes() {
+    assert!(Magic::compose(&[0xab; 16], &mut 0).is_err());
+}
+
+#[test]
+fn magic_rejects_short_buffer() {
+    assert!(Magic::compose(&MAGIC[..8], &mut 0).is_err());
+}
+
+fn open_connect_request(magic: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0x05];
+    buffer.extend_from_slice(magic);
+    buffer.push(10);
+    buffer
+}
+
+#[test]
+fn verify_magic_accepts_valid_packet() {
+    assert!(verify_magic(&open_connect_request(&MAGIC)));
+
+    // the ping carries a timestamp before the magic.
+    let mut ping = vec![0x01];
+    ping.extend_from_slice(&0u64.to_be_bytes());
+    ping.extend_from_slice(&MAGIC);
+    assert!(verify_magic(&ping));
+}
+
+#[test]
+fn verify_magic_rejects_truncated_packet() {
+    assert!(!verify_magic(&open_connect_request(&MAGIC[..12])[..13]));
+    assert!(!verify_magic(&[]));
+}
+
+#[test]
+fn verify_magic_rejects_shifted_magic() {
+    let mut shifted = vec![0x00];
+    shifted.extend_from_slice(&MAGIC);
+    assert!(!verify_magic(&open_connect_request(&shifted)));
+}
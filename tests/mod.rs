@@ -1 +1,7 @@
+mod address;
+mod connection;
 mod defaults;
+mod magic;
+mod motd;
+mod packets;
+mod server;
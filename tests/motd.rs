@@ -0,0 +1,20 @@
+use rakrs::protocol::mcpe::motd::{Gamemode, Motd};
+
+#[test]
+fn motd_round_trip() {
+    let mut motd = Motd::new(1234, "19132");
+    motd.name = "Test Server".into();
+    motd.player_count = 3;
+    motd.gamemode = Gamemode::Creative;
+
+    let parsed = motd.to_string().parse::<Motd>().unwrap();
+    assert_eq!(parsed.name, "Test Server");
+    assert_eq!(parsed.player_count, 3);
+    assert_eq!(parsed.server_guid, 1234);
+    assert_eq!(parsed.port, "19132");
+}
+
+#[test]
+fn motd_rejects_missing_fields() {
+    assert!("MCPE;Test Server;448".parse::<Motd>().is_err());
+}
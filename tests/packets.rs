@@ -0,0 +1,128 @@
+use std::net::SocketAddr;
+
+use binary_utils::Streamable;
+use rakrs::protocol::online::{
+    ConnectedPing, ConnectedPong, ConnectionAccept, ConnectionRequest, DetectLostConnections,
+    Disconnect, NewConnection, OnlinePacket, SYSTEM_ADDRESS_COUNT,
+};
+use rakrs::protocol::Packet;
+
+/// `ConnectionRequestAccepted` for a client at `192.168.0.12:54321`, laid out the way RakLib
+/// sends it, but with the ten system addresses of the original RakNet.
+#[rustfmt::skip]
+const CONNECTION_ACCEPT: [u8; 96] = [
+    0x10, // packet id
+    0x04, 0x3f, 0x57, 0xff, 0xf3, 0xd4, 0x31, // client address
+    0x00, 0x00, // system index
+    0x04, 0x80, 0xff, 0xff, 0xfe, 0x00, 0x00, // 127.0.0.1:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // 0.0.0.0:0
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34, // request time
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x56, 0x78, // timestamp
+];
+
+#[test]
+fn connection_accept_matches_capture() {
+    let client: SocketAddr = "192.168.0.12:54321".parse().unwrap();
+    let accept = ConnectionAccept::new(client, 0x1234, 0x5678);
+    assert_eq!(accept.system_addresses.len(), SYSTEM_ADDRESS_COUNT);
+
+    let buffer = Packet::from(accept).parse().unwrap();
+    assert_eq!(buffer, CONNECTION_ACCEPT);
+}
+
+#[test]
+fn connection_accept_round_trip() {
+    let packet = Packet::compose(&CONNECTION_ACCEPT, &mut 0).unwrap();
+    match packet.get_online() {
+        OnlinePacket::ConnectionAccept(pk) => {
+            assert_eq!(pk.client_address, "192.168.0.12:54321".parse().unwrap());
+            assert_eq!(pk.system_addresses.len(), SYSTEM_ADDRESS_COUNT);
+            assert_eq!(pk.system_addresses[0], "127.0.0.1:0".parse().unwrap());
+            assert_eq!(pk.system_addresses[9], "0.0.0.0:0".parse().unwrap());
+            assert_eq!(pk.request_time, 0x1234);
+            assert_eq!(pk.timestamp, 0x5678);
+        }
+        packet => panic!("Expected ConnectionAccept, got {:?}", packet),
+    }
+}
+
+#[test]
+fn online_packets_round_trip() {
+    let address: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+    let packets: Vec<(u8, Packet)> = vec![
+        (0x00, ConnectedPing { time: 1 }.into()),
+        (
+            0x03,
+            ConnectedPong {
+                ping_time: 1,
+                pong_time: 2,
+            }
+            .into(),
+        ),
+        (0x04, DetectLostConnections {}.into()),
+        (
+            0x09,
+            ConnectionRequest {
+                client_id: 1,
+                time: 2,
+            }
+            .into(),
+        ),
+        (0x10, ConnectionAccept::new(address, 1, 2).into()),
+        (
+            0x13,
+            NewConnection {
+                server_address: address,
+                system_address: address,
+                request_time: 1,
+                timestamp: 2,
+            }
+            .into(),
+        ),
+        (0x15, Disconnect {}.into()),
+    ];
+
+    for (id, packet) in packets {
+        let buffer = packet.parse().unwrap();
+        assert_eq!(buffer[0], id);
+
+        let composed = Packet::compose(&buffer, &mut 0).unwrap();
+        let matches = match composed.get_online() {
+            OnlinePacket::ConnectedPing(_) => id == 0x00,
+            OnlinePacket::ConnectedPong(_) => id == 0x03,
+            OnlinePacket::DetectLostConnections(_) => id == 0x04,
+            OnlinePacket::ConnectionRequest(_) => id == 0x09,
+            OnlinePacket::ConnectionAccept(_) => id == 0x10,
+            OnlinePacket::NewConnection(_) => id == 0x13,
+            OnlinePacket::Disconnect(_) => id == 0x15,
+            OnlinePacket::Unknown(_) => false,
+        };
+        assert!(
+            matches,
+            "packet {:#04x} composed into the wrong variant",
+            id
+        );
+        assert_eq!(composed.parse().unwrap(), buffer);
+    }
+}
+
+#[test]
+fn unknown_online_ids_are_kept() {
+    let packet = Packet::compose(&[0x7f], &mut 0).unwrap();
+    match packet.get_online() {
+        OnlinePacket::Unknown(id) => assert_eq!(id, 0x7f),
+        packet => panic!("Expected Unknown, got {:?}", packet),
+    }
+    assert_eq!(packet.parse().unwrap(), vec![0x7f]);
+
+    // game packets are not raknet packets at all.
+    assert!(Packet::compose(&[0xfe, 1], &mut 0).is_err());
+}
@@ -0,0 +1,1080 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use binary_utils::Streamable;
+use netrex_events::Channel;
+use rakrs::connection::state::ConnectionState;
+use rakrs::connection::Connection;
+use rakrs::protocol::offline::{
+    OfflinePacket, OpenConnectRequest, SessionInfoRequest, UnconnectedPing,
+    UnconnectedPingOpenConnections,
+};
+use rakrs::protocol::online::NewConnection;
+use rakrs::protocol::util::Magic;
+use rakrs::protocol::Packet;
+use rakrs::{
+    start, RakEvent, RakNetServer, RakNetServerBuilder, RakNetVersion, RakResult, Reliability,
+    DEFAULT_TICK_INTERVAL, MAX_FRAMED_MTU_SIZE,
+};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+/// Runs the server until `client` completes, the server is stopped afterwards
+/// so its connections can be inspected.
+pub async fn run<F, C>(server: RakNetServer, client: C) -> (Arc<RakNetServer>, F::Output)
+where
+    F: Future,
+    C: FnOnce(SocketAddr) -> F,
+{
+    run_with(server, Channel::new(), client).await
+}
+
+/// Runs the server like `run`, dispatching its events to the listeners of the channel.
+pub async fn run_with<'a, F, C>(
+    server: RakNetServer,
+    channel: Channel<'a, RakEvent, RakResult>,
+    client: C,
+) -> (Arc<RakNetServer>, F::Output)
+where
+    F: Future,
+    C: FnOnce(SocketAddr) -> F,
+{
+    let address = server.address.as_str().parse::<SocketAddr>().unwrap();
+    let (tasks, server, _) = start(server, channel).await;
+
+    let output = tokio::select! {
+        _ = tasks => panic!("The server stopped unexpectedly"),
+        output = client(address) => output,
+    };
+    (server, output)
+}
+
+/// Sends the datagram to the server and collects every reply until it goes quiet.
+pub async fn exchange(socket: &UdpSocket, address: SocketAddr, data: &[u8]) -> Vec<Vec<u8>> {
+    socket.send_to(data, address).await.unwrap();
+
+    let mut replies = Vec::new();
+    let mut buf = [0; 8192];
+    while let Ok(Ok((len, _))) =
+        timeout(Duration::from_millis(150), socket.recv_from(&mut buf)).await
+    {
+        replies.push(buf[..len].to_vec());
+    }
+    replies
+}
+
+pub fn offline(buffer: &[u8]) -> OfflinePacket {
+    Packet::compose(buffer, &mut 0).unwrap().get_offline()
+}
+
+fn open_connect_request() -> Vec<u8> {
+    Packet::from(OpenConnectRequest {
+        magic: Magic::new(),
+        protocol: 10,
+        mtu_size: 1400,
+    })
+    .parse()
+    .unwrap()
+}
+
+fn session_info_request(address: SocketAddr, cookie: Option<u32>) -> Vec<u8> {
+    Packet::from(SessionInfoRequest {
+        magic: Magic::new(),
+        cookie,
+        address,
+        mtu_size: 1400,
+        client_id: 1,
+    })
+    .parse()
+    .unwrap()
+}
+
+/// Wraps the body in a reliable ordered frame, in a datagram of its own. The datagram
+/// sequence, reliable index and order index are all `index`.
+fn ordered_datagram(index: u32, body: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x84];
+    datagram.extend_from_slice(&index.to_le_bytes()[..3]);
+    datagram.push(0x60);
+    datagram.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+    datagram.extend_from_slice(&index.to_le_bytes()[..3]);
+    datagram.extend_from_slice(&index.to_le_bytes()[..3]);
+    datagram.push(0);
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+/// Walks through the whole handshake, the first two datagram sequences are used up.
+async fn connect(socket: &UdpSocket, address: SocketAddr) {
+    exchange(socket, address, &open_connect_request()).await;
+    exchange(socket, address, &session_info_request(address, None)).await;
+
+    let mut request = vec![0x09];
+    request.extend_from_slice(&1i64.to_be_bytes());
+    request.extend_from_slice(&0i64.to_be_bytes());
+    exchange(socket, address, &ordered_datagram(0, &request)).await;
+
+    let new_connection = Packet::from(NewConnection {
+        server_address: address,
+        system_address: socket.local_addr().unwrap(),
+        request_time: 0,
+        timestamp: 0,
+    })
+    .parse()
+    .unwrap();
+    exchange(socket, address, &ordered_datagram(1, &new_connection)).await;
+}
+
+#[tokio::test]
+async fn banned_ip_is_never_connected() {
+    let server = RakNetServer::new("127.0.0.1:19140".into());
+    server.ban("127.0.0.1");
+    assert!(server.is_banned("127.0.0.1:50000"));
+
+    let (server, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            replies.push(exchange(&socket, address, &open_connect_request()).await);
+        }
+        // anything that isn't a handshake is dropped.
+        replies.push(exchange(&socket, address, &[0x84, 0, 0, 0]).await);
+        replies
+    })
+    .await;
+
+    // exactly one ConnectionBanned per handshake attempt.
+    for attempt in &replies[..2] {
+        assert_eq!(attempt.len(), 1);
+        match offline(&attempt[0]) {
+            OfflinePacket::ConnectionBanned(pk) => assert_eq!(pk.server_id, server.server_guid),
+            packet => panic!("Expected ConnectionBanned, got {:?}", packet),
+        }
+    }
+    assert!(replies[2].is_empty());
+    assert!(server.connections.read().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn unbanned_ip_can_connect() {
+    let server = RakNetServer::new("127.0.0.1:19141".into());
+    server.ban("127.0.0.1");
+    server.unban("127.0.0.1");
+
+    let (server, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        exchange(&socket, address, &open_connect_request()).await
+    })
+    .await;
+
+    assert_eq!(replies.len(), 1);
+    match offline(&replies[0]) {
+        OfflinePacket::OpenConnectReply(_) => {}
+        packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+    }
+    assert_eq!(server.connections.read().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn full_server_has_no_free_connections() {
+    let mut server = RakNetServer::new("127.0.0.1:19142".into());
+    server.set_max_connections(1);
+
+    let (server, replies) = run(server, |address| async move {
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        vec![
+            exchange(&first, address, &open_connect_request()).await,
+            exchange(&second, address, &open_connect_request()).await,
+            // clients we already know about are unaffected.
+            exchange(&first, address, &open_connect_request()).await,
+        ]
+    })
+    .await;
+
+    match offline(&replies[0][0]) {
+        OfflinePacket::OpenConnectReply(_) => {}
+        packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+    }
+    match offline(&replies[1][0]) {
+        OfflinePacket::NoFreeIncomingConnections(pk) => {
+            assert_eq!(pk.server_id, server.server_guid)
+        }
+        packet => panic!("Expected NoFreeIncomingConnections, got {:?}", packet),
+    }
+    match offline(&replies[2][0]) {
+        OfflinePacket::OpenConnectReply(_) => {}
+        packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+    }
+    assert_eq!(server.connections.read().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn cookies_must_be_echoed() {
+    let mut server = RakNetServer::new("127.0.0.1:19143".into());
+    server.use_cookies = true;
+
+    let (server, replies) = run(server, |address| async move {
+        let mut replies = Vec::new();
+        let mut cookies = Vec::new();
+        for _ in 0..3 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let reply = exchange(&socket, address, &open_connect_request()).await;
+            let cookie = match offline(&reply[0]) {
+                OfflinePacket::OpenConnectReply(pk) => {
+                    assert!(pk.security);
+                    pk.cookie.unwrap()
+                }
+                packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+            };
+            cookies.push((socket, cookie));
+        }
+
+        // no cookie, someone else's cookie and finally the right cookie.
+        let echoed = [None, Some(cookies[0].1), Some(cookies[2].1)];
+        for ((socket, _), cookie) in cookies.iter().zip(echoed) {
+            replies.push(exchange(socket, address, &session_info_request(address, cookie)).await);
+        }
+        replies
+    })
+    .await;
+
+    assert!(replies[0].is_empty());
+    assert!(replies[1].is_empty());
+    match offline(&replies[2][0]) {
+        OfflinePacket::SessionInfoReply(_) => {}
+        packet => panic!("Expected SessionInfoReply, got {:?}", packet),
+    }
+    assert_eq!(server.connections.read().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn offline_packets_are_rate_limited() {
+    let mut server = RakNetServer::new("127.0.0.1:19144".into());
+    server.set_rate_limit(1, 5);
+
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 0,
+        magic: Magic::new(),
+        client_id: 1,
+    })
+    .parse()
+    .unwrap();
+
+    let (server, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..29 {
+            socket.send_to(&ping, address).await.unwrap();
+        }
+        exchange(&socket, address, &ping).await
+    })
+    .await;
+
+    // the burst is answered, anything after that is dropped until the bucket refills.
+    assert!(replies.len() >= 5 && replies.len() <= 6);
+    assert_eq!(server.stats().rate_limited, 30 - replies.len() as u64);
+}
+
+#[tokio::test]
+async fn connected_traffic_is_not_rate_limited() {
+    let mut server = RakNetServer::new("127.0.0.1:19164".into());
+    server.set_rate_limit(1, 2);
+
+    let (server, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut replies = exchange(&socket, address, &open_connect_request()).await;
+        replies.extend(exchange(&socket, address, &session_info_request(address, None)).await);
+        // unreliable frames, well past what the bucket holds.
+        for sequence in 0..20u8 {
+            let datagram = [0x84, sequence, 0, 0, 0x00, 0, 8, 0xfe];
+            socket.send_to(&datagram, address).await.unwrap();
+        }
+        let last = [0x84, 20, 0, 0, 0x00, 0, 8, 0xfe];
+        replies.extend(exchange(&socket, address, &last).await);
+        replies
+    })
+    .await;
+
+    // both handshake replies, and the acks for the frames.
+    assert!(replies.len() > 2);
+    assert!(replies[2..].iter().all(|reply| reply[0] == 0xc0));
+    assert_eq!(server.stats().rate_limited, 0);
+}
+
+#[tokio::test]
+async fn full_server_is_not_advertised() {
+    let mut server = RakNetServer::new("127.0.0.1:19145".into());
+    server.set_max_connections(1);
+
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 1,
+        magic: Magic::new(),
+        client_id: 2,
+    })
+    .parse()
+    .unwrap();
+    let open_ping = Packet::from(UnconnectedPingOpenConnections {
+        timestamp: 1,
+        magic: Magic::new(),
+        client_id: 2,
+    })
+    .parse()
+    .unwrap();
+
+    let (_, replies) = run(server, |address| async move {
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        vec![
+            exchange(&first, address, &open_ping).await,
+            exchange(&first, address, &open_connect_request()).await,
+            exchange(&second, address, &open_ping).await,
+            exchange(&second, address, &ping).await,
+        ]
+    })
+    .await;
+
+    // the server is advertised until the first client takes the only slot.
+    match offline(&replies[0][0]) {
+        OfflinePacket::UnconnectedPong(_) => {}
+        packet => panic!("Expected UnconnectedPong, got {:?}", packet),
+    }
+    assert!(replies[2].is_empty());
+    assert_eq!(replies[3].len(), 1);
+    match offline(&replies[3][0]) {
+        OfflinePacket::UnconnectedPong(pk) => assert_eq!(pk.timestamp, 1),
+        packet => panic!("Expected UnconnectedPong, got {:?}", packet),
+    }
+}
+
+#[tokio::test]
+async fn guid_is_sent_in_every_reply() {
+    let mut server = RakNetServer::new("127.0.0.1:19146".into());
+    assert_ne!(server.guid(), 0);
+    server.set_guid(0x1234_5678);
+    assert_eq!(server.guid(), 0x1234_5678);
+    assert_eq!(server.get_motd().server_guid, 0x1234_5678);
+
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 1,
+        magic: Magic::new(),
+        client_id: 2,
+    })
+    .parse()
+    .unwrap();
+
+    let (_, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut replies = exchange(&socket, address, &ping).await;
+        replies.extend(exchange(&socket, address, &open_connect_request()).await);
+        replies.extend(exchange(&socket, address, &session_info_request(address, None)).await);
+        replies
+    })
+    .await;
+
+    assert_eq!(replies.len(), 3);
+    for reply in &replies {
+        let guid = match offline(reply) {
+            OfflinePacket::UnconnectedPong(pk) => pk.server_id,
+            OfflinePacket::OpenConnectReply(pk) => pk.server_id,
+            OfflinePacket::SessionInfoReply(pk) => pk.server_id,
+            packet => panic!("Unexpected reply {:?}", packet),
+        };
+        assert_eq!(guid, 0x1234_5678);
+    }
+}
+
+#[test]
+fn max_mtu_fits_in_a_frame() {
+    let mut server = RakNetServer::new("127.0.0.1:19170".into());
+    server.set_max_mtu(u16::MAX);
+    assert_eq!(server.max_mtu, MAX_FRAMED_MTU_SIZE);
+}
+
+#[tokio::test]
+async fn large_datagrams_are_received_whole() {
+    let mut server = RakNetServer::new("127.0.0.1:19147".into());
+    server.set_max_mtu(4096);
+
+    let request = Packet::from(OpenConnectRequest {
+        magic: Magic::new(),
+        protocol: 10,
+        mtu_size: 3500,
+    })
+    .parse()
+    .unwrap();
+    assert!(request.len() > 2048);
+
+    let (_, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        exchange(&socket, address, &request).await
+    })
+    .await;
+
+    // the mtu is derived from the size of the request, so it must not be cut short.
+    match offline(&replies[0]) {
+        OfflinePacket::OpenConnectReply(pk) => assert_eq!(pk.mtu_size, 3500),
+        packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+    }
+}
+
+#[tokio::test]
+async fn connection_cap_holds_for_many_addresses() {
+    let mut server = RakNetServer::new("127.0.0.1:19148".into());
+    server.set_max_connections(1);
+
+    let (server, replies) = run(server, |address| async move {
+        let mut replies = Vec::new();
+        for _ in 0..5 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            replies.push(exchange(&socket, address, &open_connect_request()).await);
+        }
+        replies
+    })
+    .await;
+
+    // only the first address is accepted, everyone after it is turned away.
+    match offline(&replies[0][0]) {
+        OfflinePacket::OpenConnectReply(_) => {}
+        packet => panic!("Expected OpenConnectReply, got {:?}", packet),
+    }
+    for reply in &replies[1..] {
+        assert_eq!(reply.len(), 1);
+        match offline(&reply[0]) {
+            OfflinePacket::NoFreeIncomingConnections(_) => {}
+            packet => panic!("Expected NoFreeIncomingConnections, got {:?}", packet),
+        }
+    }
+    assert_eq!(server.connections.read().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn half_open_connections_are_reaped() {
+    let server = RakNetServer::new("127.0.0.1:19149".into());
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+    let send = Arc::new(send);
+
+    // the connected client finished its handshake, it no longer has a deadline.
+    let expired = Some(SystemTime::now() - Duration::from_secs(1));
+    for (address, state, deadline) in [
+        ("127.0.0.1:50001", ConnectionState::Connecting, expired),
+        ("127.0.0.1:50002", ConnectionState::Connected, None),
+    ] {
+        let mut connection = Connection::new(
+            address.into(),
+            send.clone(),
+            SystemTime::now(),
+            server.server_guid,
+            server.motd.clone(),
+            Arc::new(RwLock::new(None)),
+            server.version.clone(),
+        );
+        connection.state = state;
+        connection.handshake_deadline = deadline;
+        server
+            .connections
+            .write()
+            .unwrap()
+            .insert(address.into(), Arc::new(Mutex::new(connection)));
+    }
+
+    let (server, _) = run(server, |_| tokio::time::sleep(Duration::from_millis(200))).await;
+
+    let connections = server.connections.read().unwrap();
+    assert!(!connections.contains_key("127.0.0.1:50001"));
+    assert!(connections.contains_key("127.0.0.1:50002"));
+    assert_eq!(server.stats().handshake_timeouts, 1);
+}
+
+#[tokio::test]
+async fn idle_connections_are_reaped() {
+    let server = RakNetServer::new("127.0.0.1:19154".into());
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+    let send = Arc::new(send);
+
+    // the first client vanished, the second one is still sending packets.
+    for (address, idle) in [
+        ("127.0.0.1:50001", Duration::from_secs(11)),
+        ("127.0.0.1:50002", Duration::from_secs(0)),
+    ] {
+        let mut connection = Connection::new(
+            address.into(),
+            send.clone(),
+            SystemTime::now(),
+            server.server_guid,
+            server.motd.clone(),
+            Arc::new(RwLock::new(None)),
+            server.version.clone(),
+        );
+        connection.state = ConnectionState::Connected;
+        connection.handshake_deadline = None;
+        connection.recv_time = SystemTime::now() - idle;
+        server
+            .connections
+            .write()
+            .unwrap()
+            .insert(address.into(), Arc::new(Mutex::new(connection)));
+    }
+
+    let (server, _) = run(server, |_| tokio::time::sleep(Duration::from_millis(200))).await;
+
+    let connections = server.connections.read().unwrap();
+    assert!(!connections.contains_key("127.0.0.1:50001"));
+    assert!(connections.contains_key("127.0.0.1:50002"));
+}
+
+#[tokio::test]
+async fn failed_sends_are_counted() {
+    let server = RakNetServer::new("127.0.0.1:19167".into());
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+
+    // the server is only bound to IPv4, so it can't reach this client.
+    let address = "[::1]:50001";
+    let mut connection = Connection::new(
+        address.into(),
+        Arc::new(send),
+        SystemTime::now(),
+        server.server_guid,
+        server.motd.clone(),
+        Arc::new(RwLock::new(None)),
+        server.version.clone(),
+    );
+    // the queue of a connection that isn't reliable is sent as is, from the server's socket.
+    connection.state = ConnectionState::Offline;
+    connection.send(vec![0xfe, 0], false).unwrap();
+    server
+        .connections
+        .write()
+        .unwrap()
+        .insert(address.into(), Arc::new(Mutex::new(connection)));
+
+    let (server, _) = run(server, |_| tokio::time::sleep(Duration::from_millis(200))).await;
+    assert!(server.stats().send_errors > 0);
+}
+
+#[tokio::test]
+async fn ipv6_clients_are_answered() {
+    let server = RakNetServer::new("[::1]:19150".into());
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 1,
+        magic: Magic::new(),
+        client_id: 2,
+    })
+    .parse()
+    .unwrap();
+
+    let (server, (client, replies)) = run(server, |address| async move {
+        let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        let mut replies = exchange(&socket, address, &ping).await;
+        replies.extend(exchange(&socket, address, &open_connect_request()).await);
+        (socket.local_addr().unwrap(), replies)
+    })
+    .await;
+
+    // the reply can only be sent if the address token parses back into the address.
+    assert_eq!(replies.len(), 2);
+    let token = format!("[::1]:{}", client.port());
+    assert!(server.connections.read().unwrap().contains_key(&token));
+}
+
+#[tokio::test]
+async fn clients_can_connect_to_every_address() {
+    let mut server = RakNetServer::new("127.0.0.1:19162".into());
+    server.add_address("[::1]:19163");
+
+    let (server, clients) = run(server, |address| async move {
+        let mut clients = Vec::new();
+        for (bind, address) in [
+            ("127.0.0.1:0", address),
+            ("[::1]:0", "[::1]:19163".parse().unwrap()),
+        ] {
+            let socket = UdpSocket::bind(bind).await.unwrap();
+            let mut replies = Vec::new();
+            for request in [open_connect_request(), session_info_request(address, None)] {
+                socket.send_to(&request, address).await.unwrap();
+                let mut buf = [0; 2048];
+                let (len, from) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                // the client is answered from the address it sent to.
+                assert_eq!(from, address);
+                replies.push(offline(&buf[..len]));
+            }
+            clients.push((socket.local_addr().unwrap().to_string(), replies));
+        }
+        clients
+    })
+    .await;
+
+    let connections = server.connections.read().unwrap();
+    for (token, replies) in clients {
+        assert!(matches!(replies[0], OfflinePacket::OpenConnectReply(_)));
+        assert!(matches!(replies[1], OfflinePacket::SessionInfoReply(_)));
+        assert!(connections.contains_key(&token));
+    }
+}
+
+#[tokio::test]
+async fn listeners_receive_the_events_of_a_connection() {
+    let server = RakNetServer::new("127.0.0.1:19168".into());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let captured = events.clone();
+    let mut listener = move |event: RakEvent, _: Option<RakResult>| {
+        captured.lock().unwrap().push(event);
+        None
+    };
+    let mut channel = Channel::new();
+    channel.receive(&mut listener);
+
+    let (_, client) = run_with(server, channel, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        connect(&socket, address).await;
+        exchange(&socket, address, &ordered_datagram(2, &[0xfe, 1])).await;
+        exchange(&socket, address, &ordered_datagram(3, &[0x15])).await;
+        // the events are dispatched on the next tick.
+        tokio::time::sleep(DEFAULT_TICK_INTERVAL * 2).await;
+        socket.local_addr().unwrap().to_string()
+    })
+    .await;
+
+    let events = events.lock().unwrap();
+    let events = events
+        .iter()
+        .filter(|event| match event {
+            RakEvent::Connect(address, _)
+            | RakEvent::GamePacket(address, _)
+            | RakEvent::Disconnect(address, _) => *address == client,
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+    match events.as_slice() {
+        [RakEvent::Connect(_, _), RakEvent::GamePacket(_, stream), RakEvent::Disconnect(_, _)] => {
+            assert_eq!(stream, &vec![0xfe, 1])
+        }
+        events => panic!(
+            "Expected Connect, GamePacket and Disconnect, got {:?}",
+            events
+        ),
+    }
+}
+
+#[tokio::test]
+async fn start_async_drives_the_server() {
+    let server = RakNetServer::new("127.0.0.1:19169".into());
+    let address = server.address.as_str().parse::<SocketAddr>().unwrap();
+    let packets = Arc::new(Mutex::new(Vec::new()));
+    let captured = packets.clone();
+    let mut listener = move |event: RakEvent, _: Option<RakResult>| {
+        if let RakEvent::GamePacket(_, stream) = event {
+            captured.lock().unwrap().push(stream);
+        }
+        None
+    };
+    let mut channel = Channel::new();
+    channel.receive(&mut listener);
+
+    let client = async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        connect(&socket, address).await;
+        exchange(&socket, address, &ordered_datagram(2, &[0xfe, 1])).await;
+        tokio::time::sleep(DEFAULT_TICK_INTERVAL * 2).await;
+    };
+    tokio::select! {
+        _ = server.start_async(channel) => panic!("The server stopped unexpectedly"),
+        _ = client => {}
+    }
+
+    assert_eq!(*packets.lock().unwrap(), vec![vec![0xfe, 1]]);
+}
+
+#[tokio::test]
+async fn handle_sends_without_locking_the_connection() {
+    let server = RakNetServer::new("127.0.0.1:19166".into());
+    let handle = server.handle();
+    assert!(!handle.send("127.0.0.1:1", vec![0xfe, 0], Reliability::ReliableOrd));
+
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let from = sender.local_addr().unwrap().to_string();
+    let to = receiver.local_addr().unwrap().to_string();
+
+    // the listener is called while the sending client is locked, it relays to the other one.
+    let mut listener = move |event: RakEvent, _: Option<RakResult>| {
+        if let RakEvent::GamePacket(address, stream) = event {
+            if address == from {
+                assert!(handle.send(&to, stream, Reliability::ReliableOrd));
+            }
+        }
+        None
+    };
+    let mut channel = Channel::new();
+    channel.receive(&mut listener);
+
+    let (_, datagrams) = run_with(server, channel, |address| async move {
+        connect(&receiver, address).await;
+        connect(&sender, address).await;
+        sender
+            .send_to(&ordered_datagram(2, &[0xfe, 1]), address)
+            .await
+            .unwrap();
+
+        let mut datagrams = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(Ok((len, _))) =
+            timeout(Duration::from_millis(500), receiver.recv_from(&mut buf)).await
+        {
+            datagrams.push(buf[..len].to_vec());
+        }
+        datagrams
+    })
+    .await;
+
+    let relayed = datagrams
+        .iter()
+        .find(|datagram| datagram[0] == 0x80 && datagram.ends_with(&[0xfe, 1]))
+        .expect("The packet was not relayed");
+    assert_eq!(relayed[14..], [0xfe, 1]);
+}
+
+#[tokio::test]
+async fn pings_do_not_create_connections() {
+    let server = RakNetServer::new("127.0.0.1:19161".into());
+    let ping = Packet::from(UnconnectedPing {
+        timestamp: 7,
+        magic: Magic::new(),
+        client_id: 2,
+    })
+    .parse()
+    .unwrap();
+    let open_ping = Packet::from(UnconnectedPingOpenConnections {
+        timestamp: 8,
+        magic: Magic::new(),
+        client_id: 2,
+    })
+    .parse()
+    .unwrap();
+
+    let (server, replies) = run(server, |address| async move {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut replies = exchange(&socket, address, &ping).await;
+        replies.extend(exchange(&socket, address, &open_ping).await);
+        replies
+    })
+    .await;
+
+    assert_eq!(replies.len(), 2);
+    for (reply, timestamp) in replies.iter().zip([7, 8]) {
+        match offline(reply) {
+            OfflinePacket::UnconnectedPong(pk) => {
+                assert_eq!(pk.timestamp, timestamp);
+                assert_eq!(pk.server_id, server.server_guid);
+            }
+            packet => panic!("Expected UnconnectedPong, got {:?}", packet),
+        }
+    }
+    assert!(server.connections.read().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn disconnect_notification_removes_connection() {
+    let server = RakNetServer::new("127.0.0.1:19152".into());
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = socket.local_addr().unwrap().to_string();
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+
+    let mut connection = Connection::new(
+        client.clone(),
+        Arc::new(send),
+        SystemTime::now(),
+        server.server_guid,
+        server.motd.clone(),
+        Arc::new(RwLock::new(None)),
+        server.version.clone(),
+    );
+    connection.state = ConnectionState::Connected;
+    connection.handshake_deadline = None;
+    server
+        .connections
+        .write()
+        .unwrap()
+        .insert(client.clone(), Arc::new(Mutex::new(connection)));
+
+    let (server, _) = run(server, |address| async move {
+        // a single unreliable frame holding DisconnectNotification.
+        let datagram = [0x84, 0, 0, 0, 0x00, 0x00, 0x08, 0x15];
+        socket.send_to(&datagram, address).await.unwrap();
+        tokio::time::sleep(DEFAULT_TICK_INTERVAL + Duration::from_millis(30)).await;
+    })
+    .await;
+
+    assert!(!server.connections.read().unwrap().contains_key(&client));
+}
+
+#[tokio::test]
+async fn kicked_client_is_notified() {
+    let server = RakNetServer::new("127.0.0.1:19153".into());
+    let address = server.address.as_str().parse::<SocketAddr>().unwrap();
+    let (tasks, server, _) = start(server, Channel::<RakEvent, RakResult>::new()).await;
+
+    let client = async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let token = socket.local_addr().unwrap().to_string();
+        exchange(&socket, address, &open_connect_request()).await;
+        exchange(&socket, address, &session_info_request(address, None)).await;
+
+        assert!(!server.kick("127.0.0.1:1"));
+        assert!(server.kick(&token));
+        // the client is already on its way out.
+        assert!(!server.kick(&token));
+
+        let mut buf = [0; 2048];
+        let (len, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::sleep(DEFAULT_TICK_INTERVAL * 2).await;
+        (token, buf[..len].to_vec())
+    };
+
+    let (token, datagram) = tokio::select! {
+        _ = tasks => panic!("The server stopped unexpectedly"),
+        output = client => output,
+    };
+
+    // a single reliable ordered frame holding the notification.
+    assert_eq!(datagram[0], 0x80);
+    assert_eq!(datagram[4], 0x60);
+    assert_eq!(datagram[14..], [0x15]);
+    assert!(!server.connections.read().unwrap().contains_key(&token));
+}
+
+#[tokio::test]
+async fn packets_are_sent_to_connected_clients() {
+    let server = RakNetServer::new("127.0.0.1:19155".into());
+    let address = server.address.as_str().parse::<SocketAddr>().unwrap();
+    let (tasks, _, send) = start(server, Channel::<RakEvent, RakResult>::new()).await;
+
+    let client = async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let token = socket.local_addr().unwrap().to_string();
+        exchange(&socket, address, &open_connect_request()).await;
+        exchange(&socket, address, &session_info_request(address, None)).await;
+
+        send.send((token, vec![0xfe, 1], false)).await.unwrap();
+
+        let mut buf = [0; 2048];
+        let (len, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf[..len].to_vec()
+    };
+
+    let datagram = tokio::select! {
+        _ = tasks => panic!("The server stopped unexpectedly"),
+        output = client => output,
+    };
+
+    // the packet is batched on the next tick, in a reliable ordered frame.
+    assert_eq!(datagram[0], 0x80);
+    assert_eq!(datagram[4], 0x60);
+    assert_eq!(datagram[14..], [0xfe, 1]);
+}
+
+#[test]
+fn stats_sum_the_traffic_of_every_connection() {
+    let server = RakNetServer::new("127.0.0.1:19156".into());
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+    let send = Arc::new(send);
+
+    for (address, datagram) in [
+        ("127.0.0.1:50001", vec![0x84; 10]),
+        ("127.0.0.1:50002", vec![0x84; 5]),
+    ] {
+        let mut connection = Connection::new(
+            address.into(),
+            send.clone(),
+            SystemTime::now(),
+            server.server_guid,
+            server.motd.clone(),
+            Arc::new(RwLock::new(None)),
+            server.version.clone(),
+        );
+        connection.recv(&datagram);
+        server
+            .connections
+            .write()
+            .unwrap()
+            .insert(address.into(), Arc::new(Mutex::new(connection)));
+    }
+
+    let stats = server.stats();
+    assert_eq!(stats.packets_recv, 2);
+    assert_eq!(stats.bytes_recv, 15);
+}
+
+#[test]
+fn broadcast_reaches_every_connected_client() {
+    let server = RakNetServer::new("127.0.0.1:19157".into());
+    let (send, mut recv) = tokio::sync::mpsc::channel(2048);
+    let send = Arc::new(send);
+
+    for (address, state) in [
+        ("127.0.0.1:50001", ConnectionState::Connected),
+        ("127.0.0.1:50002", ConnectionState::Connected),
+        ("127.0.0.1:50003", ConnectionState::Connected),
+        ("127.0.0.1:50004", ConnectionState::Connecting),
+    ] {
+        let mut connection = Connection::new(
+            address.into(),
+            send.clone(),
+            SystemTime::now(),
+            server.server_guid,
+            server.motd.clone(),
+            Arc::new(RwLock::new(None)),
+            server.version.clone(),
+        );
+        connection.state = state;
+        server
+            .connections
+            .write()
+            .unwrap()
+            .insert(address.into(), Arc::new(Mutex::new(connection)));
+    }
+
+    let exclude = "127.0.0.1:50003".parse().unwrap();
+    let sent = server.broadcast(vec![0xfe, 1], Reliability::Unreliable, Some(exclude));
+    assert_eq!(sent, 2);
+
+    for connection in server.connections.read().unwrap().values() {
+        connection.lock().unwrap().tick();
+    }
+    let mut receivers = Vec::new();
+    while let Ok((address, datagram)) = recv.try_recv() {
+        if datagram.ends_with(&[0xfe, 1]) {
+            receivers.push(address);
+        }
+    }
+    receivers.sort();
+    assert_eq!(receivers, vec!["127.0.0.1:50001", "127.0.0.1:50002"]);
+}
+
+#[test]
+fn connections_are_locked_independently() {
+    let server = Arc::new(RakNetServer::new("127.0.0.1:19158".into()));
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+    let send = Arc::new(send);
+
+    for address in ["127.0.0.1:50001", "127.0.0.1:50002"] {
+        let connection = Connection::new(
+            address.into(),
+            send.clone(),
+            SystemTime::now(),
+            server.server_guid,
+            server.motd.clone(),
+            Arc::new(RwLock::new(None)),
+            server.version.clone(),
+        );
+        server
+            .connections
+            .write()
+            .unwrap()
+            .insert(address.into(), Arc::new(Mutex::new(connection)));
+    }
+
+    // while one connection is busy, the other can still be sent to.
+    let busy = server.connections.read().unwrap()["127.0.0.1:50001"].clone();
+    let _guard = busy.lock().unwrap();
+
+    let (done, finished) = std::sync::mpsc::channel();
+    let other = server.clone();
+    std::thread::spawn(move || {
+        let sent = other.send_stream("127.0.0.1:50002", vec![0xfe], Reliability::Unreliable);
+        done.send(sent).unwrap();
+    });
+    assert_eq!(finished.recv_timeout(Duration::from_secs(1)), Ok(true));
+}
+
+#[test]
+fn snapshot_holds_every_connection() {
+    let server = RakNetServer::new("127.0.0.1:19165".into());
+    let (send, _recv) = tokio::sync::mpsc::channel(2048);
+    let send = Arc::new(send);
+
+    for (address, state) in [
+        ("127.0.0.1:50001", ConnectionState::Connecting),
+        ("127.0.0.1:50002", ConnectionState::Connected),
+    ] {
+        let mut connection = Connection::new(
+            address.into(),
+            send.clone(),
+            SystemTime::now(),
+            server.server_guid,
+            server.motd.clone(),
+            Arc::new(RwLock::new(None)),
+            server.version.clone(),
+        );
+        connection.state = state;
+        connection.mtu = 1200;
+        server
+            .connections
+            .write()
+            .unwrap()
+            .insert(address.into(), Arc::new(Mutex::new(connection)));
+    }
+
+    let mut snapshot = server.connections_snapshot();
+    snapshot.sort_by(|a, b| a.address.cmp(&b.address));
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[0].address, "127.0.0.1:50001");
+    assert_eq!(snapshot[0].state, ConnectionState::Connecting);
+    assert_eq!(snapshot[1].address, "127.0.0.1:50002");
+    assert_eq!(snapshot[1].state, ConnectionState::Connected);
+    for info in snapshot {
+        assert_eq!(info.mtu, 1200);
+        assert_eq!(info.latency, None);
+    }
+}
+
+#[test]
+fn tick_interval_is_configurable() {
+    let mut server = RakNetServer::new("127.0.0.1:19159".into());
+    assert_eq!(server.tick_interval, DEFAULT_TICK_INTERVAL);
+    server.set_tick_interval(Duration::from_millis(10));
+    assert_eq!(server.tick_interval, Duration::from_millis(10));
+}
+
+#[test]
+#[should_panic]
+fn tick_interval_can_not_be_zero() {
+    let mut server = RakNetServer::new("127.0.0.1:19160".into());
+    server.set_tick_interval(Duration::ZERO);
+}
+
+#[test]
+fn builder_applies_configuration() {
+    let server = RakNetServerBuilder::new()
+        .address("127.0.0.1:19151")
+        .version(RakNetVersion::V11)
+        .max_connections(12)
+        .tick_interval(Duration::from_millis(20))
+        .max_mtu(1200)
+        .guid(0x1234_5678)
+        .build();
+
+    assert_eq!(server.address, "127.0.0.1:19151");
+    assert_eq!(server.version, RakNetVersion::V11);
+    assert_eq!(server.max_connections, 12);
+    assert_eq!(server.tick_interval, Duration::from_millis(20));
+    assert_eq!(server.max_mtu, 1200);
+    assert_eq!(server.guid(), 0x1234_5678);
+    assert_eq!(server.get_motd().server_guid, 0x1234_5678);
+
+    // anything that isn't set keeps its default.
+    let defaults = RakNetServerBuilder::new().build();
+    assert_eq!(defaults.address, "0.0.0.0:19132");
+    assert_eq!(defaults.version, RakNetVersion::V10);
+    assert_eq!(defaults.max_connections, usize::MAX);
+    assert_eq!(defaults.tick_interval, DEFAULT_TICK_INTERVAL);
+}